@@ -0,0 +1,39 @@
+// background.rs
+// What rays see when they escape the scene
+
+use crate::vector::{Color, Vec3};
+
+pub trait Background {
+	// The radiance arriving from this direction.
+	fn value(&self, direction: &Vec3) -> Color;
+	// A random direction toward the background for direct lighting, the
+	// radiance from there, and the pdf (per solid angle) of picking it.
+	// Backgrounds that aren't worth sampling return None.
+	fn sample(&self) -> Option<(Vec3, Color, f64)> {
+		None
+	}
+	// The pdf that sample() picks this direction with.
+	fn pdf(&self, _direction: &Vec3) -> f64 {
+		0.0
+	}
+}
+
+// The same color in every direction
+#[derive(Default)]
+pub struct SolidBackground {
+	color: Color,
+}
+
+impl SolidBackground {
+	pub fn new(color: Color) -> Self {
+		Self {
+			color,
+		}
+	}
+}
+
+impl Background for SolidBackground {
+	fn value(&self, _direction: &Vec3) -> Color {
+		self.color
+	}
+}
@@ -27,8 +27,7 @@ pub struct BmpPicture {
     rows: Vec<Row>
 }
 
-#[derive(Default)]
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Default)]
 struct BitmapFileHeader {
     pub signature: u16,
@@ -38,7 +37,7 @@ struct BitmapFileHeader {
     pub offset: u32
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Default)]
 struct BitmapInfoHeader {
     pub size: u32,
@@ -54,7 +53,7 @@ struct BitmapInfoHeader {
     pub clrs_used: u32
 }
 
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(Default)]
 struct BitmapHeaders {
     pub bfh: BitmapFileHeader,
@@ -88,7 +87,7 @@ impl BmpPicture {
     pub fn write_file(&self, fname: &str) -> Result<usize, Error> {
         let mut wd = BufWriter::new(File::create(fname)?);
         let row_bytes = self.width as usize * 3;
-        let padding = if row_bytes % 4 != 0 { 4 - row_bytes % 4 } else { 0 };
+        let padding = if !row_bytes.is_multiple_of(4) { 4 - row_bytes % 4 } else { 0 };
         let mut buffer = { let mut v = Vec::<u8>::new(); v.resize_with(size_of::<BitmapHeaders>(), Default::default); v };
         let header = unsafe { (buffer.as_mut_ptr() as *mut BitmapHeaders).as_mut().unwrap() };
         let mut bytes_written = 0;
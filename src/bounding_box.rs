@@ -40,9 +40,11 @@ impl AxisAlignedBoundingBox {
 
             t_min_so_far = t0.max(t_min_so_far);
             t_max_so_far = t1.min(t_max_so_far);
-
+            if t_max_so_far <= t_min_so_far {
+                return false;
+            }
         }
-        t_max > t_min
+        true
     }
     pub fn surrounding_box(&self, other: &AxisAlignedBoundingBox) -> AxisAlignedBoundingBox {
         let small = Vec3::new(self.min().x().min(other.min().x()),
@@ -0,0 +1,268 @@
+// bump.rs
+// Normal and bump mapping
+
+use crate::hitable::HitRecord;
+use crate::material::Material;
+use crate::microfacet::Frame;
+use crate::ray::Ray;
+use crate::texture::Texture;
+use crate::vector::{Color, Vec3};
+use std::sync::Arc;
+
+// How far heights are stepped in u and v to find their slope. The point moves
+// along the surface by as much, so textures of either see the same step.
+const BUMP_DELTA: f64 = 1.0e-3;
+
+enum Perturbation {
+	// A tangent space normal stored as a color from 0 to 1, with red along the
+	// tangent, green along the bitangent, and blue out of the surface.
+	Map(Arc<dyn Texture + Send + Sync>),
+	// A height from the first channel of a texture, which strength scales to
+	// a distance the surface is pushed out by.
+	Bump {
+		height: Arc<dyn Texture + Send + Sync>,
+		strength: f64,
+	},
+}
+
+// Any material, with a shading normal bent away from the geometry's. The
+// geometric normal still decides which side of the surface light is on, so
+// light scattered below the surface by the bent normal is absorbed instead of
+// leaking through.
+pub struct NormalMapped {
+	base: Arc<dyn Material + Send + Sync>,
+	perturbation: Perturbation,
+}
+
+impl NormalMapped {
+	pub fn new(base: Arc<dyn Material + Send + Sync>, map: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			base,
+			perturbation: Perturbation::Map(map),
+		}
+	}
+
+	pub fn from_height(base: Arc<dyn Material + Send + Sync>, height: Arc<dyn Texture + Send + Sync>, strength: f64) -> Self {
+		Self {
+			base,
+			perturbation: Perturbation::Bump {
+				height,
+				strength,
+			},
+		}
+	}
+
+	// The perturbed normal on the outside of the surface, not yet normalized.
+	fn outward_normal(&self, rec: &HitRecord) -> Vec3 {
+		let outward = if rec.front_face() {
+			*rec.geometric_normal()
+		} else {
+			-*rec.geometric_normal()
+		};
+		let (u, v, point) = (rec.u(), rec.v(), rec.point());
+		match &self.perturbation {
+			Perturbation::Map(map) => {
+				let frame = Frame::from_tangent(&outward, rec.dpdu());
+				frame.to_world(&(map.value(u, v, point) * 2.0 - 1.0))
+			}
+			// The normal of the displaced surface, from how it moves with u and
+			// v (PBRT's BumpMap).
+			Perturbation::Bump {
+				height,
+				strength,
+			} => {
+				let (dpdu, dpdv) = (*rec.dpdu(), *rec.dpdv());
+				let h = height.value(u, v, point).r();
+				let dh_du = (height.value(u + BUMP_DELTA, v, &(*point + &(dpdu * BUMP_DELTA))).r() - h) / BUMP_DELTA;
+				let dh_dv = (height.value(u, v + BUMP_DELTA, &(*point + &(dpdv * BUMP_DELTA))).r() - h) / BUMP_DELTA;
+				let normal = (dpdu + &(outward * (dh_du * strength))).cross(&(dpdv + &(outward * (dh_dv * strength))));
+				// u and v may run either way around the outward normal.
+				if dpdu.cross(&dpdv).dot(&outward) < 0.0 {
+					-normal
+				} else {
+					normal
+				}
+			}
+		}
+	}
+
+	// The hit as the base material sees it. A normal bent so far that the
+	// viewer is behind it is ignored, since nothing could be seen of it.
+	fn shaded(&self, ray: &Ray, rec: &HitRecord) -> HitRecord {
+		let mut normal = self.outward_normal(rec);
+		if normal.len2() <= 0.0 {
+			return rec.clone();
+		}
+		normal = normal.unit();
+		if !rec.front_face() {
+			normal = -normal;
+		}
+		let mut shaded = rec.clone();
+		if normal.dot(ray.direction()) < 0.0 && normal.dot(rec.geometric_normal()) > 0.0 {
+			shaded.set_shading_normal(normal);
+		}
+		shaded
+	}
+}
+
+// Whether a direction is on the same side of the shading normal as of the
+// geometric one.
+fn same_side(rec: &HitRecord, direction: &Vec3) -> bool {
+	direction.dot(rec.normal()) * direction.dot(rec.geometric_normal()) > 0.0
+}
+
+impl Material for NormalMapped {
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let rec = self.shaded(ray, rec);
+		let (attenuation, scattered) = self.base.scatter(ray, &rec)?;
+		if !same_side(&rec, scattered.direction()) {
+			return None;
+		}
+		Some((attenuation, scattered))
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		let rec = self.shaded(ray, rec);
+		if !same_side(&rec, direction) {
+			return 0.0;
+		}
+		self.base.pdf(ray, &rec, direction)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let rec = self.shaded(ray, rec);
+		if !same_side(&rec, direction) {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		self.base.eval(ray, &rec, direction)
+	}
+
+	fn has_pdf(&self, rec: &HitRecord) -> bool {
+		self.base.has_pdf(rec)
+	}
+
+	fn emitted(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base.emitted(u, v, point)
+	}
+
+	fn emitted_toward(&self, ray: &Ray, rec: &HitRecord) -> Color {
+		self.base.emitted_toward(ray, rec)
+	}
+
+	fn is_emissive(&self) -> bool {
+		self.base.is_emissive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.base.is_two_sided()
+	}
+
+	fn visible_to_camera(&self) -> bool {
+		self.base.visible_to_camera()
+	}
+
+	fn passes_through(&self, u: f64, v: f64, point: &Vec3) -> bool {
+		self.base.passes_through(u, v, point)
+	}
+
+	fn opacity(&self, u: f64, v: f64, point: &Vec3) -> f64 {
+		self.base.opacity(u, v, point)
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base.albedo(u, v, point)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::Lambertian;
+	use crate::random;
+	use crate::texture::SolidColor;
+
+	// Heights that rise by one per unit of u, or of x.
+	struct RampInU;
+	struct RampInX;
+
+	impl Texture for RampInU {
+		fn value(&self, u: f64, _v: f64, _point: &Vec3) -> Color {
+			Color::new(u, u, u)
+		}
+	}
+
+	impl Texture for RampInX {
+		fn value(&self, _u: f64, _v: f64, point: &Vec3) -> Color {
+			Color::new(point.x(), point.x(), point.x())
+		}
+	}
+
+	fn white() -> Arc<dyn Material + Send + Sync> {
+		Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(1.0, 1.0, 1.0))))
+	}
+
+	// A hit in the middle of a size by size square facing +z, from the front
+	// or from behind.
+	fn hit(size: f64, front_face: bool) -> (Ray, HitRecord) {
+		let (normal, direction) = if front_face {
+			(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0))
+		} else {
+			(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0))
+		};
+		let point = Vec3::new(0.5 * size, 0.5 * size, 0.0);
+		let mut rec = HitRecord::new(point, normal, 1.0, front_face, white(), 0.5, 0.5);
+		rec.set_derivatives(Vec3::new(size, 0.0, 0.0), Vec3::new(0.0, size, 0.0));
+		(Ray::new(point - &direction, direction, 0.0), rec)
+	}
+
+	fn assert_near(a: &Vec3, b: &Vec3) {
+		assert!((*a - b).len() < 1.0e-6, "{}, {}, {} is not {}, {}, {}", a.x(), a.y(), a.z(), b.x(), b.y(), b.z());
+	}
+
+	// A height that rises by strength * slope per unit along x tilts the
+	// normal back by that slope, however big the surface is.
+	#[test]
+	fn bumps_by_the_height_slope() {
+		for size in [1.0, 4.0] {
+			for front_face in [true, false] {
+				let side = if front_face {
+					1.0
+				} else {
+					-1.0
+				};
+				let (ray, rec) = hit(size, front_face);
+				let bumped = NormalMapped::from_height(white(), Arc::new(RampInX), 0.5);
+				assert_near(bumped.shaded(&ray, &rec).normal(), &(Vec3::new(-0.5, 0.0, 1.0).unit() * side));
+				// In u, the slope per unit of x shrinks as the surface grows.
+				let bumped = NormalMapped::from_height(white(), Arc::new(RampInU), 0.5);
+				assert_near(bumped.shaded(&ray, &rec).normal(), &(Vec3::new(-0.5 / size, 0.0, 1.0).unit() * side));
+			}
+		}
+	}
+
+	// The bent normal lets some directions through that are below the
+	// geometry, and those are absorbed.
+	#[test]
+	fn keeps_light_on_its_side() {
+		let (ray, rec) = hit(1.0, true);
+		let bumped = NormalMapped::from_height(white(), Arc::new(RampInX), 2.0);
+		let below = Vec3::new(-1.0, 0.0, -0.2).unit();
+		let above = Vec3::new(-1.0, 0.0, 0.2).unit();
+		let shaded = bumped.shaded(&ray, &rec);
+		assert!(below.dot(shaded.normal()) > 0.0);
+		assert_eq!(bumped.pdf(&ray, &rec, &below), 0.0);
+		assert_eq!(bumped.eval(&ray, &rec, &below).r(), 0.0);
+		assert!(bumped.pdf(&ray, &rec, &above) > 0.0);
+		assert!(bumped.eval(&ray, &rec, &above).r() > 0.0);
+
+		random::seed(1);
+		let mut kept = 0;
+		for _ in 0..10_000 {
+			if let Some((_, scattered)) = bumped.scatter(&ray, &rec) {
+				assert!(scattered.direction().z() > 0.0);
+				kept += 1;
+			}
+		}
+		assert!(kept > 5_000 && kept < 10_000, "kept {}", kept);
+	}
+}
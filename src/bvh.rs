@@ -8,7 +8,9 @@ use crate::{
 	hitable::{HitList, HitRecord, Hitable},
 	random::random_int,
 	ray::Ray,
+	stats::count_bvh_node,
 };
+use std::cmp::Ordering;
 use std::sync::Arc;
 
 #[derive(Clone)]
@@ -26,7 +28,6 @@ impl BvhNode {
 		Self::new_slice(list, 0, list.len(), time0, time1)
 	}
 
-	#[allow(unused_assignments)]
 	pub fn new_slice(list: &mut HitList, start: usize, end: usize, time0: f64, time1: f64) -> Self {
 		let objects = list.objects_mut();
 
@@ -38,10 +39,13 @@ impl BvhNode {
 		};
 
 		let object_span = end - start;
-		let mut left = objects[start].clone();
-		let mut right = objects[start].clone();
+		let left;
+		let right;
 
-		if object_span == 2 {
+		if object_span == 1 {
+			left = objects[start].clone();
+			right = objects[start].clone();
+		} else if object_span == 2 {
 			if comparator(objects[start].clone(), objects[start + 1].clone()) {
 				left = objects[start].clone();
 				right = objects[start + 1].clone();
@@ -50,7 +54,15 @@ impl BvhNode {
 				right = objects[start].clone();
 			}
 		} else {
-			// std::sort(objects.begin() + start, objects.begin() + end, comparator);
+			objects[start..end].sort_by(|a, b| {
+				if comparator(a.clone(), b.clone()) {
+					Ordering::Less
+				} else if comparator(b.clone(), a.clone()) {
+					Ordering::Greater
+				} else {
+					Ordering::Equal
+				}
+			});
 			let mid = start + object_span / 2;
 			left = Arc::new(BvhNode::new_slice(list, start, mid, time0, time1));
 			right = Arc::new(BvhNode::new_slice(list, mid, end, time0, time1));
@@ -81,6 +93,7 @@ impl Hitable for BvhNode {
 	}
 
 	fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+		count_bvh_node();
 		let is_hit = self.bbox.hit(ray, t_min, t_max);
 		if !is_hit {
 			None
@@ -88,19 +101,11 @@ impl Hitable for BvhNode {
 			let hit_left = self.left.hit(ray, t_min, t_max);
 
 			if let Some(hl) = hit_left {
-				// In here hit_left hit, so now we check the right.
-				if let Some(hr) = self.right.hit(ray, t_min, hl.t()) {
-					Some(hr)
-				} else {
-					None
-				}
+				// In here hit_left hit, so now we check the right for anything closer.
+				self.right.hit(ray, t_min, hl.t()).or(Some(hl))
 			} else {
 				// If we get here, hit_left evaluated to None
-				if let Some(hr) = self.right.hit(ray, t_min, t_max) {
-					Some(hr)
-				} else {
-					None
-				}
+				self.right.hit(ray, t_min, t_max)
 			}
 		}
 	}
@@ -17,6 +17,7 @@ pub struct Camera {
 }
 
 impl Camera {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(look_from: Vec3, look_at: Vec3, vup: Vec3, vfov: f64, aspect_ratio: f64, aperature: f64, focus_dist: f64, time0: f64, time1: f64) -> Self {
         let theta = vfov.to_radians();
         let h = (theta / 2.0).tan();
@@ -52,11 +53,19 @@ impl Camera {
         &self.origin
     }
 
+    pub fn time0(&self) -> f64 {
+        self.time0
+    }
+
+    pub fn time1(&self) -> f64 {
+        self.time1
+    }
+
     pub fn get_ray(&self, s: f64, t: f64) -> Ray {
         let rd = random_in_unit_disk() * self.lens_radius;
         let offset = self.u * rd.x() + &(self.v * rd.y());
         let timeoff = random_double(self.time0, self.time1);
-        Ray::new(self.origin().clone() + &offset, self.lower_left_corner + &(self.horizontal * s) + &(self.vertical * t) - self.origin() - &offset, timeoff)
+        Ray::new(*self.origin() + &offset, self.lower_left_corner + &(self.horizontal * s) + &(self.vertical * t) - self.origin() - &offset, timeoff)
     }
 }
 
@@ -0,0 +1,274 @@
+// checkpoint.rs
+// Render checkpoints, so long renders can be resumed or extended
+
+use crate::denoise::GuideBuffer;
+use crate::light_sampler::LightSampling;
+use crate::png::PngPicture;
+use crate::progressive::NoiseEstimate;
+use crate::renderer::RenderSettings;
+use crate::vector::Vec3;
+use std::fs::{rename, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+use std::vec::Vec;
+
+const MAGIC: &[u8; 8] = b"RTCKPT02";
+const OLD_MAGIC: &[u8; 8] = b"RTCKPT01";
+
+// Everything needed to pick a render back up: the settings it was started
+// with, the accumulated samples, and where the random sequence left off.
+// Each pass reseeds the workers from the seed and the pass number, so the
+// pass count is all of the RNG state that has to be kept.
+pub struct Checkpoint {
+	pub width: u32,
+	pub height: u32,
+	pub max_depth: i32,
+	pub seed: u64,
+	// The sample count the render was working toward, and its pass size, or
+	// zero for a single pass.
+	pub samples: u32,
+	pub pass_samples: u32,
+	pub filter: String,
+	pub filter_radius: f64,
+	pub spectral: bool,
+	pub light_sampling: LightSampling,
+	pub samples_done: u32,
+	pub passes: u32,
+	pub sample_counts: Vec<u32>,
+	pub picture: PngPicture,
+	pub guides: GuideBuffer,
+	pub noise: NoiseEstimate,
+}
+
+impl Checkpoint {
+	// The state of a render that hasn't traced anything yet.
+	pub fn new(settings: &RenderSettings) -> Self {
+		let (width, height) = (settings.width, settings.height);
+		Self {
+			width,
+			height,
+			max_depth: settings.max_depth,
+			seed: settings.seed,
+			samples: settings.samples,
+			pass_samples: settings.progressive.pass_samples().unwrap_or(0),
+			filter: String::from(settings.filter.name()),
+			filter_radius: settings.filter.radius(),
+			spectral: settings.spectral,
+			light_sampling: settings.light_sampling,
+			samples_done: 0,
+			passes: 0,
+			sample_counts: vec![0; (width * height) as usize],
+			picture: PngPicture::new(width, height),
+			guides: GuideBuffer::new(width, height),
+			noise: NoiseEstimate::new(width, height),
+		}
+	}
+
+	// Whether the samples taken so far can be added to with these settings.
+	// Differences that would mix two different images are an error. Ones
+	// that only change how the rest is rendered are warnings, and the
+	// checkpoint takes the new settings on.
+	pub fn accept_settings(&mut self, settings: &RenderSettings) -> Result<Vec<String>, String> {
+		if self.width != settings.width || self.height != settings.height {
+			return Err(format!("checkpoint is {}x{}, but the image is {}x{}", self.width, self.height, settings.width, settings.height));
+		}
+		if self.filter != settings.filter.name() || self.filter_radius != settings.filter.radius() {
+			return Err(format!("checkpoint used the {} filter of radius {}, not {} of radius {}", self.filter, self.filter_radius, settings.filter.name(), settings.filter.radius()));
+		}
+		if self.max_depth != settings.max_depth {
+			return Err(format!("checkpoint traced to a max depth of {}, not {}", self.max_depth, settings.max_depth));
+		}
+		if self.spectral != settings.spectral {
+			let mode = |spectral: bool| {
+				if spectral {
+					"spectral"
+				} else {
+					"RGB"
+				}
+			};
+			return Err(format!("checkpoint was rendered in {} mode, not {}", mode(self.spectral), mode(settings.spectral)));
+		}
+		let mut warnings = Vec::new();
+		if self.samples != settings.samples {
+			warnings.push(format!("checkpoint was rendering to {} samples per pixel, now {}", self.samples, settings.samples));
+		}
+		let pass_samples = settings.progressive.pass_samples().unwrap_or(0);
+		if self.pass_samples != pass_samples {
+			warnings.push(format!("checkpoint used passes of {} samples, now {}", self.pass_samples, pass_samples));
+		}
+		if self.light_sampling != settings.light_sampling {
+			warnings.push(format!("checkpoint used {} light sampling, now {}", self.light_sampling.name(), settings.light_sampling.name()));
+		}
+		self.samples = settings.samples;
+		self.pass_samples = pass_samples;
+		self.light_sampling = settings.light_sampling;
+		Ok(warnings)
+	}
+
+	pub fn write_file(&self, fname: &str) -> Result<(), Error> {
+		// Write to the side and then rename, so a kill mid-write can't
+		// destroy the last good checkpoint.
+		let tmpname = format!("{}.tmp", fname);
+		{
+			let mut wd = BufWriter::new(File::create(&tmpname)?);
+			wd.write_all(MAGIC)?;
+			write_u32(&mut wd, self.width)?;
+			write_u32(&mut wd, self.height)?;
+			write_u32(&mut wd, self.max_depth as u32)?;
+			write_u64(&mut wd, self.seed)?;
+			write_u32(&mut wd, self.samples)?;
+			write_u32(&mut wd, self.pass_samples)?;
+			write_str(&mut wd, &self.filter)?;
+			write_f64(&mut wd, self.filter_radius)?;
+			write_u32(&mut wd, self.spectral as u32)?;
+			write_str(&mut wd, self.light_sampling.name())?;
+			write_u32(&mut wd, self.samples_done)?;
+			write_u32(&mut wd, self.passes)?;
+			for count in self.sample_counts.iter() {
+				write_u32(&mut wd, *count)?;
+			}
+			self.picture.write_checkpoint(&mut wd)?;
+			self.guides.write_checkpoint(&mut wd)?;
+			self.noise.write_checkpoint(&mut wd)?;
+			wd.flush()?;
+		}
+		rename(&tmpname, fname)
+	}
+
+	pub fn read_file(fname: &str) -> Result<Self, Error> {
+		let mut rd = BufReader::new(File::open(fname)?);
+		let mut magic = [0u8; 8];
+		rd.read_exact(&mut magic)?;
+		if &magic == OLD_MAGIC {
+			return Err(Error::new(ErrorKind::InvalidData, "checkpoint is from an older version without its settings"));
+		}
+		if &magic != MAGIC {
+			return Err(Error::new(ErrorKind::InvalidData, "not a raytrace checkpoint"));
+		}
+		let width = read_u32(&mut rd)?;
+		let height = read_u32(&mut rd)?;
+		let max_depth = read_u32(&mut rd)? as i32;
+		let seed = read_u64(&mut rd)?;
+		let samples = read_u32(&mut rd)?;
+		let pass_samples = read_u32(&mut rd)?;
+		let filter = read_str(&mut rd)?;
+		let filter_radius = read_f64(&mut rd)?;
+		let spectral = read_u32(&mut rd)? != 0;
+		let light_sampling = LightSampling::from_name(&read_str(&mut rd)?).ok_or_else(|| Error::new(ErrorKind::InvalidData, "unknown light sampling in checkpoint"))?;
+		let samples_done = read_u32(&mut rd)?;
+		let passes = read_u32(&mut rd)?;
+		let mut sample_counts = Vec::with_capacity((width * height) as usize);
+		for _ in 0..width * height {
+			sample_counts.push(read_u32(&mut rd)?);
+		}
+		let picture = PngPicture::read_checkpoint(&mut rd, width, height)?;
+		let guides = GuideBuffer::read_checkpoint(&mut rd, width, height)?;
+		let noise = NoiseEstimate::read_checkpoint(&mut rd, width, height)?;
+		Ok(Self {
+			width,
+			height,
+			max_depth,
+			seed,
+			samples,
+			pass_samples,
+			filter,
+			filter_radius,
+			spectral,
+			light_sampling,
+			samples_done,
+			passes,
+			sample_counts,
+			picture,
+			guides,
+			noise,
+		})
+	}
+}
+
+// Decides when a render in progress should be saved.
+pub struct Checkpointer {
+	filename: String,
+	interval: Duration,
+	last: Instant,
+}
+
+impl Checkpointer {
+	pub fn new(filename: &str, interval: Duration) -> Self {
+		Self {
+			filename: String::from(filename),
+			interval,
+			last: Instant::now(),
+		}
+	}
+
+	pub fn filename(&self) -> &str {
+		&self.filename
+	}
+
+	pub fn due(&self) -> bool {
+		self.last.elapsed() >= self.interval
+	}
+
+	// A failed save still restarts the interval, so it isn't retried after every pass.
+	pub fn save(&mut self, checkpoint: &Checkpoint) -> Result<(), Error> {
+		self.last = Instant::now();
+		checkpoint.write_file(&self.filename)
+	}
+}
+
+// Checkpoints are little endian regardless of the machine that wrote them.
+
+pub fn write_u32<W: Write>(w: &mut W, val: u32) -> Result<(), Error> {
+	w.write_all(&val.to_le_bytes())
+}
+
+pub fn write_u64<W: Write>(w: &mut W, val: u64) -> Result<(), Error> {
+	w.write_all(&val.to_le_bytes())
+}
+
+pub fn write_f64<W: Write>(w: &mut W, val: f64) -> Result<(), Error> {
+	w.write_all(&val.to_le_bytes())
+}
+
+pub fn write_vec3<W: Write>(w: &mut W, val: &Vec3) -> Result<(), Error> {
+	write_f64(w, val.x())?;
+	write_f64(w, val.y())?;
+	write_f64(w, val.z())
+}
+
+// Strings are their length and then their bytes.
+pub fn write_str<W: Write>(w: &mut W, val: &str) -> Result<(), Error> {
+	write_u32(w, val.len() as u32)?;
+	w.write_all(val.as_bytes())
+}
+
+pub fn read_u32<R: Read>(r: &mut R) -> Result<u32, Error> {
+	let mut buf = [0u8; 4];
+	r.read_exact(&mut buf)?;
+	Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64<R: Read>(r: &mut R) -> Result<u64, Error> {
+	let mut buf = [0u8; 8];
+	r.read_exact(&mut buf)?;
+	Ok(u64::from_le_bytes(buf))
+}
+
+pub fn read_f64<R: Read>(r: &mut R) -> Result<f64, Error> {
+	let mut buf = [0u8; 8];
+	r.read_exact(&mut buf)?;
+	Ok(f64::from_le_bytes(buf))
+}
+
+pub fn read_str<R: Read>(r: &mut R) -> Result<String, Error> {
+	let mut buf = vec![0u8; read_u32(r)? as usize];
+	r.read_exact(&mut buf)?;
+	String::from_utf8(buf).map_err(|_| Error::new(ErrorKind::InvalidData, "string in checkpoint is not UTF-8"))
+}
+
+pub fn read_vec3<R: Read>(r: &mut R) -> Result<Vec3, Error> {
+	let x = read_f64(r)?;
+	let y = read_f64(r)?;
+	let z = read_f64(r)?;
+	Ok(Vec3::new(x, y, z))
+}
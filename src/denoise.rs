@@ -0,0 +1,199 @@
+// denoise.rs
+// Edge-avoiding A-trous wavelet denoiser
+
+use crate::checkpoint::{read_f64, read_vec3, write_f64, write_vec3};
+use crate::vector::{Color, Vec3};
+use std::io::{Error, Read, Write};
+use std::vec::Vec;
+
+// B3-spline coefficients used by every pass of the a-trous filter.
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+const DEFAULT_ITERATIONS: u32 = 5;
+const SIGMA_NORMAL: f64 = 0.1;
+const SIGMA_ALBEDO: f64 = 0.1;
+const SIGMA_DEPTH: f64 = 0.05;
+
+// The guide images (AOVs) recorded at the first hit of each camera ray.
+// These are averaged over all samples of a pixel, and they are used to
+// keep the filter from blurring across geometric and texture edges.
+pub struct GuideBuffer {
+	width: u32,
+	height: u32,
+	albedo: Vec<Color>,
+	normal: Vec<Vec3>,
+	depth: Vec<f64>,
+}
+
+impl GuideBuffer {
+	pub fn new(width: u32, height: u32) -> Self {
+		let size = (width * height) as usize;
+		Self {
+			width,
+			height,
+			albedo: vec![Color::default(); size],
+			normal: vec![Vec3::default(); size],
+			depth: vec![0.0; size],
+		}
+	}
+
+	pub fn set_pixel(&mut self, x: u32, y: u32, albedo: &Color, normal: &Vec3, depth: f64) {
+		let idx = (y * self.width + x) as usize;
+		self.albedo[idx] = *albedo;
+		self.normal[idx] = *normal;
+		self.depth[idx] = depth;
+	}
+
+	pub fn write_checkpoint<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+		for i in 0..self.albedo.len() {
+			write_vec3(w, &self.albedo[i])?;
+			write_vec3(w, &self.normal[i])?;
+			write_f64(w, self.depth[i])?;
+		}
+		Ok(())
+	}
+
+	pub fn read_checkpoint<R: Read>(r: &mut R, width: u32, height: u32) -> Result<Self, Error> {
+		let mut guides = Self::new(width, height);
+		for i in 0..guides.albedo.len() {
+			guides.albedo[i] = read_vec3(r)?;
+			guides.normal[i] = read_vec3(r)?;
+			guides.depth[i] = read_f64(r)?;
+		}
+		Ok(guides)
+	}
+
+	// Move this buffer toward another one by t. Progressive renders use this
+	// to keep a running average of the guides over all passes.
+	pub fn blend(&mut self, other: &GuideBuffer, t: f64) {
+		for (a, b) in self.albedo.iter_mut().zip(other.albedo.iter()) {
+			*a = *a * (1.0 - t) + &(*b * t);
+		}
+		for (a, b) in self.normal.iter_mut().zip(other.normal.iter()) {
+			*a = *a * (1.0 - t) + &(*b * t);
+		}
+		for (a, b) in self.depth.iter_mut().zip(other.depth.iter()) {
+			*a = *a * (1.0 - t) + *b * t;
+		}
+	}
+
+	pub fn get_width(&self) -> u32 {
+		self.width
+	}
+
+	pub fn get_height(&self) -> u32 {
+		self.height
+	}
+}
+
+#[derive(Clone)]
+pub struct Denoiser {
+	strength: f64,
+	iterations: u32,
+}
+
+impl Default for Denoiser {
+	fn default() -> Self {
+		Self::new(1.0)
+	}
+}
+
+impl Denoiser {
+	pub fn new(strength: f64) -> Self {
+		Self {
+			strength,
+			iterations: DEFAULT_ITERATIONS,
+		}
+	}
+
+	pub fn with_iterations(strength: f64, iterations: u32) -> Self {
+		Self {
+			strength,
+			iterations,
+		}
+	}
+
+	pub fn strength(&self) -> f64 {
+		self.strength
+	}
+
+	pub fn iterations(&self) -> u32 {
+		self.iterations
+	}
+
+	// Filter the color buffer in place. The colors must already be
+	// normalized by the number of samples, but not yet tone mapped.
+	// The buffer is laid out row-major, the same as the guides.
+	pub fn denoise(&self, color: &mut Vec<Color>, guides: &GuideBuffer) {
+		let width = guides.get_width() as i64;
+		let height = guides.get_height() as i64;
+		assert_eq!(color.len(), (width * height) as usize);
+		if self.strength <= 0.0 {
+			return;
+		}
+
+		// Filter the illumination rather than the color, so that textures
+		// (which the albedo guide already knows about) stay sharp.
+		for (c, a) in color.iter_mut().zip(guides.albedo.iter()) {
+			*c /= &demodulation(a);
+		}
+
+		let mut output = vec![Color::default(); color.len()];
+		// The color weight is the only one that depends on strength. It
+		// shrinks every pass, since each pass sees a smoother image.
+		let mut sigma_color = self.strength;
+		for iteration in 0..self.iterations {
+			let step = 1i64 << iteration;
+			for y in 0..height {
+				for x in 0..width {
+					let p = (y * width + x) as usize;
+					let mut sum = Color::default();
+					let mut weight_sum = 0.0;
+					for (dy, ky) in KERNEL.iter().enumerate() {
+						let qy = y + (dy as i64 - 2) * step;
+						if qy < 0 || qy >= height {
+							continue;
+						}
+						for (dx, kx) in KERNEL.iter().enumerate() {
+							let qx = x + (dx as i64 - 2) * step;
+							if qx < 0 || qx >= width {
+								continue;
+							}
+							let q = (qy * width + qx) as usize;
+							let w = kx * ky * self.edge_weight(color, guides, p, q, sigma_color, step as f64);
+							sum += &(color[q] * w);
+							weight_sum += w;
+						}
+					}
+					// The center tap always has a weight of 9/64, so this can't be zero.
+					output[p] = sum / weight_sum;
+				}
+			}
+			std::mem::swap(color, &mut output);
+			sigma_color *= 0.5;
+		}
+
+		for (c, a) in color.iter_mut().zip(guides.albedo.iter()) {
+			*c *= &demodulation(a);
+		}
+	}
+
+	fn edge_weight(&self, color: &[Color], guides: &GuideBuffer, p: usize, q: usize, sigma_color: f64, step: f64) -> f64 {
+		let dc = (color[p] - &color[q]).len2();
+		let dn = (guides.normal[p] - &guides.normal[q]).len2();
+		let da = (guides.albedo[p] - &guides.albedo[q]).len2();
+		// Depth is compared relative to the center pixel and the tap distance,
+		// so that slanted surfaces don't get cut off by the depth gradient.
+		let dd = (guides.depth[p] - guides.depth[q]).abs() / (SIGMA_DEPTH * step * guides.depth[p].max(1.0e-3));
+
+		let wc = (-dc / (sigma_color * sigma_color)).exp();
+		let wn = (-dn / (SIGMA_NORMAL * SIGMA_NORMAL)).exp();
+		let wa = (-da / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp();
+		let wd = (-dd).exp();
+		wc * wn * wa * wd
+	}
+}
+
+// Escaped rays and black surfaces have no albedo to divide out.
+fn demodulation(albedo: &Color) -> Color {
+	Color::new(albedo.r().max(0.01), albedo.g().max(0.01), albedo.b().max(0.01))
+}
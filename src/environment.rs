@@ -0,0 +1,193 @@
+// environment.rs
+// Image based lighting from equirectangular HDR images
+
+use crate::background::Background;
+use crate::hdr::HdrImage;
+use crate::random::random_f64;
+use crate::vector::{Color, Vec3};
+use std::f64::consts::PI;
+use std::io::{Error, ErrorKind};
+use std::vec::Vec;
+
+// The image wraps around the vertical (y) axis. The top row looks straight up,
+// the bottom row straight down. Directions are picked in proportion to the
+// luminance of the pixels, so small bright features like the sun are found
+// by direct light sampling instead of by chance.
+pub struct EnvironmentMap {
+	width: usize,
+	height: usize,
+	pixels: Vec<Color>,
+	// Turn about the y axis, in radians.
+	rotation: f64,
+	intensity: f64,
+	// Sampling weight of every pixel: its luminance times its solid angle.
+	weights: Vec<f64>,
+	total: f64,
+	// CDF over the rows, and within every row over its pixels.
+	marginal: Vec<f64>,
+	conditional: Vec<f64>,
+}
+
+impl EnvironmentMap {
+	// Rotation is in degrees.
+	pub fn new(width: usize, height: usize, pixels: Vec<Color>, rotation: f64, intensity: f64) -> Self {
+		assert_eq!(pixels.len(), width * height);
+		let mut weights = Vec::with_capacity(width * height);
+		for y in 0..height {
+			// Rows near the poles cover less of the sphere.
+			let sin_theta = ((y as f64 + 0.5) / height as f64 * PI).sin();
+			for x in 0..width {
+				let px = &pixels[y * width + x];
+				let lum = 0.2126 * px.r() + 0.7152 * px.g() + 0.0722 * px.b();
+				weights.push(lum.max(0.0) * sin_theta);
+			}
+		}
+
+		let mut marginal = Vec::with_capacity(height + 1);
+		let mut conditional = Vec::with_capacity(height * (width + 1));
+		let mut total = 0.0;
+		marginal.push(0.0);
+		for y in 0..height {
+			let row = &weights[y * width..(y + 1) * width];
+			build_cdf(row, &mut conditional);
+			total += row.iter().sum::<f64>();
+			marginal.push(total);
+		}
+		if total > 0.0 {
+			for m in marginal.iter_mut() {
+				*m /= total;
+			}
+		}
+
+		Self {
+			width,
+			height,
+			pixels,
+			rotation: rotation.to_radians(),
+			intensity,
+			weights,
+			total,
+			marginal,
+			conditional,
+		}
+	}
+
+	// Load a .hdr or .exr file, chosen by the extension.
+	pub fn from_file(fname: &str, rotation: f64, intensity: f64) -> Result<Self, Error> {
+		let lower = fname.to_lowercase();
+		if lower.ends_with(".hdr") {
+			let img = HdrImage::read_file(fname)?;
+			Ok(Self::new(img.width, img.height, img.pixels, rotation, intensity))
+		} else if lower.ends_with(".exr") {
+			let image = exr::prelude::read_first_rgba_layer_from_file(
+				fname,
+				|resolution, _| (resolution.width(), vec![Color::default(); resolution.width() * resolution.height()]),
+				|(width, pixels), pos, (r, g, b, _a): (f32, f32, f32, f32)| pixels[pos.y() * *width + pos.x()] = Color::new(r as f64, g as f64, b as f64),
+			)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+			let size = image.layer_data.size;
+			let (_, pixels) = image.layer_data.channel_data.pixels;
+			Ok(Self::new(size.width(), size.height(), pixels, rotation, intensity))
+		} else {
+			Err(Error::new(ErrorKind::InvalidInput, "environment maps must be .hdr or .exr"))
+		}
+	}
+
+	pub fn width(&self) -> usize {
+		self.width
+	}
+
+	pub fn height(&self) -> usize {
+		self.height
+	}
+
+	// The pixel a direction lands on, and the sine of its polar angle.
+	fn pixel_of(&self, direction: &Vec3) -> (usize, f64) {
+		let d = direction.unit();
+		let theta = d.y().clamp(-1.0, 1.0).acos();
+		let phi = (-d.z()).atan2(d.x()) + PI + self.rotation;
+		let u = (phi / (2.0 * PI)).rem_euclid(1.0);
+		let v = theta / PI;
+		let x = ((u * self.width as f64) as usize).min(self.width - 1);
+		let y = ((v * self.height as f64) as usize).min(self.height - 1);
+		(y * self.width + x, theta.sin())
+	}
+
+	// Converts the pdf of a pixel to a pdf over solid angle.
+	fn pdf_of(&self, idx: usize, sin_theta: f64) -> f64 {
+		if self.total <= 0.0 || sin_theta <= 0.0 {
+			return 0.0;
+		}
+		let pdf_uv = self.weights[idx] / self.total * (self.width * self.height) as f64;
+		pdf_uv / (2.0 * PI * PI * sin_theta)
+	}
+}
+
+impl Background for EnvironmentMap {
+	fn value(&self, direction: &Vec3) -> Color {
+		let (idx, _) = self.pixel_of(direction);
+		self.pixels[idx] * self.intensity
+	}
+
+	fn sample(&self) -> Option<(Vec3, Color, f64)> {
+		if self.total <= 0.0 {
+			return None;
+		}
+		let (y, fy) = sample_cdf(&self.marginal, random_f64());
+		let row = &self.conditional[y * (self.width + 1)..(y + 1) * (self.width + 1)];
+		let (x, fx) = sample_cdf(row, random_f64());
+
+		let u = (x as f64 + fx) / self.width as f64;
+		let v = (y as f64 + fy) / self.height as f64;
+		let theta = v * PI;
+		let phi = u * 2.0 * PI - PI - self.rotation;
+		let sin_theta = theta.sin();
+		let direction = Vec3::new(sin_theta * phi.cos(), theta.cos(), -sin_theta * phi.sin());
+
+		let idx = y * self.width + x;
+		let pdf = self.pdf_of(idx, sin_theta);
+		if pdf <= 0.0 {
+			return None;
+		}
+		Some((direction, self.pixels[idx] * self.intensity, pdf))
+	}
+
+	fn pdf(&self, direction: &Vec3) -> f64 {
+		let (idx, sin_theta) = self.pixel_of(direction);
+		self.pdf_of(idx, sin_theta)
+	}
+}
+
+// Appends the normalized CDF of weights (one longer than weights) to cdf. A
+// row with nothing in it gets a uniform CDF.
+fn build_cdf(weights: &[f64], cdf: &mut Vec<f64>) {
+	let start = cdf.len();
+	let mut sum = 0.0;
+	cdf.push(0.0);
+	for w in weights.iter() {
+		sum += w;
+		cdf.push(sum);
+	}
+	let n = weights.len() as f64;
+	for (i, c) in cdf[start..].iter_mut().enumerate() {
+		*c = if sum > 0.0 {
+			*c / sum
+		} else {
+			i as f64 / n
+		};
+	}
+}
+
+// Finds the bucket of the CDF that u falls into, and how far into it.
+fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+	let n = cdf.len() - 1;
+	// The first entry past u, so empty buckets are never picked.
+	let i = cdf.partition_point(|c| *c <= u).clamp(1, n) - 1;
+	let width = cdf[i + 1] - cdf[i];
+	let frac = if width > 0.0 {
+		((u - cdf[i]) / width).clamp(0.0, 1.0)
+	} else {
+		0.5
+	};
+	(i, frac)
+}
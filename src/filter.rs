@@ -0,0 +1,236 @@
+// filter.rs
+// Pixel reconstruction filters
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// A reconstruction filter decides how much a sample contributes to a pixel
+// based on the offset (in pixels) from the sample to the pixel's center.
+// Samples are splatted into every pixel within radius() of them.
+pub trait Filter {
+	// The name it's chosen by, which checkpoints record.
+	fn name(&self) -> &'static str;
+	fn radius(&self) -> f64;
+	fn evaluate(&self, x: f64, y: f64) -> f64;
+}
+
+// FILTERS
+
+// Box filter, every sample inside the radius counts the same.
+pub struct BoxFilter {
+	radius: f64,
+}
+
+impl Default for BoxFilter {
+	fn default() -> Self {
+		Self::new(0.5)
+	}
+}
+
+impl BoxFilter {
+	pub fn new(radius: f64) -> Self {
+		Self {
+			radius,
+		}
+	}
+}
+
+impl Filter for BoxFilter {
+	fn name(&self) -> &'static str {
+		"box"
+	}
+
+	fn radius(&self) -> f64 {
+		self.radius
+	}
+
+	fn evaluate(&self, x: f64, y: f64) -> f64 {
+		if x.abs() <= self.radius && y.abs() <= self.radius {
+			1.0
+		} else {
+			0.0
+		}
+	}
+}
+
+// Tent (triangle) filter, falls off linearly to zero at the radius.
+pub struct TentFilter {
+	radius: f64,
+}
+
+impl Default for TentFilter {
+	fn default() -> Self {
+		Self::new(1.0)
+	}
+}
+
+impl TentFilter {
+	pub fn new(radius: f64) -> Self {
+		Self {
+			radius,
+		}
+	}
+}
+
+impl Filter for TentFilter {
+	fn name(&self) -> &'static str {
+		"tent"
+	}
+
+	fn radius(&self) -> f64 {
+		self.radius
+	}
+
+	fn evaluate(&self, x: f64, y: f64) -> f64 {
+		(self.radius - x.abs()).max(0.0) * (self.radius - y.abs()).max(0.0)
+	}
+}
+
+// Gaussian filter. The curve is shifted down so that it reaches zero at the
+// radius instead of being cut off abruptly.
+pub struct GaussianFilter {
+	radius: f64,
+	sigma: f64,
+}
+
+impl Default for GaussianFilter {
+	fn default() -> Self {
+		Self::new(1.5, 0.5)
+	}
+}
+
+impl GaussianFilter {
+	pub fn new(radius: f64, sigma: f64) -> Self {
+		Self {
+			radius,
+			sigma,
+		}
+	}
+
+	fn gaussian(&self, x: f64) -> f64 {
+		(-x * x / (2.0 * self.sigma * self.sigma)).exp()
+	}
+
+	fn evaluate_1d(&self, x: f64) -> f64 {
+		(self.gaussian(x) - self.gaussian(self.radius)).max(0.0)
+	}
+}
+
+impl Filter for GaussianFilter {
+	fn name(&self) -> &'static str {
+		"gaussian"
+	}
+
+	fn radius(&self) -> f64 {
+		self.radius
+	}
+
+	fn evaluate(&self, x: f64, y: f64) -> f64 {
+		self.evaluate_1d(x) * self.evaluate_1d(y)
+	}
+}
+
+// Mitchell-Netravali cubic filter. This has negative lobes, so it sharpens
+// a little, which is why B and C are tunable. B = C = 1/3 is the usual pick.
+pub struct MitchellFilter {
+	radius: f64,
+	b: f64,
+	c: f64,
+}
+
+impl Default for MitchellFilter {
+	fn default() -> Self {
+		Self::new(2.0, 1.0 / 3.0, 1.0 / 3.0)
+	}
+}
+
+impl MitchellFilter {
+	pub fn new(radius: f64, b: f64, c: f64) -> Self {
+		Self {
+			radius,
+			b,
+			c,
+		}
+	}
+
+	// The cubic is defined over [-2, 2], so x has to be scaled into that range first.
+	fn evaluate_1d(&self, x: f64) -> f64 {
+		let x = (2.0 * x / self.radius).abs();
+		let (b, c) = (self.b, self.c);
+		let value = if x > 2.0 {
+			0.0
+		} else if x > 1.0 {
+			(-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)
+		} else {
+			(12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)
+		};
+		value / 6.0
+	}
+}
+
+impl Filter for MitchellFilter {
+	fn name(&self) -> &'static str {
+		"mitchell"
+	}
+
+	fn radius(&self) -> f64 {
+		self.radius
+	}
+
+	fn evaluate(&self, x: f64, y: f64) -> f64 {
+		self.evaluate_1d(x) * self.evaluate_1d(y)
+	}
+}
+
+// Four term Blackman-Harris window, stretched over the radius.
+pub struct BlackmanHarrisFilter {
+	radius: f64,
+}
+
+impl Default for BlackmanHarrisFilter {
+	fn default() -> Self {
+		Self::new(2.0)
+	}
+}
+
+impl BlackmanHarrisFilter {
+	pub fn new(radius: f64) -> Self {
+		Self {
+			radius,
+		}
+	}
+
+	fn evaluate_1d(&self, x: f64) -> f64 {
+		if x.abs() > self.radius {
+			return 0.0;
+		}
+		let t = PI * x / self.radius;
+		0.35875 + 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() + 0.01168 * (3.0 * t).cos()
+	}
+}
+
+impl Filter for BlackmanHarrisFilter {
+	fn name(&self) -> &'static str {
+		"blackman-harris"
+	}
+
+	fn radius(&self) -> f64 {
+		self.radius
+	}
+
+	fn evaluate(&self, x: f64, y: f64) -> f64 {
+		self.evaluate_1d(x) * self.evaluate_1d(y)
+	}
+}
+
+// Look up a filter by the name given on the command line.
+pub fn from_name(name: &str) -> Option<Arc<dyn Filter + Send + Sync>> {
+	match name {
+		"box" => Some(Arc::new(BoxFilter::default())),
+		"tent" => Some(Arc::new(TentFilter::default())),
+		"gaussian" => Some(Arc::new(GaussianFilter::default())),
+		"mitchell" => Some(Arc::new(MitchellFilter::default())),
+		"blackman-harris" => Some(Arc::new(BlackmanHarrisFilter::default())),
+		_ => None,
+	}
+}
@@ -0,0 +1,160 @@
+// hdr.rs
+// Radiance RGBE (.hdr) image reader
+
+use crate::vector::Color;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::vec::Vec;
+
+// A floating point image, rows top to bottom.
+pub struct HdrImage {
+	pub width: usize,
+	pub height: usize,
+	pub pixels: Vec<Color>,
+}
+
+fn invalid(msg: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, msg)
+}
+
+// Every pixel shares one exponent among its three mantissas.
+fn rgbe_to_color(rgbe: &[u8]) -> Color {
+	if rgbe[3] == 0 {
+		return Color::new(0.0, 0.0, 0.0);
+	}
+	let scale = 2.0f64.powi(rgbe[3] as i32 - (128 + 8));
+	Color::new(rgbe[0] as f64 * scale, rgbe[1] as f64 * scale, rgbe[2] as f64 * scale)
+}
+
+// Scanlines are either flat RGBE quads, or (in the common "new" format) run
+// length encoded one component at a time.
+fn read_scanline<R: Read>(rd: &mut R, width: usize, line: &mut [u8]) -> Result<(), Error> {
+	let mut head = [0u8; 4];
+	rd.read_exact(&mut head)?;
+	if !(8..=0x7fff).contains(&width) || head[0] != 2 || head[1] != 2 || head[2] & 0x80 != 0 {
+		line[..4].copy_from_slice(&head);
+		return rd.read_exact(&mut line[4..]);
+	}
+	if ((head[2] as usize) << 8 | head[3] as usize) != width {
+		return Err(invalid("scanline width mismatch"));
+	}
+	for component in 0..4 {
+		let mut x = 0;
+		while x < width {
+			let mut count = [0u8; 1];
+			rd.read_exact(&mut count)?;
+			let mut count = count[0] as usize;
+			if count > 128 {
+				count -= 128;
+				if x + count > width {
+					return Err(invalid("bad scanline run"));
+				}
+				let mut val = [0u8; 1];
+				rd.read_exact(&mut val)?;
+				for _ in 0..count {
+					line[x * 4 + component] = val[0];
+					x += 1;
+				}
+			} else {
+				if count == 0 || x + count > width {
+					return Err(invalid("bad scanline run"));
+				}
+				let mut vals = vec![0u8; count];
+				rd.read_exact(&mut vals)?;
+				for val in vals.iter() {
+					line[x * 4 + component] = *val;
+					x += 1;
+				}
+			}
+		}
+	}
+	Ok(())
+}
+
+impl HdrImage {
+	pub fn read_file(fname: &str) -> Result<Self, Error> {
+		Self::read(&mut BufReader::new(File::open(fname)?))
+	}
+
+	fn read<R: BufRead>(rd: &mut R) -> Result<Self, Error> {
+		let mut line = String::new();
+		rd.read_line(&mut line)?;
+		if !line.starts_with("#?") {
+			return Err(invalid("not a Radiance HDR file"));
+		}
+		// The header is a list of variables, ended by a blank line.
+		loop {
+			line.clear();
+			if rd.read_line(&mut line)? == 0 {
+				return Err(invalid("truncated header"));
+			}
+			let l = line.trim();
+			if l.is_empty() {
+				break;
+			}
+			if let Some(format) = l.strip_prefix("FORMAT=") {
+				if format != "32-bit_rle_rgbe" {
+					return Err(invalid("only RGBE pixels are supported"));
+				}
+			}
+		}
+		// Only the standard orientation, top to bottom and left to right.
+		line.clear();
+		rd.read_line(&mut line)?;
+		let fields: Vec<&str> = line.split_whitespace().collect();
+		if fields.len() != 4 || fields[0] != "-Y" || fields[2] != "+X" {
+			return Err(invalid("unsupported resolution line"));
+		}
+		let height = fields[1].parse::<usize>().map_err(|_| invalid("bad height"))?;
+		let width = fields[3].parse::<usize>().map_err(|_| invalid("bad width"))?;
+		if width == 0 || height == 0 {
+			return Err(invalid("empty image"));
+		}
+		// The size comes straight from the file, so a bad one has to fail
+		// here rather than overflow or abort the allocation.
+		let count = width.checked_mul(height).ok_or_else(|| invalid("image too large"))?;
+		let mut pixels = Vec::new();
+		pixels.try_reserve_exact(count).map_err(|_| invalid("image too large"))?;
+		let mut scanline = vec![0u8; width * 4];
+		for _ in 0..height {
+			read_scanline(rd, width, &mut scanline)?;
+			for rgbe in scanline.chunks(4) {
+				pixels.push(rgbe_to_color(rgbe));
+			}
+		}
+		Ok(Self {
+			width,
+			height,
+			pixels,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+
+	fn read(resolution: &str, pixels: &[u8]) -> Result<HdrImage, Error> {
+		let mut file = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n{}\n", resolution).into_bytes();
+		file.extend_from_slice(pixels);
+		HdrImage::read(&mut Cursor::new(file))
+	}
+
+	#[test]
+	fn reads_flat_pixels() {
+		let image = read("-Y 1 +X 2", &[128, 64, 0, 129, 0, 0, 0, 0]).unwrap();
+		assert_eq!((image.width, image.height), (2, 1));
+		assert_eq!(image.pixels[0].x(), 1.0);
+		assert_eq!(image.pixels[0].y(), 0.5);
+		assert_eq!(image.pixels[1].x(), 0.0);
+	}
+
+	#[test]
+	fn rejects_bad_sizes() {
+		for resolution in ["-Y 1 +X 0", "-Y 0 +X 1", "-Y 18446744073709551615 +X 2", "-Y 4294967296 +X 4294967296"].iter() {
+			let error = read(resolution, &[]).err().unwrap();
+			assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", resolution);
+		}
+	}
+}
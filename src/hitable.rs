@@ -1,38 +1,75 @@
+use crate::light_sampler::LightBounds;
 use crate::texture::SolidColor;
-use crate::{bounding_box::AxisAlignedBoundingBox, material::Material, ray::Ray, vector::Vec3};
+use crate::{bounding_box::AxisAlignedBoundingBox, material::{Lambertian, Material}, ray::Ray, vector::Vec3};
 use std::{sync::Arc, vec::Vec};
 
+#[derive(Clone)]
 pub struct HitRecord {
 	point: Vec3,
+	// The normal used for shading, which normal and bump maps may bend away
+	// from the geometric normal. Both face against the ray.
 	normal: Vec3,
+	geometric_normal: Vec3,
 	t: f64,
-	material: Material,
+	material: Arc<dyn Material + Send + Sync>,
 	front_face: bool,
 	u: f64,
 	v: f64,
+	// How the point moves with u and v. dpdu is the tangent anisotropic
+	// materials are aligned to, and bump maps step along both. Zero when the
+	// shape doesn't have them.
+	dpdu: Vec3,
+	dpdv: Vec3,
+	// Identifies the object that was hit, see object_id().
+	object: usize,
 }
 
 impl Default for HitRecord {
 	fn default() -> Self {
-		Self::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0), 0.0, false, Material::new_lambertian(Arc::new(SolidColor::from_rgb(0.5, 0.25, 0.75))), 0.0, 0.0)
+		Self::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.0), 0.0, false, Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(0.5, 0.25, 0.75)))), 0.0, 0.0)
 	}
 }
 
 impl HitRecord {
-	pub fn new(point: Vec3, normal: Vec3, t: f64, front_face: bool, material: Material, u: f64, v: f64) -> Self {
+	pub fn new(point: Vec3, normal: Vec3, t: f64, front_face: bool, material: Arc<dyn Material + Send + Sync>, u: f64, v: f64) -> Self {
 		Self {
 			point,
 			normal,
+			geometric_normal: normal,
 			t,
 			material,
 			front_face,
 			u,
 			v,
+			dpdu: Vec3::new(0.0, 0.0, 0.0),
+			dpdv: Vec3::new(0.0, 0.0, 0.0),
+			object: 0,
 		}
 	}
 
-	pub fn material(&self) -> &Material {
-		&self.material
+	pub fn set_derivatives(&mut self, dpdu: Vec3, dpdv: Vec3) {
+		self.dpdu = dpdu;
+		self.dpdv = dpdv;
+	}
+
+	pub fn dpdu(&self) -> &Vec3 {
+		&self.dpdu
+	}
+
+	pub fn dpdv(&self) -> &Vec3 {
+		&self.dpdv
+	}
+
+	pub fn set_object(&mut self, object: &dyn Hitable) {
+		self.object = object_id(object);
+	}
+
+	pub fn object(&self) -> usize {
+		self.object
+	}
+
+	pub fn material(&self) -> &(dyn Material + Send + Sync) {
+		self.material.as_ref()
 	}
 
 	pub fn point(&self) -> &Vec3 {
@@ -43,6 +80,14 @@ impl HitRecord {
 		&self.normal
 	}
 
+	pub fn set_shading_normal(&mut self, normal: Vec3) {
+		self.normal = normal;
+	}
+
+	pub fn geometric_normal(&self) -> &Vec3 {
+		&self.geometric_normal
+	}
+
 	pub fn t(&self) -> f64 {
 		self.t
 	}
@@ -66,8 +111,34 @@ impl HitRecord {
 
 pub trait Hitable {
 	fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+	// Like hit(), but also where the material lets rays through. Light
+	// sampling needs the surface itself, not the random choice hit() makes on
+	// partly opaque ones.
+	fn hit_surface(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+		self.hit(ray, t_min, t_max)
+	}
 	fn bounding_box(&self, time0: f64, time1: f64) -> Option<AxisAlignedBoundingBox>;
 	fn translate(&mut self, x: f64, y: f64, z: f64);
+
+	// Objects with an emissive material can be sampled as lights. These tell
+	// how bright they are and where they shine, and None for everything else.
+	fn light_bounds(&self) -> Option<LightBounds> {
+		None
+	}
+	// The pdf (per solid angle) of random() picking this direction from origin.
+	fn pdf_value(&self, _origin: &Vec3, _direction: &Vec3) -> f64 {
+		0.0
+	}
+	// A random direction from origin toward this object.
+	fn random(&self, _origin: &Vec3) -> Vec3 {
+		Vec3::new(1.0, 0.0, 0.0)
+	}
+}
+
+// Objects are told apart by their address, which doesn't change once they are
+// shared in an Arc.
+pub fn object_id(object: &dyn Hitable) -> usize {
+	object as *const dyn Hitable as *const () as usize
 }
 
 #[derive(Default, Clone)]
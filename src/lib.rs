@@ -0,0 +1,36 @@
+// lib.rs
+// Raytracer library
+
+pub mod background;
+pub mod bmp;
+pub mod bounding_box;
+pub mod bump;
+pub mod bvh;
+pub mod camera;
+pub mod checkpoint;
+pub mod denoise;
+pub mod environment;
+pub mod filter;
+pub mod hdr;
+pub mod hitable;
+pub mod light;
+pub mod light_sampler;
+pub mod material;
+pub mod medium;
+pub mod merl;
+pub mod microfacet;
+pub mod nodes;
+pub mod objects;
+pub mod perlin;
+pub mod png;
+pub mod principled;
+pub mod progressive;
+pub mod random;
+pub mod ray;
+pub mod renderer;
+pub mod sky;
+pub mod spectrum;
+pub mod stats;
+pub mod texture;
+pub mod threadpool;
+pub mod vector;
@@ -0,0 +1,492 @@
+// light.rs
+// Analytic lights that aren't part of the geometry
+
+use crate::bounding_box::AxisAlignedBoundingBox;
+use crate::hitable::{object_id, HitList, Hitable};
+use crate::light_sampler::{LightBounds, LightBvh, LightSampling, PowerSampler};
+use crate::random::random_f64;
+use crate::ray::Ray;
+use crate::vector::{Color, Vec3};
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::sync::Arc;
+use std::vec::Vec;
+
+// Light arriving at a point: the direction toward the light, how far away it
+// is, the incident radiance, and the pdf (per solid angle) of picking that
+// direction. Delta lights can't be hit by rays, so their radiance already
+// includes the inverse square falloff and their pdf is 1.
+pub struct LightSample {
+	pub direction: Vec3,
+	pub distance: f64,
+	pub radiance: Color,
+	pub pdf: f64,
+	pub delta: bool,
+}
+
+pub trait Light {
+	fn sample(&self, point: &Vec3, time: f64) -> Option<LightSample>;
+	// The pdf of sample() picking this direction. Zero for delta lights, which
+	// no other sampling technique can find.
+	fn pdf(&self, _point: &Vec3, _direction: &Vec3) -> f64 {
+		0.0
+	}
+	// Total emitted power, for choosing between lights. Lights at infinity
+	// need the radius of the scene they shine on.
+	fn power(&self, scene_radius: f64) -> f64;
+	// None for lights at infinity.
+	fn bounds(&self) -> Option<LightBounds>;
+	// The id of the object that emits this light, if it's part of the scene.
+	fn object(&self) -> Option<usize> {
+		None
+	}
+}
+
+// Radiance is in W/(m^2 sr), the same units as emissive materials and the
+// background. Colors tint the light and should stay between 0 and 1, the
+// brightness comes from the power or irradiance.
+
+// Shines equally in every direction.
+pub struct PointLight {
+	position: Vec3,
+	color: Color,
+	// Total emitted power, in watts.
+	power: f64,
+}
+
+impl PointLight {
+	pub fn new(position: Vec3, color: Color, power: f64) -> Self {
+		Self {
+			position,
+			color,
+			power,
+		}
+	}
+}
+
+impl Light for PointLight {
+	fn sample(&self, point: &Vec3, _time: f64) -> Option<LightSample> {
+		let to_light = self.position - point;
+		let dist2 = to_light.len2();
+		if dist2 <= 0.0 {
+			return None;
+		}
+		let intensity = self.power / (4.0 * PI);
+		Some(LightSample {
+			direction: to_light.unit(),
+			distance: dist2.sqrt(),
+			radiance: self.color * (intensity / dist2),
+			pdf: 1.0,
+			delta: true,
+		})
+	}
+
+	fn power(&self, _scene_radius: f64) -> f64 {
+		self.power * self.color.luminance()
+	}
+
+	fn bounds(&self) -> Option<LightBounds> {
+		let bounds = AxisAlignedBoundingBox::new(self.position, self.position);
+		let intensity = self.power / (4.0 * PI) * self.color.luminance();
+		Some(LightBounds::new(bounds, Vec3::new(0.0, 0.0, 1.0), intensity, -1.0, 0.0, false))
+	}
+}
+
+// A point light limited to a cone. Inside the inner angle it is at full
+// strength, and it fades out toward the outer angle.
+pub struct SpotLight {
+	position: Vec3,
+	direction: Vec3,
+	cos_inner: f64,
+	cos_outer: f64,
+	// Exponent of the fade between the cones. 1 is linear in the cosine.
+	falloff: f64,
+	color: Color,
+	power: f64,
+}
+
+impl SpotLight {
+	// The cone angles are in degrees, measured from the direction the spot points in.
+	pub fn new(position: Vec3, direction: Vec3, inner: f64, outer: f64, falloff: f64, color: Color, power: f64) -> Self {
+		let outer = outer.max(inner);
+		Self {
+			position,
+			direction: direction.unit(),
+			cos_inner: inner.to_radians().cos(),
+			cos_outer: outer.to_radians().cos(),
+			falloff,
+			color,
+			power,
+		}
+	}
+
+	fn intensity(&self) -> f64 {
+		// The power is spread over the cone, counting the fade as half strength.
+		let solid_angle = 2.0 * PI * (1.0 - 0.5 * (self.cos_inner + self.cos_outer));
+		self.power / solid_angle
+	}
+
+	fn fade(&self, cos_theta: f64) -> f64 {
+		if cos_theta >= self.cos_inner {
+			1.0
+		} else if cos_theta <= self.cos_outer {
+			0.0
+		} else {
+			((cos_theta - self.cos_outer) / (self.cos_inner - self.cos_outer)).powf(self.falloff)
+		}
+	}
+}
+
+impl Light for SpotLight {
+	fn sample(&self, point: &Vec3, _time: f64) -> Option<LightSample> {
+		let to_light = self.position - point;
+		let dist2 = to_light.len2();
+		if dist2 <= 0.0 {
+			return None;
+		}
+		let direction = to_light.unit();
+		let fade = self.fade(-direction.dot(&self.direction));
+		if fade <= 0.0 {
+			return None;
+		}
+		Some(LightSample {
+			direction,
+			distance: dist2.sqrt(),
+			radiance: self.color * (self.intensity() * fade / dist2),
+			pdf: 1.0,
+			delta: true,
+		})
+	}
+
+	fn power(&self, _scene_radius: f64) -> f64 {
+		self.power * self.color.luminance()
+	}
+
+	fn bounds(&self) -> Option<LightBounds> {
+		let bounds = AxisAlignedBoundingBox::new(self.position, self.position);
+		let cos_e = (self.cos_outer.acos() - self.cos_inner.acos()).cos();
+		Some(LightBounds::new(bounds, self.direction, self.intensity() * self.color.luminance(), self.cos_inner, cos_e, false))
+	}
+}
+
+// A light so far away that its rays are parallel, like the sun.
+pub struct DirectionalLight {
+	// The direction the light travels in.
+	direction: Vec3,
+	color: Color,
+	// Irradiance on a surface facing the light, in W/m^2.
+	irradiance: f64,
+}
+
+impl DirectionalLight {
+	pub fn new(direction: Vec3, color: Color, irradiance: f64) -> Self {
+		Self {
+			direction: direction.unit(),
+			color,
+			irradiance,
+		}
+	}
+}
+
+impl Light for DirectionalLight {
+	fn sample(&self, _point: &Vec3, _time: f64) -> Option<LightSample> {
+		Some(LightSample {
+			direction: -self.direction,
+			distance: f64::INFINITY,
+			radiance: self.color * self.irradiance,
+			pdf: 1.0,
+			delta: true,
+		})
+	}
+
+	// As much as falls on a disk the size of the scene.
+	fn power(&self, scene_radius: f64) -> f64 {
+		self.irradiance * self.color.luminance() * PI * scene_radius * scene_radius
+	}
+
+	fn bounds(&self) -> Option<LightBounds> {
+		None
+	}
+}
+
+// An object in the scene with an emissive material. It is sampled by picking
+// a direction toward it, and is also found by rays that hit it.
+pub struct AreaLight {
+	object: Arc<dyn Hitable + Send + Sync>,
+	bounds: LightBounds,
+}
+
+impl AreaLight {
+	// None if the object doesn't emit light.
+	pub fn new(object: Arc<dyn Hitable + Send + Sync>) -> Option<Self> {
+		let bounds = object.light_bounds()?;
+		Some(Self {
+			object,
+			bounds,
+		})
+	}
+}
+
+impl Light for AreaLight {
+	// Partly opaque surfaces are weighted by their opacity, and add what
+	// shines through them from further along the object, the same on average
+	// as rays that hit them.
+	fn sample(&self, point: &Vec3, time: f64) -> Option<LightSample> {
+		let direction = self.object.random(point).unit();
+		let ray = Ray::new(*point, direction, time);
+		let rec = self.object.hit_surface(&ray, 0.001, f64::INFINITY)?;
+		let pdf = self.object.pdf_value(point, &direction);
+		if pdf <= 0.0 {
+			return None;
+		}
+		let distance = rec.t();
+		let mut radiance = Color::new(0.0, 0.0, 0.0);
+		let mut through = 1.0;
+		let mut next = Some(rec);
+		while let Some(rec) = next {
+			let opacity = rec.material().opacity(rec.u(), rec.v(), rec.point());
+			radiance += &(rec.material().emitted_toward(&ray, &rec) * (through * opacity));
+			through *= 1.0 - opacity;
+			next = if through > 0.0 {
+				self.object.hit_surface(&ray, rec.t() + 0.001, f64::INFINITY)
+			} else {
+				None
+			};
+		}
+		Some(LightSample {
+			direction,
+			distance,
+			radiance,
+			pdf,
+			delta: false,
+		})
+	}
+
+	fn pdf(&self, point: &Vec3, direction: &Vec3) -> f64 {
+		self.object.pdf_value(point, direction)
+	}
+
+	fn power(&self, _scene_radius: f64) -> f64 {
+		self.bounds.power()
+	}
+
+	fn bounds(&self) -> Option<LightBounds> {
+		Some(self.bounds)
+	}
+
+	fn object(&self) -> Option<usize> {
+		Some(object_id(self.object.as_ref()))
+	}
+}
+
+#[derive(Clone, Default)]
+enum Sampler {
+	#[default]
+	Uniform,
+	Power(PowerSampler),
+	Bvh(LightBvh),
+}
+
+
+// The lights, and how to choose one of them. Call build() once they are all
+// added, until then lights are chosen uniformly.
+#[derive(Default, Clone)]
+pub struct LightList {
+	lights: Vec<Arc<dyn Light + Send + Sync>>,
+	// Object id to light index, for rays that hit an emitter.
+	objects: HashMap<usize, usize>,
+	sampler: Sampler,
+}
+
+impl LightList {
+	pub fn new() -> Self {
+		Self {
+			lights: Vec::new(),
+			objects: HashMap::new(),
+			sampler: Sampler::Uniform,
+		}
+	}
+
+	pub fn add(&mut self, light: Arc<dyn Light + Send + Sync>) {
+		if let Some(id) = light.object() {
+			self.objects.insert(id, self.lights.len());
+		}
+		self.lights.push(light);
+	}
+
+	// Adds every emissive object in the list as an area light. Objects inside
+	// a BVH or another container aren't looked for, so call this before
+	// building one.
+	pub fn add_emitters(&mut self, objects: &HitList) {
+		for object in objects.objects().iter() {
+			if let Some(light) = AreaLight::new(object.clone()) {
+				self.add(Arc::new(light));
+			}
+		}
+	}
+
+	pub fn build(&mut self, sampling: LightSampling, scene_bounds: Option<AxisAlignedBoundingBox>) {
+		let scene_radius = scene_bounds.map_or(1.0, |b| (*b.max() - b.min()).len() / 2.0);
+		self.sampler = match sampling {
+			LightSampling::Uniform => Sampler::Uniform,
+			LightSampling::Power => {
+				let powers: Vec<f64> = self.lights.iter().map(|l| l.power(scene_radius)).collect();
+				Sampler::Power(PowerSampler::new(&powers))
+			}
+			LightSampling::Bvh => {
+				let bounds: Vec<Option<LightBounds>> = self.lights.iter().map(|l| l.bounds()).collect();
+				Sampler::Bvh(LightBvh::new(&bounds))
+			}
+		};
+	}
+
+	// Picks a light to shine on a surface at point with this normal, and the
+	// probability of picking it.
+	pub fn sample(&self, point: &Vec3, normal: &Vec3) -> Option<(usize, f64)> {
+		if self.lights.is_empty() {
+			return None;
+		}
+		match &self.sampler {
+			Sampler::Uniform => {
+				let n = self.lights.len();
+				Some((((random_f64() * n as f64) as usize).min(n - 1), 1.0 / n as f64))
+			}
+			Sampler::Power(power) => power.sample(),
+			Sampler::Bvh(bvh) => bvh.sample(point, normal),
+		}
+	}
+
+	// The probability of sample() picking this light.
+	pub fn pmf(&self, point: &Vec3, normal: &Vec3, index: usize) -> f64 {
+		match &self.sampler {
+			Sampler::Uniform => 1.0 / self.lights.len() as f64,
+			Sampler::Power(power) => power.pmf(index),
+			Sampler::Bvh(bvh) => bvh.pmf(point, normal, index),
+		}
+	}
+
+	pub fn light(&self, index: usize) -> &Arc<dyn Light + Send + Sync> {
+		&self.lights[index]
+	}
+
+	// The light an object was added as, if any.
+	pub fn find(&self, object: usize) -> Option<usize> {
+		self.objects.get(&object).copied()
+	}
+
+	pub fn lights(&self) -> &Vec<Arc<dyn Light + Send + Sync>> {
+		&self.lights
+	}
+
+	pub fn len(&self) -> usize {
+		self.lights.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.lights.is_empty()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::background::SolidBackground;
+	use crate::material::{Cutout, DiffuseLight, Lambertian};
+	use crate::objects::sphere::Sphere;
+	use crate::objects::xyrect::XyRect;
+	use crate::random;
+	use crate::texture::SolidColor;
+
+	// Picks every light as often as pmf() says it does, and reports that same
+	// pmf with the pick.
+	fn assert_picks_match_pmf(lights: &LightList, point: &Vec3, normal: &Vec3, samples: usize) {
+		random::seed(1);
+		let mut counts = vec![0usize; lights.len()];
+		for _ in 0..samples {
+			if let Some((i, pmf)) = lights.sample(point, normal) {
+				assert!((pmf - lights.pmf(point, normal, i)).abs() < 1e-12, "light {} picked with {}, pmf {}", i, pmf, lights.pmf(point, normal, i));
+				counts[i] += 1;
+			}
+		}
+		let mut total = 0.0;
+		for (i, count) in counts.iter().enumerate() {
+			let pmf = lights.pmf(point, normal, i);
+			let frequency = *count as f64 / samples as f64;
+			let sigma = (pmf * (1.0 - pmf) / samples as f64).sqrt();
+			assert!((frequency - pmf).abs() <= 5.0 * sigma + 1e-4, "light {} picked {} of the time, pmf {}", i, frequency, pmf);
+			total += pmf;
+		}
+		assert!(total <= 1.0 + 1e-9, "pmfs add up to {}", total);
+	}
+
+	fn scene() -> (LightList, AxisAlignedBoundingBox) {
+		let mut lights = LightList::new();
+		lights.add(Arc::new(PointLight::new(Vec3::new(0.0, 4.0, 0.0), Color::new(1.0, 1.0, 1.0), 100.0)));
+		lights.add(Arc::new(PointLight::new(Vec3::new(6.0, 1.0, 2.0), Color::new(1.0, 0.5, 0.2), 20.0)));
+		lights.add(Arc::new(PointLight::new(Vec3::new(-8.0, 3.0, -5.0), Color::new(0.2, 0.2, 1.0), 400.0)));
+		lights.add(Arc::new(SpotLight::new(Vec3::new(2.0, 5.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 20.0, 30.0, 1.0, Color::new(1.0, 1.0, 1.0), 50.0)));
+		lights.add(Arc::new(DirectionalLight::new(Vec3::new(1.0, -1.0, 0.0), Color::new(1.0, 1.0, 0.9), 2.0)));
+		let emitter = DiffuseLight::new(Arc::new(SolidColor::from_rgb(4.0, 4.0, 4.0)));
+		lights.add(Arc::new(AreaLight::new(Arc::new(Sphere::new(Vec3::new(-3.0, 2.0, 3.0), 1.0, Arc::new(emitter)))).unwrap()));
+		(lights, AxisAlignedBoundingBox::new(Vec3::new(-10.0, -1.0, -10.0), Vec3::new(10.0, 6.0, 10.0)))
+	}
+
+	#[test]
+	fn samplers_pick_lights_by_their_pmf() {
+		let points = [(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), (Vec3::new(5.0, 1.0, 0.0), Vec3::new(-1.0, 0.0, 0.0)), (Vec3::new(0.0, -0.5, 0.0), Vec3::new(0.0, -1.0, 0.0))];
+		for sampling in [LightSampling::Uniform, LightSampling::Power, LightSampling::Bvh].iter() {
+			let (mut lights, bounds) = scene();
+			lights.build(*sampling, Some(bounds));
+			for (point, normal) in points.iter() {
+				assert_picks_match_pmf(&lights, point, normal, 200_000);
+			}
+		}
+	}
+
+	// A white floor seen from eye and lit by a point light at light, with a
+	// black square between them at z = 1 cut out by opacity, or no square.
+	fn floor_through(opacity: Option<f64>, eye: Vec3, light: Vec3) -> Color {
+		let mut world = HitList::new();
+		world.add(Arc::new(XyRect::new(-10.0, 10.0, -10.0, 10.0, 0.0, Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(1.0, 1.0, 1.0)))))));
+		if let Some(opacity) = opacity {
+			let black = Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(0.0, 0.0, 0.0))));
+			world.add(Arc::new(XyRect::new(-1.0, 1.0, -1.0, 1.0, 1.0, Arc::new(Cutout::new(black, Arc::new(SolidColor::from_rgb(opacity, opacity, opacity)))))));
+		}
+		let mut lights = LightList::new();
+		lights.add(Arc::new(PointLight::new(light, Color::new(1.0, 1.0, 1.0), 100.0)));
+		lights.build(LightSampling::Uniform, world.bounding_box(0.0, 0.0));
+		random::seed(1);
+		let ray = Ray::new(eye, -eye, 0.0);
+		ray.color(&SolidBackground::new(Color::new(0.0, 0.0, 0.0)), &lights, &world, 1).0
+	}
+
+	// Camera rays and shadow rays both go through transparent texels as if
+	// nothing were there, and are stopped by opaque ones.
+	#[test]
+	fn rays_pass_through_transparent_texels() {
+		let (above, aside) = (Vec3::new(0.0, 0.0, 2.0), Vec3::new(3.0, 0.0, 0.5));
+		for (eye, light) in [(above, aside), (aside, above)] {
+			let open = floor_through(None, eye, light).r();
+			assert!(open > 0.0);
+			assert!((floor_through(Some(0.0), eye, light).r() - open).abs() < 1e-12);
+			assert_eq!(floor_through(Some(1.0), eye, light).r(), 0.0);
+		}
+	}
+
+	// Light sampling sees the whole of a partly opaque emitter, weighed by its
+	// opacity, without choosing at random which texels are there.
+	#[test]
+	fn weighs_emission_by_opacity() {
+		let white = Arc::new(DiffuseLight::new(Arc::new(SolidColor::from_rgb(2.0, 2.0, 2.0))));
+		let plain = XyRect::new(-1.0, 1.0, -1.0, 1.0, 1.0, white.clone());
+		let cutout = AreaLight::new(Arc::new(XyRect::new(-1.0, 1.0, -1.0, 1.0, 1.0, Arc::new(Cutout::new(white, Arc::new(SolidColor::from_rgb(0.25, 0.25, 0.25))))))).unwrap();
+		let point = Vec3::new(0.2, 0.1, 0.0);
+		random::seed(1);
+		for _ in 0..100 {
+			let ls = cutout.sample(&point, 0.0).unwrap();
+			assert!((ls.radiance.r() - 0.5).abs() < 1e-12, "radiance {}", ls.radiance.r());
+			assert!((ls.pdf - plain.pdf_value(&point, &ls.direction)).abs() < 1e-9);
+			assert_eq!(cutout.pdf(&point, &ls.direction), ls.pdf);
+		}
+	}
+}
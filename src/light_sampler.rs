@@ -0,0 +1,526 @@
+// light_sampler.rs
+// Choosing which light to sample at a shading point
+
+use crate::bounding_box::AxisAlignedBoundingBox;
+use crate::random::random_f64;
+use crate::vector::Vec3;
+use std::f64::consts::PI;
+use std::vec::Vec;
+
+// How the integrator picks one light out of many.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LightSampling {
+	// Every light is equally likely.
+	Uniform,
+	// In proportion to emitted power, the same everywhere in the scene.
+	Power,
+	// By importance to the shading point, through a light BVH.
+	Bvh,
+}
+
+impl LightSampling {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"uniform" => Some(LightSampling::Uniform),
+			"power" => Some(LightSampling::Power),
+			"bvh" => Some(LightSampling::Bvh),
+			_ => None,
+		}
+	}
+
+	pub fn name(self) -> &'static str {
+		match self {
+			LightSampling::Uniform => "uniform",
+			LightSampling::Power => "power",
+			LightSampling::Bvh => "bvh",
+		}
+	}
+}
+
+// A conservative description of one or more lights, following PBRT-v4: where
+// they are, which way they shine (a cone around w, cos_theta_o), how far
+// past that cone a surface can still emit (cos_theta_e), and how bright they
+// are. phi is the intensity in the brightest direction, so that phi / d^2 is
+// roughly the irradiance at distance d.
+#[derive(Copy, Clone)]
+pub struct LightBounds {
+	bounds: AxisAlignedBoundingBox,
+	w: Vec3,
+	phi: f64,
+	cos_theta_o: f64,
+	cos_theta_e: f64,
+	two_sided: bool,
+}
+
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+	if cos_a > cos_b {
+		1.0
+	} else {
+		cos_a * cos_b + sin_a * sin_b
+	}
+}
+
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+	if cos_a > cos_b {
+		0.0
+	} else {
+		sin_a * cos_b - cos_a * sin_b
+	}
+}
+
+fn sin_from_cos(cos: f64) -> f64 {
+	(1.0 - cos * cos).max(0.0).sqrt()
+}
+
+// Rotate v by theta radians about axis (Rodrigues' formula).
+fn rotate(v: &Vec3, axis: &Vec3, theta: f64) -> Vec3 {
+	let k = axis.unit();
+	let (sin, cos) = theta.sin_cos();
+	*v * cos + &(k.cross(v) * sin) + &(k * (k.dot(v) * (1.0 - cos)))
+}
+
+impl LightBounds {
+	pub fn new(bounds: AxisAlignedBoundingBox, w: Vec3, phi: f64, cos_theta_o: f64, cos_theta_e: f64, two_sided: bool) -> Self {
+		Self {
+			bounds,
+			w: w.unit(),
+			phi,
+			cos_theta_o,
+			cos_theta_e,
+			two_sided,
+		}
+	}
+
+	fn center(&self) -> Vec3 {
+		(*self.bounds.min() + self.bounds.max()) * 0.5
+	}
+
+	fn diagonal(&self) -> Vec3 {
+		*self.bounds.max() - self.bounds.min()
+	}
+
+	// Total emitted power, assuming the lights are diffuse emitters.
+	pub fn power(&self) -> f64 {
+		if self.cos_theta_o <= -1.0 {
+			// Shines everywhere, like a sphere.
+			4.0 * PI * self.phi
+		} else if self.two_sided {
+			2.0 * PI * self.phi
+		} else {
+			PI * self.phi
+		}
+	}
+
+	// Smallest bounds holding both.
+	pub fn union(&self, other: &LightBounds) -> LightBounds {
+		if self.phi <= 0.0 {
+			return *other;
+		}
+		if other.phi <= 0.0 {
+			return *self;
+		}
+		let (w, cos_theta_o) = cone_union(&self.w, self.cos_theta_o, &other.w, other.cos_theta_o);
+		LightBounds {
+			bounds: self.bounds.surrounding_box(&other.bounds),
+			w,
+			phi: self.phi + other.phi,
+			cos_theta_o,
+			cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+			two_sided: self.two_sided || other.two_sided,
+		}
+	}
+
+	// An estimate of how much these lights could contribute at point, to a
+	// surface with this normal. It is never zero unless they can't reach it.
+	pub fn importance(&self, point: &Vec3, normal: &Vec3) -> f64 {
+		let pc = self.center();
+		let to_point = *point - &pc;
+		let dist2 = to_point.len2();
+		let diag = self.diagonal();
+		let d2 = dist2.max(diag.len() / 2.0);
+		let wi = if dist2 > 0.0 {
+			to_point.unit()
+		} else {
+			self.w
+		};
+
+		let mut cos_theta_w = self.w.dot(&wi);
+		if self.two_sided {
+			cos_theta_w = cos_theta_w.abs();
+		}
+		let sin_theta_w = sin_from_cos(cos_theta_w);
+
+		// The cone of directions the bounds cover, seen from point.
+		let radius2 = (diag * 0.5).len2();
+		let cos_theta_b = if dist2 < radius2 {
+			-1.0
+		} else {
+			(1.0 - radius2 / dist2).max(0.0).sqrt()
+		};
+		let sin_theta_b = sin_from_cos(cos_theta_b);
+
+		// The smallest angle between the emission cone and point.
+		let sin_theta_o = sin_from_cos(self.cos_theta_o);
+		let cos_theta_x = cos_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+		let sin_theta_x = sin_sub_clamped(sin_theta_w, cos_theta_w, sin_theta_o, self.cos_theta_o);
+		let cos_theta_p = cos_sub_clamped(sin_theta_x, cos_theta_x, sin_theta_b, cos_theta_b);
+		if cos_theta_p <= self.cos_theta_e {
+			return 0.0;
+		}
+
+		let mut importance = self.phi * cos_theta_p / d2;
+		if normal.len2() > 0.0 {
+			let cos_theta_i = wi.dot(&normal.unit()).abs();
+			let sin_theta_i = sin_from_cos(cos_theta_i);
+			importance *= cos_sub_clamped(sin_theta_i, cos_theta_i, sin_theta_b, cos_theta_b);
+		}
+		importance.max(0.0)
+	}
+
+	// The cost of a BVH node with these bounds, split along dim.
+	fn cost(&self, dim: usize) -> f64 {
+		let theta_o = self.cos_theta_o.clamp(-1.0, 1.0).acos();
+		let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+		let theta_w = (theta_o + theta_e).min(PI);
+		let sin_theta_o = sin_from_cos(self.cos_theta_o);
+		let m_omega = 2.0 * PI * (1.0 - self.cos_theta_o) + PI / 2.0 * (2.0 * theta_w * sin_theta_o - (theta_o - 2.0 * theta_w).cos() - 2.0 * theta_o * sin_theta_o + self.cos_theta_o);
+		let diag = self.diagonal();
+		let max_extent = diag.x().max(diag.y()).max(diag.z());
+		let kr = if diag[dim] > 0.0 {
+			max_extent / diag[dim]
+		} else {
+			1.0
+		};
+		let area = 2.0 * (diag.x() * diag.y() + diag.y() * diag.z() + diag.z() * diag.x());
+		self.phi * m_omega * kr * area
+	}
+}
+
+// The smallest cone holding two cones of directions.
+fn cone_union(wa: &Vec3, cos_a: f64, wb: &Vec3, cos_b: f64) -> (Vec3, f64) {
+	let theta_a = cos_a.clamp(-1.0, 1.0).acos();
+	let theta_b = cos_b.clamp(-1.0, 1.0).acos();
+	let theta_d = wa.dot(wb).clamp(-1.0, 1.0).acos();
+	if (theta_d + theta_b).min(PI) <= theta_a {
+		return (*wa, cos_a);
+	}
+	if (theta_d + theta_a).min(PI) <= theta_b {
+		return (*wb, cos_b);
+	}
+	let theta_o = (theta_a + theta_d + theta_b) / 2.0;
+	if theta_o >= PI {
+		return (*wa, -1.0);
+	}
+	let axis = wa.cross(wb);
+	if axis.len2() == 0.0 {
+		return (*wa, -1.0);
+	}
+	(rotate(wa, &axis, theta_o - theta_a), theta_o.cos())
+}
+
+// Picks lights in proportion to their power.
+#[derive(Clone)]
+pub struct PowerSampler {
+	cdf: Vec<f64>,
+}
+
+impl PowerSampler {
+	pub fn new(powers: &[f64]) -> Self {
+		let total: f64 = powers.iter().map(|p| p.max(0.0)).sum();
+		let mut cdf = Vec::with_capacity(powers.len() + 1);
+		let mut sum = 0.0;
+		cdf.push(0.0);
+		for p in powers.iter() {
+			// With no power anywhere, fall back to picking uniformly.
+			sum += if total > 0.0 {
+				p.max(0.0) / total
+			} else {
+				1.0 / powers.len() as f64
+			};
+			cdf.push(sum);
+		}
+		Self {
+			cdf,
+		}
+	}
+
+	pub fn sample(&self) -> Option<(usize, f64)> {
+		let n = self.cdf.len() - 1;
+		if n == 0 {
+			return None;
+		}
+		let u = random_f64() * self.cdf[n];
+		let i = (self.cdf.partition_point(|c| *c <= u).clamp(1, n)) - 1;
+		let pmf = self.pmf(i);
+		if pmf > 0.0 {
+			Some((i, pmf))
+		} else {
+			None
+		}
+	}
+
+	pub fn pmf(&self, index: usize) -> f64 {
+		self.cdf[index + 1] - self.cdf[index]
+	}
+}
+
+#[derive(Clone)]
+struct LightBvhNode {
+	bounds: LightBounds,
+	// A leaf holds a light, an interior node the index of its second child.
+	// The first child always follows its parent.
+	light: Option<usize>,
+	second: usize,
+}
+
+// The light BVH from PBRT-v4. Every level chooses between its two children
+// in proportion to their importance, so lights that are close, bright, and
+// facing the shading point are chosen most often. Lights without bounds
+// (directional lights) are chosen uniformly, beside the tree.
+#[derive(Clone)]
+pub struct LightBvh {
+	nodes: Vec<LightBvhNode>,
+	infinite: Vec<usize>,
+	// Per light, the way down to its leaf: bit i is set for taking the
+	// second child at depth i.
+	trails: Vec<u64>,
+}
+
+const BVH_BUCKETS: usize = 12;
+
+impl LightBvh {
+	// bounds holds an entry for every light, None for the ones at infinity.
+	pub fn new(bounds: &[Option<LightBounds>]) -> Self {
+		let mut bvh = Self {
+			nodes: Vec::new(),
+			infinite: Vec::new(),
+			trails: vec![0; bounds.len()],
+		};
+		let mut finite = Vec::new();
+		for (i, b) in bounds.iter().enumerate() {
+			match b {
+				Some(b) if b.phi > 0.0 => finite.push((i, *b)),
+				Some(_) => {}
+				None => bvh.infinite.push(i),
+			}
+		}
+		if !finite.is_empty() {
+			bvh.build(&mut finite, 0, 0);
+		}
+		bvh
+	}
+
+	fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+		let index = self.nodes.len();
+		if lights.len() == 1 {
+			let (light, bounds) = lights[0];
+			self.trails[light] = trail;
+			self.nodes.push(LightBvhNode {
+				bounds,
+				light: Some(light),
+				second: 0,
+			});
+			return index;
+		}
+
+		// Split where the cost of the two halves is lowest.
+		let mut cmin = lights[0].1.center();
+		let mut cmax = cmin;
+		for (_, b) in lights.iter() {
+			let c = b.center();
+			for a in 0..3 {
+				cmin[a] = cmin[a].min(c[a]);
+				cmax[a] = cmax[a].max(c[a]);
+			}
+		}
+		let node_bounds = lights.iter().skip(1).fold(lights[0].1, |acc, (_, b)| acc.union(b));
+		// Trails only have 64 bits, so once the tree gets deep enough that
+		// lopsided splits could run out of them, the lights are halved by
+		// count along their widest axis instead.
+		let levels_left = usize::BITS - (lights.len() - 1).leading_zeros();
+		let balanced = depth + levels_left >= 63;
+		let mut best: Option<(f64, usize, usize)> = None;
+		for dim in 0..3 {
+			if balanced {
+				break;
+			}
+			let extent = cmax[dim] - cmin[dim];
+			if extent <= 0.0 {
+				continue;
+			}
+			let bucket_of = |b: &LightBounds| (((b.center()[dim] - cmin[dim]) / extent * BVH_BUCKETS as f64) as usize).min(BVH_BUCKETS - 1);
+			let mut buckets: Vec<Option<LightBounds>> = vec![None; BVH_BUCKETS];
+			for (_, b) in lights.iter() {
+				let k = bucket_of(b);
+				buckets[k] = Some(match buckets[k] {
+					Some(acc) => acc.union(b),
+					None => *b,
+				});
+			}
+			for split in 0..BVH_BUCKETS - 1 {
+				let below = buckets[..=split].iter().flatten().fold(None, |acc: Option<LightBounds>, b| Some(acc.map_or(*b, |a| a.union(b))));
+				let above = buckets[split + 1..].iter().flatten().fold(None, |acc: Option<LightBounds>, b| Some(acc.map_or(*b, |a| a.union(b))));
+				let cost = below.map_or(0.0, |b| b.cost(dim)) + above.map_or(0.0, |b| b.cost(dim));
+				if below.is_some() && above.is_some() && best.is_none_or(|(c, _, _)| cost < c) {
+					best = Some((cost, dim, split));
+				}
+			}
+		}
+		let mid = match best {
+			Some((_, dim, split)) => {
+				let extent = cmax[dim] - cmin[dim];
+				let bucket_of = |b: &LightBounds| (((b.center()[dim] - cmin[dim]) / extent * BVH_BUCKETS as f64) as usize).min(BVH_BUCKETS - 1);
+				lights.sort_by_key(|(_, b)| bucket_of(b));
+				lights.iter().position(|(_, b)| bucket_of(b) > split).unwrap_or(lights.len() / 2)
+			}
+			// All of the centers are in the same place, or the split has to
+			// be balanced.
+			None => {
+				let mut widest = 0;
+				for a in 1..3 {
+					if cmax[a] - cmin[a] > cmax[widest] - cmin[widest] {
+						widest = a;
+					}
+				}
+				lights.sort_by(|(_, a), (_, b)| a.center()[widest].total_cmp(&b.center()[widest]));
+				lights.len() / 2
+			}
+		};
+
+		self.nodes.push(LightBvhNode {
+			bounds: node_bounds,
+			light: None,
+			second: 0,
+		});
+		let (first, second) = lights.split_at_mut(mid);
+		self.build(first, trail, depth + 1);
+		let second_index = self.build(second, trail | 1 << depth, depth + 1);
+		self.nodes[index].second = second_index;
+		index
+	}
+
+	fn infinite_probability(&self) -> f64 {
+		let tree = if self.nodes.is_empty() {
+			0.0
+		} else {
+			1.0
+		};
+		let n = self.infinite.len() as f64;
+		if n + tree > 0.0 {
+			n / (n + tree)
+		} else {
+			0.0
+		}
+	}
+
+	pub fn sample(&self, point: &Vec3, normal: &Vec3) -> Option<(usize, f64)> {
+		let p_infinite = self.infinite_probability();
+		if random_f64() < p_infinite {
+			let n = self.infinite.len();
+			let i = ((random_f64() * n as f64) as usize).min(n - 1);
+			return Some((self.infinite[i], p_infinite / n as f64));
+		}
+		if self.nodes.is_empty() {
+			return None;
+		}
+		let mut pmf = 1.0 - p_infinite;
+		let mut node = 0;
+		loop {
+			let n = &self.nodes[node];
+			if let Some(light) = n.light {
+				if n.bounds.importance(point, normal) > 0.0 {
+					return Some((light, pmf));
+				}
+				return None;
+			}
+			let first = self.nodes[node + 1].bounds.importance(point, normal);
+			let second = self.nodes[n.second].bounds.importance(point, normal);
+			if first + second <= 0.0 {
+				return None;
+			}
+			let p_first = first / (first + second);
+			if random_f64() < p_first {
+				pmf *= p_first;
+				node += 1;
+			} else {
+				pmf *= 1.0 - p_first;
+				node = n.second;
+			}
+		}
+	}
+
+	// The probability that sample() picks this light.
+	pub fn pmf(&self, point: &Vec3, normal: &Vec3, light: usize) -> f64 {
+		let p_infinite = self.infinite_probability();
+		if self.infinite.contains(&light) {
+			return p_infinite / self.infinite.len() as f64;
+		}
+		if self.nodes.is_empty() {
+			return 0.0;
+		}
+		let mut trail = self.trails[light];
+		let mut pmf = 1.0 - p_infinite;
+		let mut node = 0;
+		loop {
+			let n = &self.nodes[node];
+			if let Some(leaf) = n.light {
+				if leaf != light || n.bounds.importance(point, normal) <= 0.0 {
+					return 0.0;
+				}
+				return pmf;
+			}
+			let first = self.nodes[node + 1].bounds.importance(point, normal);
+			let second = self.nodes[n.second].bounds.importance(point, normal);
+			if first + second <= 0.0 {
+				return 0.0;
+			}
+			if trail & 1 == 0 {
+				pmf *= first / (first + second);
+				node += 1;
+			} else {
+				pmf *= second / (first + second);
+				node = n.second;
+			}
+			trail >>= 1;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::random;
+
+	// Lights that are spaced further apart, and brighter, every time make the
+	// tree as lopsided as it gets, deep enough to need the balanced splits.
+	#[test]
+	fn deep_bvh_picks_lights_by_their_pmf() {
+		let n = 200;
+		let mut bounds = Vec::new();
+		for i in 0..n {
+			let x = 1.3f64.powi(i);
+			bounds.push(Some(LightBounds::new(AxisAlignedBoundingBox::new(Vec3::new(x, 0.0, 0.0), Vec3::new(x + 0.01, 0.01, 0.01)), Vec3::new(0.0, 0.0, 1.0), 1.3f64.powi(2 * i), -1.0, 0.0, true)));
+		}
+		let bvh = LightBvh::new(&bounds);
+		let (point, normal) = (Vec3::new(0.5, 0.5, 3.0), Vec3::new(0.0, 0.0, 0.0));
+
+		random::seed(1);
+		let samples = 400_000;
+		let mut counts = vec![0usize; n as usize];
+		for _ in 0..samples {
+			let (i, pmf) = bvh.sample(&point, &normal).unwrap();
+			assert!((pmf - bvh.pmf(&point, &normal, i)).abs() <= 1e-12 * pmf, "light {} picked with {}, pmf {}", i, pmf, bvh.pmf(&point, &normal, i));
+			counts[i] += 1;
+		}
+		let mut total = 0.0;
+		for (i, count) in counts.iter().enumerate() {
+			let pmf = bvh.pmf(&point, &normal, i);
+			assert!(pmf > 0.0, "light {} can't be picked", i);
+			let frequency = *count as f64 / samples as f64;
+			let sigma = (pmf * (1.0 - pmf) / samples as f64).sqrt();
+			assert!((frequency - pmf).abs() <= 5.0 * sigma + 1e-4, "light {} picked {} of the time, pmf {}", i, frequency, pmf);
+			total += pmf;
+		}
+		assert!((total - 1.0).abs() < 1e-9, "pmfs add up to {}", total);
+	}
+}
@@ -3,39 +3,41 @@
 // Stephen Marz
 // 9 Dec 2020
 
-pub mod bmp;
-pub mod bounding_box;
-pub mod bvh;
-pub mod camera;
-pub mod hitable;
-pub mod material;
-pub mod objects;
-pub mod perlin;
-pub mod png;
-pub mod random;
-pub mod ray;
-pub mod texture;
-pub mod threadpool;
-pub mod vector;
-
-use crate::hitable::HitList;
-use crate::material::Material;
-use crate::objects::sphere::Sphere;
-use crate::objects::xyrect::XyRect;
-use crate::random::random_double;
-use crate::texture::CheckeredTexture;
-use crate::texture::ImageTexture;
-use crate::texture::SolidColor;
-use crate::threadpool::ThreadPool;
-use crate::{camera::Camera, vector::Vec3};
-use std::{env::args, sync::Arc};
-
-const DEFAULT_PIXELS_UPDATE: i32 = 1000;
+use raytrace::background::{Background, SolidBackground};
+use raytrace::bvh::BvhNode;
+use raytrace::camera::Camera;
+use raytrace::checkpoint::{Checkpoint, Checkpointer};
+use raytrace::denoise::Denoiser;
+use raytrace::environment::EnvironmentMap;
+use raytrace::filter;
+use raytrace::hitable::HitList;
+use raytrace::light::{DirectionalLight, Light, LightList, PointLight, SpotLight};
+use raytrace::light_sampler::LightSampling;
+use raytrace::material::{DiffuseLight, Lambertian};
+use raytrace::nodes;
+use raytrace::objects::sphere::Sphere;
+use raytrace::objects::xyrect::XyRect;
+use raytrace::png::PngPicture;
+use raytrace::random::{self, random_double};
+use raytrace::renderer::{self, RenderSettings, Renderer, StopReason};
+use raytrace::sky::{GradientSky, HosekWilkieData, HosekWilkieSky, PreethamSky};
+use raytrace::stats;
+use raytrace::texture::{CheckeredTexture, ImageTexture, SolidColor, Texture};
+use raytrace::vector::Vec3;
+use std::{
+	env::args,
+	process::exit,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
 
 fn main() {
-	let args: Vec<String> = args().collect();
+	let (flags, args): (Vec<String>, Vec<String>) = args().partition(|a| a.starts_with("--"));
 	if args.len() < 7 {
-		println!("Usage: {} [filename] [width] [height] [samples] [max depth] [num threads] <frames> <progress update interval>", args[0]);
+		println!("Usage: {} [filename] [width] [height] [samples] [max depth] [num threads] <frames> <progress update interval> <--denoise[=strength]> <--filter=box|tent|gaussian|mitchell|blackman-harris> <--progressive=samples per pass> <--time=seconds> <--noise=threshold> <--checkpoint[=seconds]> <--resume> <--seed=number> <--environment=file.hdr|file.exr> <--environment-rotation=degrees> <--environment-intensity=scale> <--sky=gradient|preetham|hosek-wilkie> <--sun-elevation=degrees> <--sun-azimuth=degrees> <--turbidity=value> <--ground-albedo=value> <--sky-intensity=scale> <--sky-data=ArHosekSkyModelData_RGB.h> <--light-sampling=uniform|power|bvh> <--point-light=x,y,z,r,g,b,watts> <--spot-light=x,y,z,dx,dy,dz,inner,outer,falloff,r,g,b,watts> <--directional-light=dx,dy,dz,r,g,b,irradiance> <--ground-texture=graph file> <--sphere-texture=graph file> <--spectral>", args[0]);
 		return;
 	}
 
@@ -47,16 +49,196 @@ fn main() {
 	let max_depth = args[5].parse::<i32>().unwrap();
 	let num_threads = args[6].parse::<usize>().unwrap();
 	let mut frames = 1usize;
-	let mut pixel_update = DEFAULT_PIXELS_UPDATE;
+	let mut settings = RenderSettings::new(image_width, image_height, samples, max_depth, num_threads);
 
 	if args.len() >= 8 {
 		frames = args[7].parse::<usize>().unwrap();
 	}
 
 	if args.len() >= 9 {
-		pixel_update = args[8].parse::<i32>().unwrap();
+		settings.progress_interval = args[8].parse::<u32>().unwrap();
 	}
 
+	let mut checkpoint_interval = None;
+	let mut resume = false;
+	let mut seed = None;
+	let mut environment = None;
+	let mut environment_rotation = 0.0;
+	let mut environment_intensity = 1.0;
+	let mut sky = None;
+	let mut sun_elevation = 45.0;
+	let mut sun_azimuth = 0.0;
+	let mut turbidity = 3.0;
+	let mut ground_albedo = 0.1;
+	let mut sky_intensity = 1.0;
+	let mut sky_data = None;
+	let mut extra_lights: Vec<Arc<dyn Light + Send + Sync>> = Vec::new();
+	let mut ground_texture: Option<Arc<dyn Texture + Send + Sync>> = None;
+	let mut sphere_texture: Option<Arc<dyn Texture + Send + Sync>> = None;
+	for flag in flags.iter() {
+		if flag == "--denoise" {
+			settings.denoiser = Some(Denoiser::default());
+		} else if let Some(strength) = flag.strip_prefix("--denoise=") {
+			settings.denoiser = Some(Denoiser::new(strength.parse::<f64>().unwrap()));
+		} else if let Some(name) = flag.strip_prefix("--filter=") {
+			if let Some(f) = filter::from_name(name) {
+				settings.filter = f;
+			} else {
+				println!("Unknown filter '{}'", name);
+				return;
+			}
+		} else if let Some(pass_samples) = flag.strip_prefix("--progressive=") {
+			settings.progressive.set_pass_samples(pass_samples.parse::<u32>().unwrap());
+		} else if let Some(seconds) = flag.strip_prefix("--time=") {
+			settings.progressive.set_time_budget(Duration::from_secs_f64(seconds.parse::<f64>().unwrap()));
+		} else if let Some(threshold) = flag.strip_prefix("--noise=") {
+			settings.progressive.set_noise_threshold(threshold.parse::<f64>().unwrap());
+		} else if flag == "--checkpoint" {
+			checkpoint_interval = Some(Duration::from_secs(0));
+		} else if let Some(seconds) = flag.strip_prefix("--checkpoint=") {
+			checkpoint_interval = Some(Duration::from_secs_f64(seconds.parse::<f64>().unwrap()));
+		} else if flag == "--resume" {
+			resume = true;
+		} else if let Some(number) = flag.strip_prefix("--seed=") {
+			seed = Some(number.parse::<u64>().unwrap());
+		} else if let Some(fname) = flag.strip_prefix("--environment=") {
+			environment = Some(fname.to_string());
+		} else if let Some(degrees) = flag.strip_prefix("--environment-rotation=") {
+			environment_rotation = degrees.parse::<f64>().unwrap();
+		} else if let Some(scale) = flag.strip_prefix("--environment-intensity=") {
+			environment_intensity = scale.parse::<f64>().unwrap();
+		} else if let Some(name) = flag.strip_prefix("--sky=") {
+			sky = Some(name.to_string());
+		} else if let Some(degrees) = flag.strip_prefix("--sun-elevation=") {
+			sun_elevation = degrees.parse::<f64>().unwrap();
+		} else if let Some(degrees) = flag.strip_prefix("--sun-azimuth=") {
+			sun_azimuth = degrees.parse::<f64>().unwrap();
+		} else if let Some(value) = flag.strip_prefix("--turbidity=") {
+			turbidity = value.parse::<f64>().unwrap();
+		} else if let Some(value) = flag.strip_prefix("--ground-albedo=") {
+			ground_albedo = value.parse::<f64>().unwrap();
+		} else if let Some(scale) = flag.strip_prefix("--sky-intensity=") {
+			sky_intensity = scale.parse::<f64>().unwrap();
+		} else if let Some(fname) = flag.strip_prefix("--sky-data=") {
+			sky_data = Some(fname.to_string());
+		} else if let Some(name) = flag.strip_prefix("--light-sampling=") {
+			if let Some(sampling) = LightSampling::from_name(name) {
+				settings.light_sampling = sampling;
+			} else {
+				println!("Unknown light sampling '{}'", name);
+				return;
+			}
+		} else if let Some(list) = flag.strip_prefix("--point-light=") {
+			if let Some(n) = parse_numbers(list, 7) {
+				extra_lights.push(Arc::new(PointLight::new(Vec3::new(n[0], n[1], n[2]), Vec3::new(n[3], n[4], n[5]), n[6])));
+			} else {
+				println!("A point light is x,y,z,r,g,b,watts");
+				return;
+			}
+		} else if let Some(list) = flag.strip_prefix("--spot-light=") {
+			if let Some(n) = parse_numbers(list, 13) {
+				extra_lights.push(Arc::new(SpotLight::new(Vec3::new(n[0], n[1], n[2]), Vec3::new(n[3], n[4], n[5]), n[6], n[7], n[8], Vec3::new(n[9], n[10], n[11]), n[12])));
+			} else {
+				println!("A spot light is x,y,z,dx,dy,dz,inner degrees,outer degrees,falloff,r,g,b,watts");
+				return;
+			}
+		} else if let Some(list) = flag.strip_prefix("--directional-light=") {
+			if let Some(n) = parse_numbers(list, 7) {
+				extra_lights.push(Arc::new(DirectionalLight::new(Vec3::new(n[0], n[1], n[2]), Vec3::new(n[3], n[4], n[5]), n[6])));
+			} else {
+				println!("A directional light is dx,dy,dz,r,g,b,irradiance");
+				return;
+			}
+		} else if let Some(fname) = flag.strip_prefix("--ground-texture=") {
+			match nodes::read_graph(fname) {
+				Ok(texture) => ground_texture = Some(texture),
+				Err(e) => {
+					println!("Unable to read texture graph '{}': {}", fname, e);
+					return;
+				}
+			}
+		} else if let Some(fname) = flag.strip_prefix("--sphere-texture=") {
+			match nodes::read_graph(fname) {
+				Ok(texture) => sphere_texture = Some(texture),
+				Err(e) => {
+					println!("Unable to read texture graph '{}': {}", fname, e);
+					return;
+				}
+			}
+		} else if flag == "--spectral" {
+			settings.spectral = true;
+		} else {
+			println!("Unknown option '{}'", flag);
+			return;
+		}
+	}
+
+	// A resumed render keeps checkpointing, so it can be extended again later.
+	if resume && checkpoint_interval.is_none() {
+		checkpoint_interval = Some(Duration::from_secs(0));
+	}
+	if checkpoint_interval.is_some() {
+		settings.progressive.enable();
+	}
+
+	if environment.is_some() && sky.is_some() {
+		println!("Use either an environment map or a sky, not both");
+		return;
+	}
+	let background: Arc<dyn Background + Send + Sync> = if let Some(fname) = environment {
+		match EnvironmentMap::from_file(&fname, environment_rotation, environment_intensity) {
+			Ok(env) => {
+				eprintln!("Loaded environment map '{}', {}x{}.", fname, env.width(), env.height());
+				Arc::new(env)
+			}
+			Err(e) => {
+				println!("Unable to load environment map '{}' ({})", fname, e);
+				return;
+			}
+		}
+	} else if let Some(name) = sky {
+		match name.as_str() {
+			"gradient" => Arc::new(GradientSky::default()),
+			"preetham" => Arc::new(PreethamSky::new(sun_elevation, sun_azimuth, turbidity, sky_intensity)),
+			"hosek-wilkie" => {
+				// The model's coefficients come from the authors' data file.
+				let fname = match sky_data {
+					Some(fname) => fname,
+					None => {
+						println!("The Hosek-Wilkie sky needs --sky-data=ArHosekSkyModelData_RGB.h");
+						return;
+					}
+				};
+				match HosekWilkieData::from_file(&fname) {
+					Ok(data) => Arc::new(HosekWilkieSky::new(&data, sun_elevation, sun_azimuth, turbidity, ground_albedo, sky_intensity)),
+					Err(e) => {
+						println!("Unable to load sky data '{}' ({})", fname, e);
+						return;
+					}
+				}
+			}
+			_ => {
+				println!("Unknown sky '{}'", name);
+				return;
+			}
+		}
+	} else {
+		Arc::new(SolidBackground::default())
+	};
+
+	// The seed also drives the scene setup below, so a resumed render has to
+	// use the one it was started with to get the same scene back. Frame 0 is
+	// rendered with the seed itself, see frame_seed.
+	let seed = if resume {
+		match Checkpoint::read_file(&checkpoint_filename(&frame_filename(filename, 0))) {
+			Ok(ckpt) => ckpt.seed,
+			Err(_) => seed.unwrap_or_else(random::random_seed),
+		}
+	} else {
+		seed.unwrap_or_else(random::random_seed)
+	};
+	random::seed(seed);
+
 	// Set up the camera parameters
 	let lookfrom = Vec3::new(13.0, 2.0, 3.0);
 	let lookat = Vec3::new(0.0, 0.0, 0.0);
@@ -68,13 +250,13 @@ fn main() {
 	let time1 = 1.0;
 
 	let mut spheres = Vec::<Sphere>::with_capacity(25);
-	let checker = Arc::new(CheckeredTexture::new_color(Vec3::new(0.2, 0.3, 0.1), Vec3::new(0.9, 0.9, 0.9)));
-	spheres.push(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Material::new_lambertian(checker)));
+	let ground = ground_texture.unwrap_or_else(|| Arc::new(CheckeredTexture::new_color(Vec3::new(0.2, 0.3, 0.1), Vec3::new(0.9, 0.9, 0.9))));
+	spheres.push(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(ground))));
 
 	for _ in 0..1 {
 		// let solid = Arc::new(SolidColor::from_rgb(random_double(0.0, 1.0), random_double(0.0, 1.0), random_double(0.0, 1.0)));
-		let texture = Arc::new(ImageTexture::from_file("d.png"));
-		let material = Material::new_lambertian(texture);
+		let texture = sphere_texture.clone().unwrap_or_else(|| Arc::new(ImageTexture::from_file("d.png")));
+		let material = Arc::new(Lambertian::new(texture));
 		// let center = Vec3::new(random_double(-0.5, 1.5), random_double(0.2, 2.0), random_double(-2.0, 2.0));
 		// let radius = random_double(0.07, 0.7);
 		let center = Vec3::new(0.0, 0.0, 0.0);
@@ -83,19 +265,101 @@ fn main() {
 		spheres.push(sphere)
 	}
 
-	// Create the camera, world, thread pool, and picture writer (to BMP for now)
+	// The first Ctrl-C stops the render and keeps what has been traced so far.
+	// The second one gives up immediately.
+	let cancel = Arc::new(AtomicBool::new(false));
+	let handler_cancel = cancel.clone();
+	let handler = ctrlc::set_handler(move || {
+		if handler_cancel.swap(true, Ordering::SeqCst) {
+			eprintln!("\nAborting.");
+			exit(130);
+		}
+		eprintln!("\nStopping, writing the partial image. Press Ctrl-C again to abort.");
+	});
+	if handler.is_err() {
+		eprintln!("Unable to install the Ctrl-C handler, interrupting will discard the render.");
+	}
+
+	// Create the camera and render every frame
 	let camera = Arc::new(Camera::new(lookfrom, lookat, vup, vfov, aspect_ratio, aperture, dist_to_focus, time0, time1));
 	// let mut scene = random_scene();
 	for frame in 0..frames {
-		let mut pool = threadpool::ThreadPool::new(num_threads);
-		let mut frame_filename = String::from(filename);
-		frame_filename.push('-');
-		frame_filename.push_str(frame.to_string().as_str());
-		frame_filename.push_str(".png");
-
-		run(camera.clone(), make_world(&spheres), &mut pool, samples, image_width, image_height, max_depth, pixel_update, frame_filename.as_str());
-		for i in 1..spheres.len() {
-			let sphere = &mut spheres[i];
+		let frame_filename = frame_filename(filename, frame);
+		let mut checkpointer = checkpoint_interval.map(|interval| Checkpointer::new(&checkpoint_filename(&frame_filename), interval));
+
+		let build_start = Instant::now();
+		let (world, lights) = make_world(&spheres, &extra_lights, time0, time1);
+		let build_time = build_start.elapsed();
+
+		let mut frame_settings = settings.clone();
+		frame_settings.seed = frame_seed(seed, frame);
+		let output = {
+			let mut renderer = Renderer::new(world, camera.clone(), frame_settings);
+			renderer.set_cancel_flag(cancel.clone());
+			renderer.set_background(background.clone());
+			renderer.set_lights(lights);
+			if resume {
+				match Checkpoint::read_file(&checkpoint_filename(&frame_filename)) {
+					Ok(ckpt) => {
+						let (samples_done, passes) = (ckpt.samples_done, ckpt.passes);
+						match renderer.resume(ckpt) {
+							Ok(warnings) => {
+								for warning in warnings.iter() {
+									eprintln!("Warning: {}.", warning);
+								}
+								eprintln!("Resuming from {} samples per pixel after {} passes.", samples_done, passes);
+							}
+							Err(reason) => eprintln!("Unable to resume '{}' ({}), starting over.", frame_filename, reason),
+						}
+					}
+					Err(e) => eprintln!("Unable to read checkpoint for '{}' ({}), starting over.", frame_filename, e),
+				}
+			}
+
+			eprintln!("Running '{}', working to render {}x{} image.", frame_filename, image_width, image_height);
+			renderer.on_progress(|progress| progress.print());
+			let denoiser = settings.denoiser.as_ref();
+			let progressive = settings.progressive.is_progressive();
+			let snapshot_filename = frame_filename.as_str();
+			let checkpointer = &mut checkpointer;
+			renderer.on_pass(move |state, done| {
+				eprintln!();
+				// Progressive renders overwrite the output after every pass, so it can be watched.
+				if progressive && !done {
+					eprintln!("Pass {} done, {} samples per pixel.", state.passes, state.samples_done);
+					write_picture(&renderer::framebuffer(state, denoiser), snapshot_filename);
+				}
+				// The last pass is always saved, so that the render can be extended later.
+				if let Some(checkpointer) = checkpointer.as_mut() {
+					if done || checkpointer.due() {
+						match checkpointer.save(state) {
+							Ok(()) => eprintln!("Saved checkpoint '{}' at {} samples per pixel.", checkpointer.filename(), state.samples_done),
+							Err(e) => eprintln!("Unable to write checkpoint '{}': {}", checkpointer.filename(), e),
+						}
+					}
+				}
+			});
+			renderer.render()
+		};
+
+		match output.stop_reason {
+			StopReason::Completed => {}
+			StopReason::TimeBudget => eprintln!("Time budget reached after {} samples per pixel.", output.state.samples_done),
+			StopReason::NoiseThreshold => eprintln!("Noise threshold reached after {} samples per pixel.", output.state.samples_done),
+			StopReason::Cancelled => {
+				let unrendered = output.state.sample_counts.iter().filter(|c| **c == 0).count();
+				eprintln!("Render cancelled, {} of {} pixels were never traced and are left black.", unrendered, image_width * image_height);
+			}
+		}
+		if let Some(denoiser) = settings.denoiser.as_ref() {
+			eprintln!("Denoising with strength {} over {} passes.", denoiser.strength(), denoiser.iterations());
+		}
+		write_picture(&output.framebuffer(), &frame_filename);
+		stats::report(&output.counters, build_time, output.trace_time);
+		if cancel.load(Ordering::SeqCst) {
+			break;
+		}
+		for sphere in spheres.iter_mut().skip(1) {
 			let x = random_double(0.05, 0.3);
 			// let y = random_double(0.2, 1.2);
 			let z = random_double(-0.3, 0.3);
@@ -107,67 +371,59 @@ fn main() {
 	}
 }
 
-fn make_world(spheres: &Vec<Sphere>) -> Arc<HitList> {
-	let mut world = HitList::new();
+// Each frame gets its own seed, offset from the one given, so that frames
+// don't share noise patterns.
+fn frame_seed(seed: u64, frame: usize) -> u64 {
+	seed.wrapping_add(frame as u64)
+}
 
-	let solid_white = SolidColor::from_rgb(4.0, 4.0, 4.0);
-	let light_mat = Material::new_diffuse_light(Arc::new(solid_white));
-	let rect = XyRect::new(3.0, 5.0, 1.0, 3.0, -2.0, light_mat);
-	world.add(Arc::new(rect));
+fn frame_filename(filename: &str, frame: usize) -> String {
+	format!("{}-{}.png", filename, frame)
+}
 
-	for sphere in spheres.iter() {
-		world.add(Arc::new(sphere.clone()));
+// Exactly count comma separated numbers, such as the values of a light.
+fn parse_numbers(list: &str, count: usize) -> Option<Vec<f64>> {
+	let numbers = list.split(',').map(|n| n.trim().parse::<f64>().ok()).collect::<Option<Vec<f64>>>()?;
+	if numbers.len() == count {
+		Some(numbers)
+	} else {
+		None
 	}
+}
 
-	Arc::new(world)
+fn checkpoint_filename(frame_filename: &str) -> String {
+	format!("{}.ckpt", frame_filename)
 }
 
-fn run(camera: Arc<Camera>, world: Arc<HitList>, pool: &mut ThreadPool, samples: u32, image_width: u32, image_height: u32, max_depth: i32, pixel_update: i32, filename: &str) {
-	eprint!("Running '{}' Scene created, spawning threads....", filename);
-	// Spawn the thread pool with the work that needs to be done.
-	for j in 0..image_height {
-		for i in 0..image_width {
-			pool.run_c(j, i, camera.clone(), world.clone(), samples, image_width, image_height, max_depth);
-		}
+// The world, and its lights: the emissive objects in it and the point, spot
+// and directional lights given on the command line.
+fn make_world(spheres: &[Sphere], extra_lights: &[Arc<dyn Light + Send + Sync>], time0: f64, time1: f64) -> (Arc<HitList>, Arc<LightList>) {
+	let mut objects = HitList::new();
+
+	let solid_white = SolidColor::from_rgb(4.0, 4.0, 4.0);
+	let light_mat = Arc::new(DiffuseLight::new(Arc::new(solid_white)));
+	let rect = XyRect::new(3.0, 5.0, 1.0, 3.0, -2.0, light_mat);
+	objects.add(Arc::new(rect));
+
+	for sphere in spheres.iter() {
+		objects.add(Arc::new(sphere.clone()));
 	}
-	// Even though we get here, the work the threads are doing isn't necessarily done.
-	eprintln!("done.\nWorking to render {}x{} image.", image_width, image_height);
-	eprintln!(
-		"Updating progress every {} pixel{}.",
-		pixel_update,
-		if pixel_update == 1 {
-			""
-		} else {
-			"s"
-		}
-	);
-	let mut pixels_remaining = 0;
-	let mut pixels_written = 0;
-	let total_pixels = image_width * image_height;
-
-	// We get the data from the threads. Recv may block here, which might prevent
-	// us from getting data from another thread, however, the work has to get done
-	// anyway before we write to the BMP file.
-	let mut pictwriter = png::PngPicture::new(image_width, image_height, samples);
-	for t in pool.threads.drain(..) {
-		for _ in 0..t.packets_sent {
-			let d = t.data.recv().unwrap();
-			pictwriter.set_pixel(d.col, d.row, &d.color);
-
-			if pixels_remaining <= 0 {
-				eprint!("\r{:10}/{:<10} pixels traced.", pixels_written, total_pixels);
-				pixels_remaining = pixel_update;
-			}
-			pixels_remaining -= 1;
-			pixels_written += 1;
-		}
+
+	// Emissive objects have to be found before they disappear into the BVH.
+	let mut lights = LightList::new();
+	lights.add_emitters(&objects);
+	for light in extra_lights.iter() {
+		lights.add(light.clone());
 	}
-	eprintln!("\r{:10}/{:<10} pixels traced.", pixels_written, total_pixels);
-	eprintln!("Done writing pixels, writing to BMP file.");
 
-	// When we get here, the pixels have been traced and written in memory. Now,
-	// write them to the BMP file.
-	if let Ok(_) = pictwriter.write_file(filename) {
+	// Everything goes in a BVH, the world only holds its root.
+	let mut world = HitList::new();
+	world.add(Arc::new(BvhNode::new(&mut objects, time0, time1)));
+	(Arc::new(world), Arc::new(lights))
+}
+
+fn write_picture(picture: &PngPicture, filename: &str) {
+	if picture.write_file(filename).is_ok() {
 		println!("Wrote to file '{}'", filename);
 	} else {
 		println!("Unable to write to file '{}'", filename);
@@ -4,129 +4,993 @@
 // 15 Dec 2020
 
 use crate::hitable::HitRecord;
-use crate::random::{random_in_unit_sphere, random_unit_vector};
+use crate::medium::Medium;
+use crate::microfacet::{charlie_d, dielectric_half, fresnel_conductor, fresnel_dielectric, neubelt_visibility, refract as refract_microfacet, refraction_jacobian, roughness_to_alpha, Frame, Ggx, ThinFilm};
+use crate::random::{random_f64, random_in_unit_sphere, random_unit_vector};
 use crate::ray::Ray;
+use crate::spectrum::{blackbody, Ior};
 use crate::texture::{SolidColor, Texture};
 use crate::vector::{Color, Vec3};
+use std::f64::consts::PI;
 use std::ops::{Add, Mul, Neg, Sub};
 use std::sync::Arc;
 
-#[derive(Copy, Clone)]
-pub enum MaterialType {
-	Lambertian,
-	Metal,
-	DiElectric,
-	DiffuseLight,
+// How a surface scatters and emits light. Only scatter() is required, the
+// rest default to a surface that doesn't emit and can't sample lights.
+pub trait Material {
+	// The ray leaving the surface and how much it's attenuated by, or None if
+	// the light is absorbed.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)>;
+
+	// The pdf of scatter() picking this direction for a ray coming in along
+	// ray. Mirrors and glass pick a single direction and report 0.
+	fn pdf(&self, _ray: &Ray, _rec: &HitRecord, _direction: &Vec3) -> f64 {
+		0.0
+	}
+
+	// The BSDF times the cosine for light arriving from direction and leaving
+	// back along ray. Zero for mirrors and glass, like pdf().
+	fn eval(&self, _ray: &Ray, _rec: &HitRecord, _direction: &Vec3) -> Color {
+		Color::new(0.0, 0.0, 0.0)
+	}
+
+	// Whether pdf() and eval() describe this material, so that it can sample
+	// lights directly.
+	fn has_pdf(&self, _rec: &HitRecord) -> bool {
+		false
+	}
+
+	// The radiance leaving the front of the surface. Lights use this to
+	// estimate how bright they are.
+	fn emitted(&self, _u: f64, _v: f64, _point: &Vec3) -> Color {
+		Color::new(0.0, 0.0, 0.0)
+	}
+
+	// The radiance leaving the surface back along ray, which may depend on the
+	// side that was hit.
+	fn emitted_toward(&self, _ray: &Ray, rec: &HitRecord) -> Color {
+		self.emitted(rec.u(), rec.v(), rec.point())
+	}
+
+	fn is_emissive(&self) -> bool {
+		false
+	}
+
+	// Whether an emissive surface shines from its back face as well as the
+	// front one.
+	fn is_two_sided(&self) -> bool {
+		true
+	}
+
+	// Whether the camera sees the surface. Camera rays go on past the ones it
+	// doesn't, every other ray hits them as usual.
+	fn visible_to_camera(&self) -> bool {
+		true
+	}
+
+	// Whether rays go straight through the surface here, as if it weren't
+	// there. Shapes check this before reporting a hit.
+	fn passes_through(&self, _u: f64, _v: f64, _point: &Vec3) -> bool {
+		false
+	}
+
+	// The chance that passes_through() stops a ray here. Light sampling
+	// weighs emission by it instead of making the random choice.
+	fn opacity(&self, _u: f64, _v: f64, _point: &Vec3) -> f64 {
+		1.0
+	}
+
+	// The surface color as seen by the denoiser's albedo guide. Glass has no
+	// color of its own, so it reports white.
+	fn albedo(&self, _u: f64, _v: f64, _point: &Vec3) -> Color {
+		Color::new(1.0, 1.0, 1.0)
+	}
+}
+
+fn roughness_texture(roughness: f64) -> Arc<dyn Texture + Send + Sync> {
+	Arc::new(SolidColor::from_rgb(roughness, roughness, roughness))
+}
+
+// GGX roughness comes from the first channel of a texture.
+fn ggx_at(roughness: &Arc<dyn Texture + Send + Sync>, rec: &HitRecord) -> Ggx {
+	let roughness = roughness.value(rec.u(), rec.v(), rec.point()).r();
+	Ggx::new(roughness_to_alpha(roughness.clamp(0.0, 1.0)))
+}
+
+// The shading frame, with x along the surface's tangent.
+pub(crate) fn shading_frame(rec: &HitRecord) -> Frame {
+	Frame::from_tangent(rec.normal(), rec.dpdu())
+}
+
+// The outgoing and incoming directions in the shading frame, with the
+// outgoing one (back along the ray) above the surface.
+fn local_directions(ray: &Ray, rec: &HitRecord, direction: &Vec3) -> (Vec3, Vec3) {
+	let frame = shading_frame(rec);
+	(frame.to_local(&-ray.direction().unit()), frame.to_local(&direction.unit()))
+}
+
+// The index of refraction past the surface over the one the ray is in.
+fn relative_ir(ir: f64, rec: &HitRecord) -> f64 {
+	if rec.front_face() {
+		ir
+	} else {
+		1.0 / ir
+	}
 }
 
-#[derive(Clone)]
-pub struct Material {
-	material_type: MaterialType,
+// How much light survives the trip through an absorbing medium. A ray that
+// hits the back face started inside, so the distance travelled is the length
+// of the ray up to the hit.
+fn transmittance(absorption: &Color, ray: &Ray, rec: &HitRecord) -> Color {
+	if rec.front_face() {
+		return Color::new(1.0, 1.0, 1.0);
+	}
+	let distance = rec.t() * ray.direction().len();
+	Color::new((-absorption.r() * distance).exp(), (-absorption.g() * distance).exp(), (-absorption.b() * distance).exp())
+}
+
+// The absorption coefficient that tints white light to color over distance,
+// which is easier to pick than the coefficient itself.
+pub fn absorption_from_color(color: &Color, distance: f64) -> Color {
+	let coefficient = |c: f64| -c.max(1.0e-6).ln() / distance;
+	Color::new(coefficient(color.r()), coefficient(color.g()), coefficient(color.b()))
+}
+
+pub struct Lambertian {
 	albedo: Arc<dyn Texture + Send + Sync>,
-	fuzz: f64,
-	ir: f64,
 }
 
-impl Material {
-	pub fn new_lambertian(albedo: Arc<dyn Texture + Send + Sync>) -> Self {
+impl Lambertian {
+	pub fn new(albedo: Arc<dyn Texture + Send + Sync>) -> Self {
 		Self {
-			material_type: MaterialType::Lambertian,
 			albedo,
-			fuzz: 0.0,
-			ir: 0.0,
 		}
 	}
+}
+
+impl Material for Lambertian {
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let scatter_direction = rec.normal().add(&random_unit_vector());
+		let scatter_direction = if scatter_direction.near_zero() {
+			rec.normal()
+		} else {
+			&scatter_direction
+		};
+		let scattered = Ray::new(*rec.point(), *scatter_direction, ray.time());
+		let attenuation = self.albedo.value(rec.u(), rec.v(), rec.point());
+		Some((attenuation, scattered))
+	}
+
+	fn pdf(&self, _ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		(rec.normal().dot(&direction.unit()) / PI).max(0.0)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		self.albedo.value(rec.u(), rec.v(), rec.point()) * self.pdf(ray, rec, direction)
+	}
 
-	pub fn new_metal(albedo: Arc<dyn Texture + Send + Sync>, fuzz: f64) -> Self {
+	fn has_pdf(&self, _rec: &HitRecord) -> bool {
+		true
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.albedo.value(u, v, point)
+	}
+}
+
+pub struct Metal {
+	albedo: Arc<dyn Texture + Send + Sync>,
+	fuzz: f64,
+}
+
+impl Metal {
+	pub fn new(albedo: Arc<dyn Texture + Send + Sync>, fuzz: f64) -> Self {
 		Self {
-			material_type: MaterialType::Metal,
 			albedo,
 			fuzz: if fuzz < 1.0 {
 				fuzz
 			} else {
 				1.0
 			},
-			ir: 0.0,
 		}
 	}
+}
+
+impl Material for Metal {
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let reflected = reflect(&ray.direction().unit(), rec.normal());
+		let scattered = Ray::new(*rec.point(), reflected + &(random_in_unit_sphere() * self.fuzz), ray.time());
+		let attenuation = self.albedo.clone();
+		if scattered.direction().dot(rec.normal()) > 0.0 {
+			Some((attenuation.value(rec.u(), rec.v(), rec.point()), scattered))
+		} else {
+			None
+		}
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.albedo.value(u, v, point)
+	}
+}
 
-	pub fn new_dielectric(ir: f64) -> Self {
+// absorption is per unit distance inside the medium, for each color channel.
+// Clear glass doesn't absorb anything.
+pub struct DiElectric {
+	ior: Ior,
+	absorption: Color,
+}
+
+impl DiElectric {
+	pub fn new(ir: f64) -> Self {
+		Self::new_dispersive(Ior::Constant(ir))
+	}
+
+	// Glass whose index of refraction depends on the wavelength, which splits
+	// white light in spectral renders. RGB renders use the nominal index.
+	pub fn new_dispersive(ior: Ior) -> Self {
 		Self {
-			material_type: MaterialType::DiElectric,
-			albedo: Arc::new(SolidColor::default()),
-			fuzz: 0.0,
-			ir,
+			ior,
+			absorption: Color::new(0.0, 0.0, 0.0),
 		}
 	}
 
-	pub fn new_diffuse_light(emit: Arc<dyn Texture + Send + Sync>) -> Self {
+	pub fn with_absorption(mut self, absorption: Color) -> Self {
+		self.absorption = absorption;
+		self
+	}
+}
+
+impl Material for DiElectric {
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let attenuation = transmittance(&self.absorption, ray, rec);
+		// Refraction that depends on the wavelength can only follow the hero.
+		let (ir, wavelengths) = match ray.wavelengths() {
+			Some(wavelengths) if self.ior.is_dispersive() => (self.ior.at(wavelengths.hero()), Some(wavelengths.terminate_secondary())),
+			_ => (self.ior.nominal(), None),
+		};
+		let refraction_ratio = if rec.front_face() {
+			1.0 / ir
+		} else {
+			ir
+		};
+
+		let unit_direction = ray.direction().unit();
+		let cos_theta = unit_direction.neg().dot(rec.normal()).min(1.0);
+		let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+		let direction = if refraction_ratio * sin_theta > 1.0 {
+			// Total internal reflection.
+			reflect(&unit_direction, rec.normal())
+		} else {
+			refract(&unit_direction, rec.normal(), refraction_ratio)
+		};
+
+		let scattered = Ray::new(*rec.point(), direction, ray.time());
+		match wavelengths {
+			Some(wavelengths) => Some((attenuation, scattered.with_wavelengths(wavelengths))),
+			None => Some((attenuation, scattered)),
+		}
+	}
+}
+
+// Emits the same radiance in every direction. By default it shines from both
+// faces, and can be seen by the camera.
+pub struct DiffuseLight {
+	emit: Arc<dyn Texture + Send + Sync>,
+	strength: f64,
+	two_sided: bool,
+	camera_visible: bool,
+}
+
+impl DiffuseLight {
+	pub fn new(emit: Arc<dyn Texture + Send + Sync>) -> Self {
 		Self {
-			material_type: MaterialType::DiffuseLight,
-			albedo: emit,
-			fuzz: 0.0,
-			ir: 0.0,
+			emit,
+			strength: 1.0,
+			two_sided: true,
+			camera_visible: true,
 		}
 	}
 
-	pub fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-		match self.material_type {
-			MaterialType::Lambertian => self.scatter_lambertian(ray, rec),
-			MaterialType::Metal => self.scatter_metal(ray, rec),
-			MaterialType::DiElectric => self.scatter_dielectric(ray, rec),
-			MaterialType::DiffuseLight => None,
+	// The color of a black body at this temperature, like 2700 K for a warm
+	// bulb or 6500 K for daylight, with a luminance of strength.
+	pub fn blackbody(kelvin: f64, strength: f64) -> Self {
+		Self::new(Arc::new(SolidColor::new(blackbody(kelvin)))).with_strength(strength)
+	}
+
+	// Scales the color, so it can stay between 0 and 1.
+	pub fn with_strength(mut self, strength: f64) -> Self {
+		self.strength = strength;
+		self
+	}
+
+	// Only shine from the front face, the way the outward normal points.
+	pub fn one_sided(mut self) -> Self {
+		self.two_sided = false;
+		self
+	}
+
+	// Still lights the scene, but the camera sees what's behind it.
+	pub fn invisible_to_camera(mut self) -> Self {
+		self.camera_visible = false;
+		self
+	}
+}
+
+impl Material for DiffuseLight {
+	fn scatter(&self, _ray: &Ray, _rec: &HitRecord) -> Option<(Color, Ray)> {
+		None
+	}
+
+	fn emitted(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.emit.value(u, v, point) * self.strength
+	}
+
+	fn emitted_toward(&self, _ray: &Ray, rec: &HitRecord) -> Color {
+		if !self.two_sided && !rec.front_face() {
+			return Color::new(0.0, 0.0, 0.0);
 		}
+		self.emitted(rec.u(), rec.v(), rec.point())
+	}
+
+	fn is_emissive(&self) -> bool {
+		true
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.two_sided
+	}
+
+	fn visible_to_camera(&self) -> bool {
+		self.camera_visible
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.emit.value(u, v, point)
 	}
+}
+
+// A metal with a GGX microfacet surface. eta and k are the real and
+// imaginary parts of its index of refraction, per color channel.
+pub struct Conductor {
+	eta: Color,
+	k: Color,
+	// Along the tangent and the bitangent, which are the same unless the
+	// metal is anisotropic.
+	roughness_t: Arc<dyn Texture + Send + Sync>,
+	roughness_b: Arc<dyn Texture + Send + Sync>,
+	film: Option<ThinFilm>,
+}
 
-	pub fn emitted(&self, u: f64, v: f64, point: &Vec3) -> Color {
-		match self.material_type {
-			MaterialType::Lambertian => Color::new(0.0, 0.0, 0.0),
-			MaterialType::Metal => Color::new(0.0, 0.0, 0.0),
-			MaterialType::DiElectric => Color::new(0.0, 0.0, 0.0),
-			MaterialType::DiffuseLight => self.emit_diffused(u, v, point),
+impl Conductor {
+	pub fn new(eta: Color, k: Color, roughness: f64) -> Self {
+		let roughness = roughness_texture(roughness);
+		Self {
+			eta,
+			k,
+			roughness_t: roughness.clone(),
+			roughness_b: roughness,
+			film: None,
 		}
 	}
 
-	fn emit_diffused(&self, u: f64, v: f64, point: &Vec3) -> Color {
-		self.albedo.value(u, v, point)
+	// Brushed metal, which is rougher across the brushing than along it.
+	// Brushing along the tangent needs a larger roughness_b.
+	pub fn with_anisotropic_roughness(mut self, roughness_t: f64, roughness_b: f64) -> Self {
+		self.roughness_t = roughness_texture(roughness_t);
+		self.roughness_b = roughness_texture(roughness_b);
+		self
 	}
 
-	fn scatter_lambertian(&self, ray: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-		let scatter_direction = rec.normal().add(&random_unit_vector());
-		let scatter_direction = if scatter_direction.near_zero() {
-			rec.normal()
-		} else {
-			&scatter_direction
+	fn ggx(&self, rec: &HitRecord) -> Ggx {
+		let (u, v, point) = (rec.u(), rec.v(), rec.point());
+		let alpha_t = roughness_to_alpha(self.roughness_t.value(u, v, point).r().clamp(0.0, 1.0));
+		let alpha_b = roughness_to_alpha(self.roughness_b.value(u, v, point).r().clamp(0.0, 1.0));
+		Ggx::new_anisotropic(alpha_t, alpha_b)
+	}
+
+	// Coat the metal with a thin film, like the oxide on heated steel.
+	pub fn with_thin_film(mut self, film: ThinFilm) -> Self {
+		self.film = Some(film);
+		self
+	}
+
+	fn fresnel(&self, cos_theta: f64) -> Color {
+		match self.film.as_ref() {
+			Some(film) => film.reflectance_conductor(cos_theta, &self.eta, &self.k),
+			None => fresnel_conductor(cos_theta, &self.eta, &self.k),
+		}
+	}
+
+	// Measured metals, with eta and k at 650, 550 and 450 nm.
+	pub fn gold(roughness: f64) -> Self {
+		Self::new(Color::new(0.143, 0.374, 1.442), Color::new(3.983, 2.385, 1.603), roughness)
+	}
+
+	pub fn copper(roughness: f64) -> Self {
+		Self::new(Color::new(0.200, 0.924, 1.102), Color::new(3.912, 2.452, 2.142), roughness)
+	}
+
+	pub fn aluminum(roughness: f64) -> Self {
+		Self::new(Color::new(1.657, 0.880, 0.521), Color::new(9.224, 6.270, 4.837), roughness)
+	}
+
+	pub fn silver(roughness: f64) -> Self {
+		Self::new(Color::new(0.155, 0.117, 0.138), Color::new(4.828, 3.122, 2.147), roughness)
+	}
+}
+
+impl Material for Conductor {
+	// Samples the microfacet normals visible from the ray, so the weight is
+	// just the Fresnel term and the shadowing of the outgoing ray.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let ggx = self.ggx(rec);
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		if wo.z() <= 0.0 {
+			return None;
+		}
+		if ggx.is_smooth() {
+			let reflected = reflect(&ray.direction().unit(), rec.normal());
+			let attenuation = self.fresnel(wo.z());
+			return Some((attenuation, Ray::new(*rec.point(), reflected, ray.time())));
+		}
+		let h = ggx.sample_visible(&wo);
+		let wi = reflect(&-wo, &h);
+		if wi.z() <= 0.0 {
+			return None;
+		}
+		let attenuation = self.fresnel(wo.dot(&h)) * (ggx.g(&wo, &wi) / ggx.g1(&wo));
+		Some((attenuation, Ray::new(*rec.point(), frame.to_world(&wi), ray.time())))
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		let ggx = self.ggx(rec);
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if ggx.is_smooth() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return 0.0;
+		}
+		let h = (wo + &wi).unit();
+		ggx.d_visible(&wo, &h) / (4.0 * wo.dot(&h))
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let ggx = self.ggx(rec);
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if ggx.is_smooth() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		let h = (wo + &wi).unit();
+		self.fresnel(wo.dot(&h)) * (ggx.d(&h) * ggx.g(&wo, &wi) / (4.0 * wo.z()))
+	}
+
+	fn has_pdf(&self, rec: &HitRecord) -> bool {
+		!self.ggx(rec).is_smooth()
+	}
+
+	fn albedo(&self, _u: f64, _v: f64, _point: &Vec3) -> Color {
+		self.fresnel(1.0)
+	}
+}
+
+// Frosted glass, after Walter et al. 2007. ir is the index of refraction and
+// absorption the same as DiElectric.
+pub struct RoughDielectric {
+	ir: f64,
+	roughness: Arc<dyn Texture + Send + Sync>,
+	absorption: Color,
+	film: Option<ThinFilm>,
+}
+
+impl RoughDielectric {
+	pub fn new(ir: f64, roughness: f64) -> Self {
+		Self::new_textured(ir, roughness_texture(roughness))
+	}
+
+	// Glass with the roughness varying over the surface.
+	pub fn new_textured(ir: f64, roughness: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			ir,
+			roughness,
+			absorption: Color::new(0.0, 0.0, 0.0),
+			film: None,
+		}
+	}
+
+	pub fn with_absorption(mut self, absorption: Color) -> Self {
+		self.absorption = absorption;
+		self
+	}
+
+	// Coat the glass with a thin film. With an ir of 1, so that light passes
+	// straight through, this is a soap bubble.
+	pub fn with_thin_film(mut self, film: ThinFilm) -> Self {
+		self.film = Some(film);
+		self
+	}
+
+	// The reflectance of a microfacet, and the chance of sampling reflection
+	// off it instead of refraction through it.
+	fn fresnel(&self, cos_theta: f64, rec: &HitRecord) -> (Color, f64) {
+		let f = match self.film.as_ref() {
+			Some(film) if rec.front_face() => film.reflectance_dielectric(cos_theta, 1.0, self.ir),
+			Some(film) => film.reflectance_dielectric(cos_theta, self.ir, 1.0),
+			None => {
+				let f = fresnel_dielectric(cos_theta, relative_ir(self.ir, rec));
+				Color::new(f, f, f)
+			}
 		};
-		let scattered = Ray::new(rec.point().clone(), scatter_direction.clone(), ray.time());
-		let attenuation = self.albedo.value(rec.u(), rec.v(), rec.point());
-		Some((attenuation, scattered))
+		(f, mean(&f))
 	}
 
-	fn scatter_metal(&self, ray: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-		let reflected = reflect(&ray.direction().unit(), rec.normal());
-		let scattered = Ray::new(*rec.point(), reflected + &(random_in_unit_sphere() * self.fuzz), ray.time());
-		let attenuation = self.albedo.clone();
-		if scattered.direction().dot(rec.normal()) > 0.0 {
-			Some((attenuation.value(rec.u(), rec.v(), rec.point()), scattered))
+	// The BSDF times the cosine, before absorption.
+	fn eval_fresnel(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let ggx = ggx_at(&self.roughness, rec);
+		let eta = relative_ir(self.ir, rec);
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if ggx.is_smooth() || wo.z() <= 0.0 || wi.z() == 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		let h = match dielectric_half(&wo, &wi, eta) {
+			Some(h) => h,
+			None => return Color::new(0.0, 0.0, 0.0),
+		};
+		let (f, _) = self.fresnel(wo.dot(&h), rec);
+		let dg = ggx.d(&h) * ggx.g(&wo, &wi);
+		if wi.z() > 0.0 {
+			f * (dg / (4.0 * wo.z()))
 		} else {
-			None
+			(Color::new(1.0, 1.0, 1.0) - &f) * (dg * wo.dot(&h) * refraction_jacobian(&wo, &wi, &h, eta) / wo.z())
 		}
 	}
 
-	fn scatter_dielectric(&self, ray: &Ray, rec: &HitRecord) -> Option<(Vec3, Ray)> {
-		let attenuation = Color::new(1.0, 1.0, 1.0);
-		let refraction_ratio = if rec.front_face() {
-			1.0 / self.ir
+	// Reflects or refracts wo through a microfacet with normal h, and how much
+	// the choice has to be weighted by.
+	fn choose(&self, wo: &Vec3, h: &Vec3, eta: f64, rec: &HitRecord) -> (Vec3, Color) {
+		let (f, reflect_chance) = self.fresnel(wo.dot(h), rec);
+		match refract_microfacet(wo, h, eta) {
+			Some(wt) if random_f64() >= reflect_chance => (wt, (Color::new(1.0, 1.0, 1.0) - &f) / (1.0 - reflect_chance)),
+			Some(_) => (reflect(&-*wo, h), f / reflect_chance),
+			None => (reflect(&-*wo, h), Color::new(1.0, 1.0, 1.0)),
+		}
+	}
+}
+
+impl Material for RoughDielectric {
+	// Picks a visible microfacet, then reflects or refracts through it in
+	// proportion to its Fresnel reflectance. Like smooth glass, radiance isn't
+	// scaled by the change in index of refraction.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let ggx = ggx_at(&self.roughness, rec);
+		let eta = relative_ir(self.ir, rec);
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		if wo.z() <= 0.0 {
+			return None;
+		}
+		let transmitted = transmittance(&self.absorption, ray, rec);
+		if ggx.is_smooth() {
+			let (wi, weight) = self.choose(&wo, &Vec3::new(0.0, 0.0, 1.0), eta, rec);
+			return Some((transmitted * &weight, Ray::new(*rec.point(), frame.to_world(&wi), ray.time())));
+		}
+		let h = ggx.sample_visible(&wo);
+		let (wi, weight) = self.choose(&wo, &h, eta, rec);
+		// Refraction has to go through the surface, reflection can't.
+		let reflected = wi.dot(&h) > 0.0;
+		if (reflected && wi.z() <= 0.0) || (!reflected && wi.z() >= 0.0) {
+			return None;
+		}
+		let attenuation = transmitted * &weight * (ggx.g(&wo, &wi) / ggx.g1(&wo));
+		Some((attenuation, Ray::new(*rec.point(), frame.to_world(&wi), ray.time())))
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		let ggx = ggx_at(&self.roughness, rec);
+		let eta = relative_ir(self.ir, rec);
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if ggx.is_smooth() || wo.z() <= 0.0 || wi.z() == 0.0 {
+			return 0.0;
+		}
+		let h = match dielectric_half(&wo, &wi, eta) {
+			Some(h) => h,
+			None => return 0.0,
+		};
+		let reflect_chance = match refract_microfacet(&wo, &h, eta) {
+			Some(_) => self.fresnel(wo.dot(&h), rec).1,
+			None => 1.0,
+		};
+		if wi.z() > 0.0 {
+			reflect_chance * ggx.d_visible(&wo, &h) / (4.0 * wo.dot(&h))
+		} else {
+			(1.0 - reflect_chance) * ggx.d_visible(&wo, &h) * refraction_jacobian(&wo, &wi, &h, eta)
+		}
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		transmittance(&self.absorption, ray, rec) * &self.eval_fresnel(ray, rec, direction)
+	}
+
+	fn has_pdf(&self, rec: &HitRecord) -> bool {
+		!ggx_at(&self.roughness, rec).is_smooth()
+	}
+}
+
+// Skin, wax and marble, where light wanders around under the surface before
+// it comes back out. Light crosses the boundary diffusely, in both directions,
+// and takes a random walk through a medium in between, so the object has to
+// be closed. color is roughly what the object looks like from afar, and
+// mean_free_path how far each color gets between collisions.
+pub struct Subsurface {
+	color: Arc<dyn Texture + Send + Sync>,
+	mean_free_path: Color,
+}
+
+impl Subsurface {
+	pub fn new(color: Arc<dyn Texture + Send + Sync>, mean_free_path: Color) -> Self {
+		Self {
+			color,
+			mean_free_path,
+		}
+	}
+
+	// Cosine weighted, through the surface.
+	fn transmit(&self, ray: &Ray, rec: &HitRecord) -> Ray {
+		let inside = rec.normal().neg();
+		let direction = inside.add(&random_unit_vector());
+		let direction = if direction.near_zero() {
+			inside
 		} else {
-			self.ir
+			direction
 		};
+		Ray::new(*rec.point(), direction, ray.time())
+	}
+}
 
-		let unit_direction = ray.direction().unit();
-		let refracted = refract(&unit_direction, rec.normal(), refraction_ratio);
+impl Material for Subsurface {
+	// The medium takes the color where the light went in.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let scattered = self.transmit(ray, rec);
+		let white = Color::new(1.0, 1.0, 1.0);
+		if rec.front_face() {
+			let color = self.color.value(rec.u(), rec.v(), rec.point());
+			let medium = Arc::new(Medium::from_subsurface(&color, &self.mean_free_path));
+			Some((white, scattered.with_medium(medium)))
+		} else {
+			Some((white, scattered))
+		}
+	}
 
-		let scattered = Ray::new(*rec.point(), refracted, ray.time());
-		Some((attenuation, scattered))
+	fn pdf(&self, _ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		if rec.front_face() {
+			return 0.0;
+		}
+		(-rec.normal().dot(&direction.unit()) / PI).max(0.0)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		Color::new(1.0, 1.0, 1.0) * self.pdf(ray, rec, direction)
+	}
+
+	// Only light leaving the object can be lit directly, nothing shines
+	// inside of it.
+	fn has_pdf(&self, rec: &HitRecord) -> bool {
+		!rec.front_face()
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.color.value(u, v, point)
+	}
+}
+
+// Fabric, like velvet or satin: a diffuse base, and a sheen from fibers that
+// catch the light at grazing angles. The sheen is Estevez and Kulla's
+// "Charlie" distribution with Neubelt and Pettineo's visibility term, as in
+// Filament's cloth model.
+pub struct Cloth {
+	color: Arc<dyn Texture + Send + Sync>,
+	sheen: Arc<dyn Texture + Send + Sync>,
+	roughness: Arc<dyn Texture + Send + Sync>,
+}
+
+impl Cloth {
+	pub fn new(color: Arc<dyn Texture + Send + Sync>, sheen: Arc<dyn Texture + Send + Sync>, roughness: f64) -> Self {
+		Self::new_textured(color, sheen, roughness_texture(roughness))
+	}
+
+	pub fn new_textured(color: Arc<dyn Texture + Send + Sync>, sheen: Arc<dyn Texture + Send + Sync>, roughness: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			color,
+			sheen,
+			roughness,
+		}
+	}
+}
+
+impl Material for Cloth {
+	// The sheen is spread wide, so sampling the cosine suits both lobes.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let scatter_direction = rec.normal().add(&random_unit_vector());
+		if scatter_direction.near_zero() {
+			return None;
+		}
+		let scattered = Ray::new(*rec.point(), scatter_direction, ray.time());
+		let pdf = self.pdf(ray, rec, scattered.direction());
+		if pdf <= 0.0 {
+			return None;
+		}
+		Some((self.eval(ray, rec, scattered.direction()) / pdf, scattered))
+	}
+
+	fn pdf(&self, _ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		(rec.normal().dot(&direction.unit()) / PI).max(0.0)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		let (u, v, point) = (rec.u(), rec.v(), rec.point());
+		let alpha = roughness_to_alpha(self.roughness.value(u, v, point).r().clamp(0.0, 1.0));
+		let h = (wo + &wi).unit();
+		let sheen = self.sheen.value(u, v, point) * (charlie_d(&h, alpha) * neubelt_visibility(wo.z(), wi.z()));
+		(self.color.value(u, v, point) / PI + &sheen) * wi.z()
 	}
+
+	fn has_pdf(&self, _rec: &HitRecord) -> bool {
+		true
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.color.value(u, v, point)
+	}
+}
+
+// A clear dielectric coat over any other material, like varnish or the
+// clearcoat on car paint. Light the coat doesn't reflect reaches the base on
+// the way in, and has to get through the coat again on the way out, so
+// together they never reflect more light than comes in. The coat is thin
+// enough that it doesn't bend light on its way to the base.
+pub struct Clearcoat {
+	base: Arc<dyn Material + Send + Sync>,
+	ir: f64,
+	roughness: Arc<dyn Texture + Send + Sync>,
+}
+
+impl Clearcoat {
+	pub fn new(base: Arc<dyn Material + Send + Sync>, ir: f64, roughness: f64) -> Self {
+		Self::new_textured(base, ir, roughness_texture(roughness))
+	}
+
+	pub fn new_textured(base: Arc<dyn Material + Send + Sync>, ir: f64, roughness: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			base,
+			ir,
+			roughness,
+		}
+	}
+
+	// How much gets through the coat at this angle.
+	fn transmitted(&self, cos_theta: f64) -> f64 {
+		1.0 - fresnel_dielectric(cos_theta.abs(), self.ir)
+	}
+
+	// The chance of sampling the coat instead of the base. Each gets at least
+	// a quarter of the samples.
+	fn coat_chance(&self, cos_theta: f64) -> f64 {
+		(1.0 - self.transmitted(cos_theta)).clamp(0.25, 0.75)
+	}
+
+	// The coat's own reflection, times the cosine.
+	fn eval_coat(&self, ggx: &Ggx, wo: &Vec3, wi: &Vec3) -> f64 {
+		if ggx.is_smooth() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return 0.0;
+		}
+		let h = (*wo + wi).unit();
+		fresnel_dielectric(wo.dot(&h), self.ir) * ggx.d(&h) * ggx.g(wo, wi) / (4.0 * wo.z())
+	}
+
+	fn pdf_coat(&self, ggx: &Ggx, wo: &Vec3, wi: &Vec3) -> f64 {
+		if ggx.is_smooth() || wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return 0.0;
+		}
+		let h = (*wo + wi).unit();
+		ggx.d_visible(wo, &h) / (4.0 * wo.dot(&h))
+	}
+}
+
+impl Material for Clearcoat {
+	// Picks the coat or the base. When both can be evaluated, the sample is
+	// weighted against the two of them together, otherwise each is weighted
+	// by the chance of picking it.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let ggx = ggx_at(&self.roughness, rec);
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		if wo.z() <= 0.0 {
+			return None;
+		}
+		let has_pdf = self.has_pdf(rec);
+		let coat_chance = self.coat_chance(wo.z());
+		if random_f64() < coat_chance {
+			let (wi, weight) = if ggx.is_smooth() {
+				let wi = Vec3::new(-wo.x(), -wo.y(), wo.z());
+				(wi, fresnel_dielectric(wo.z(), self.ir) / coat_chance)
+			} else {
+				let h = ggx.sample_visible(&wo);
+				let wi = reflect(&-wo, &h);
+				if wi.z() <= 0.0 {
+					return None;
+				}
+				(wi, fresnel_dielectric(wo.dot(&h), self.ir) * ggx.g(&wo, &wi) / ggx.g1(&wo) / coat_chance)
+			};
+			let scattered = Ray::new(*rec.point(), frame.to_world(&wi), ray.time());
+			if has_pdf {
+				let pdf = self.pdf(ray, rec, scattered.direction());
+				if pdf <= 0.0 {
+					return None;
+				}
+				return Some((self.eval(ray, rec, scattered.direction()) / pdf, scattered));
+			}
+			return Some((Color::new(1.0, 1.0, 1.0) * weight, scattered));
+		}
+		let (attenuation, scattered) = self.base.scatter(ray, rec)?;
+		if has_pdf {
+			let pdf = self.pdf(ray, rec, scattered.direction());
+			if pdf <= 0.0 {
+				return None;
+			}
+			return Some((self.eval(ray, rec, scattered.direction()) / pdf, scattered));
+		}
+		let wi = frame.to_local(&scattered.direction().unit());
+		let weight = self.transmitted(wo.z()) * self.transmitted(wi.z()) / (1.0 - coat_chance);
+		Some((attenuation * weight, scattered))
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		let ggx = ggx_at(&self.roughness, rec);
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if wo.z() <= 0.0 {
+			return 0.0;
+		}
+		let coat_chance = self.coat_chance(wo.z());
+		coat_chance * self.pdf_coat(&ggx, &wo, &wi) + (1.0 - coat_chance) * self.base.pdf(ray, rec, direction)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let ggx = ggx_at(&self.roughness, rec);
+		let (wo, wi) = local_directions(ray, rec, direction);
+		if wo.z() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		let coat = self.eval_coat(&ggx, &wo, &wi);
+		let base = self.base.eval(ray, rec, direction) * (self.transmitted(wo.z()) * self.transmitted(wi.z()));
+		base + coat
+	}
+
+	// Both layers need a pdf for the whole to have one.
+	fn has_pdf(&self, rec: &HitRecord) -> bool {
+		self.base.has_pdf(rec) && !ggx_at(&self.roughness, rec).is_smooth()
+	}
+
+	fn emitted(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base.emitted(u, v, point)
+	}
+
+	fn emitted_toward(&self, ray: &Ray, rec: &HitRecord) -> Color {
+		self.base.emitted_toward(ray, rec)
+	}
+
+	fn is_emissive(&self) -> bool {
+		self.base.is_emissive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.base.is_two_sided()
+	}
+
+	fn visible_to_camera(&self) -> bool {
+		self.base.visible_to_camera()
+	}
+
+	fn passes_through(&self, u: f64, v: f64, point: &Vec3) -> bool {
+		self.base.passes_through(u, v, point)
+	}
+
+	fn opacity(&self, u: f64, v: f64, point: &Vec3) -> f64 {
+		self.base.opacity(u, v, point)
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base.albedo(u, v, point)
+	}
+}
+
+// Any material with holes cut in it, like a leaf or a fence on a quad. The
+// opacity comes from the first channel of a texture, such as an AlphaTexture.
+// By default a surface that is half opaque is hit by half of the rays, so
+// soft edges stay soft. With a threshold, it's either hit or not.
+pub struct Cutout {
+	base: Arc<dyn Material + Send + Sync>,
+	opacity: Arc<dyn Texture + Send + Sync>,
+	threshold: Option<f64>,
+}
+
+impl Cutout {
+	pub fn new(base: Arc<dyn Material + Send + Sync>, opacity: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			base,
+			opacity,
+			threshold: None,
+		}
+	}
+
+	// Only hit where the opacity is at least threshold.
+	pub fn with_threshold(mut self, threshold: f64) -> Self {
+		self.threshold = Some(threshold);
+		self
+	}
+}
+
+impl Material for Cutout {
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		self.base.scatter(ray, rec)
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		self.base.pdf(ray, rec, direction)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		self.base.eval(ray, rec, direction)
+	}
+
+	fn has_pdf(&self, rec: &HitRecord) -> bool {
+		self.base.has_pdf(rec)
+	}
+
+	fn emitted(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base.emitted(u, v, point)
+	}
+
+	fn emitted_toward(&self, ray: &Ray, rec: &HitRecord) -> Color {
+		self.base.emitted_toward(ray, rec)
+	}
+
+	fn is_emissive(&self) -> bool {
+		self.base.is_emissive()
+	}
+
+	fn is_two_sided(&self) -> bool {
+		self.base.is_two_sided()
+	}
+
+	fn visible_to_camera(&self) -> bool {
+		self.base.visible_to_camera()
+	}
+
+	fn passes_through(&self, u: f64, v: f64, point: &Vec3) -> bool {
+		let opacity = self.opacity(u, v, point);
+		opacity < 1.0 && random_f64() >= opacity
+	}
+
+	// The base material may have holes of its own.
+	fn opacity(&self, u: f64, v: f64, point: &Vec3) -> f64 {
+		let opacity = self.opacity.value(u, v, point).r().clamp(0.0, 1.0);
+		let own = match self.threshold {
+			Some(threshold) if opacity < threshold => 0.0,
+			Some(_) => 1.0,
+			None => opacity,
+		};
+		own * self.base.opacity(u, v, point)
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base.albedo(u, v, point)
+	}
+}
+
+fn mean(c: &Color) -> f64 {
+	(c.r() + c.g() + c.b()) / 3.0
 }
 
 fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
@@ -136,7 +1000,103 @@ fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
 fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
 	let cos_theta = uv.neg().dot(n).min(1.0);
 	let r_out_perp = uv.add(&n.mul(cos_theta)) * etai_over_etat;
-	let perp = (1.0 - r_out_perp.len2()).abs().sqrt();
-	let r_out_parallel = n.mul(perp);
+	let perp = (1.0 - r_out_perp.len2()).max(0.0).sqrt();
+	let r_out_parallel = n.mul(-perp);
 	r_out_perp + &r_out_parallel
 }
+
+// Checks shared by the tests of materials that can be sampled directly.
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use crate::random;
+	use std::f64::consts::PI;
+
+	// Bins over the sphere of directions, even in cos theta and phi, so every
+	// bin covers the same solid angle.
+	const Z_BINS: usize = 16;
+	const PHI_BINS: usize = 16;
+	// Points per side of the grid the pdf is integrated over in each bin.
+	const GRID: usize = 24;
+
+	// A hit at the origin of a surface facing +z, by a ray coming in along
+	// direction, which has to point down. front_face false is the inside.
+	pub(crate) fn hit(material: Arc<dyn Material + Send + Sync>, direction: Vec3, front_face: bool) -> (Ray, HitRecord) {
+		let direction = direction.unit();
+		let mut rec = HitRecord::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 1.0, front_face, material, 0.5, 0.5);
+		rec.set_derivatives(Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+		(Ray::new(-direction, direction, 0.0), rec)
+	}
+
+	fn bin(direction: &Vec3) -> usize {
+		let d = direction.unit();
+		let z = (((d.z() + 1.0) / 2.0 * Z_BINS as f64) as usize).min(Z_BINS - 1);
+		let phi = (d.y().atan2(d.x()).rem_euclid(2.0 * PI) / (2.0 * PI) * PHI_BINS as f64) as usize;
+		z * PHI_BINS + phi.min(PHI_BINS - 1)
+	}
+
+	// The directions scatter() picks fall in each bin as often as pdf() says.
+	// Samples that scatter() gives up on count against the pdf too.
+	pub(crate) fn assert_sampling_matches_pdf(material: &dyn Material, ray: &Ray, rec: &HitRecord, samples: usize) {
+		random::seed(1);
+		let mut observed = vec![0.0; Z_BINS * PHI_BINS];
+		for _ in 0..samples {
+			if let Some((_, scattered)) = material.scatter(ray, rec) {
+				observed[bin(scattered.direction())] += 1.0 / samples as f64;
+			}
+		}
+		let cell = 4.0 * PI / (Z_BINS * PHI_BINS * GRID * GRID) as f64;
+		for z in 0..Z_BINS * GRID {
+			for p in 0..PHI_BINS * GRID {
+				let cos_theta = -1.0 + (z as f64 + 0.5) * 2.0 / (Z_BINS * GRID) as f64;
+				let phi = (p as f64 + 0.5) * 2.0 * PI / (PHI_BINS * GRID) as f64;
+				let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+				let direction = Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+				observed[bin(&direction)] -= material.pdf(ray, rec, &direction) * cell;
+			}
+		}
+		for (i, difference) in observed.iter().enumerate() {
+			assert!(difference.abs() < 0.004, "bin {} is off by {}", i, difference);
+		}
+	}
+
+	// The average of eval / pdf over the directions scatter() picks, which is
+	// how much of the light arriving from ray's direction is scattered. Only
+	// the first channel is used.
+	pub(crate) fn reflected_energy(material: &dyn Material, ray: &Ray, rec: &HitRecord, samples: usize) -> f64 {
+		random::seed(2);
+		let mut total = 0.0;
+		for _ in 0..samples {
+			if let Some((_, scattered)) = material.scatter(ray, rec) {
+				let pdf = material.pdf(ray, rec, scattered.direction());
+				if pdf > 0.0 {
+					total += material.eval(ray, rec, scattered.direction()).r() / pdf;
+				}
+			}
+		}
+		total / samples as f64
+	}
+
+	#[test]
+	fn rough_dielectric_samples_its_pdf() {
+		for (roughness, direction, front_face) in [(0.3, Vec3::new(0.4, 0.2, -1.0), true), (0.6, Vec3::new(-1.0, 0.3, -0.5), true), (0.5, Vec3::new(0.2, 0.0, -1.0), false)] {
+			let glass: Arc<dyn Material + Send + Sync> = Arc::new(RoughDielectric::new(1.5, roughness));
+			let (ray, rec) = hit(glass.clone(), direction, front_face);
+			assert_sampling_matches_pdf(glass.as_ref(), &ray, &rec, 200_000);
+		}
+	}
+
+	#[test]
+	fn rough_dielectric_does_not_create_energy() {
+		for roughness in [0.1, 0.5, 1.0] {
+			for direction in [Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -0.1)] {
+				for front_face in [true, false] {
+					let glass: Arc<dyn Material + Send + Sync> = Arc::new(RoughDielectric::new(1.5, roughness));
+					let (ray, rec) = hit(glass.clone(), direction, front_face);
+					let energy = reflected_energy(glass.as_ref(), &ray, &rec, 50_000);
+					assert!(energy <= 1.0 + 1.0e-3, "roughness {} reflects {}", roughness, energy);
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,44 @@
+// medium.rs
+// Homogeneous participating media for subsurface scattering
+
+use crate::vector::Color;
+
+// A medium that absorbs and scatters the same everywhere, isotropically.
+// sigma_t is how often light hits something per unit distance, and albedo is
+// the chance that it scatters instead of being absorbed when it does.
+pub struct Medium {
+	sigma_t: Color,
+	albedo: Color,
+}
+
+impl Medium {
+	pub fn new(sigma_t: Color, albedo: Color) -> Self {
+		Self {
+			sigma_t,
+			albedo,
+		}
+	}
+
+	// The medium that makes a thick slab look like color, when light travels
+	// mean_free_path between collisions. The single scattering albedo comes
+	// from Chiang et al. 2016's fit.
+	pub fn from_subsurface(color: &Color, mean_free_path: &Color) -> Self {
+		let mut sigma_t = Color::new(0.0, 0.0, 0.0);
+		let mut albedo = Color::new(0.0, 0.0, 0.0);
+		for c in 0..3 {
+			sigma_t[c] = 1.0 / mean_free_path[c].max(1.0e-6);
+			let a = color[c].clamp(0.0, 1.0);
+			let s = 4.09712 + 4.20863 * a - (9.59217 + 41.6808 * a + 17.7126 * a * a).sqrt();
+			albedo[c] = (1.0 - s * s).clamp(0.0, 1.0);
+		}
+		Self::new(sigma_t, albedo)
+	}
+
+	pub fn sigma_t(&self) -> &Color {
+		&self.sigma_t
+	}
+
+	pub fn albedo(&self) -> &Color {
+		&self.albedo
+	}
+}
@@ -0,0 +1,353 @@
+// merl.rs
+// MERL measured isotropic BRDFs
+
+use crate::hitable::HitRecord;
+use crate::material::{shading_frame, Material};
+use crate::random::random_f64;
+use crate::ray::Ray;
+use crate::vector::{Color, Vec3};
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Read};
+
+// The resolution MERL measured at, in half angle, difference angle and
+// difference azimuth.
+const THETA_H_RES: usize = 90;
+const THETA_D_RES: usize = 90;
+const PHI_D_RES: usize = 180;
+const SAMPLES: usize = THETA_H_RES * THETA_D_RES * PHI_D_RES;
+// The values are stored scaled per channel.
+const SCALE: [f64; 3] = [1.0 / 1500.0, 1.15 / 1500.0, 1.66 / 1500.0];
+
+// The tables for importance sampling. For each of THETA_O_BINS angles to the
+// viewer, incoming directions are binned by angle to the normal and azimuth
+// relative to the viewer.
+const THETA_O_BINS: usize = 32;
+const THETA_BINS: usize = 32;
+const PHI_BINS: usize = 64;
+// How much of the sampling is cosine weighted, so that parts of the BRDF the
+// coarse tables miss can still be found.
+const COSINE_FRACTION: f64 = 0.1;
+
+fn invalid(msg: &str) -> Error {
+	Error::new(ErrorKind::InvalidData, msg)
+}
+
+// A BRDF measured by Matusik et al. 2003, from one of MERL's .binary files.
+// It's looked up by Rusinkiewicz's half and difference angles, and sampled
+// from tables of where it reflects the most light.
+pub struct MerlBrdf {
+	data: Vec<f64>,
+	// For each viewing angle bin, the chance of each incoming direction cell
+	// and their running total.
+	pmf: Vec<f64>,
+	cdf: Vec<f64>,
+	albedo: Color,
+}
+
+impl MerlBrdf {
+	pub fn read_file(fname: &str) -> Result<Self, Error> {
+		let mut rd = BufReader::new(File::open(fname)?);
+		let mut header = [0u8; 12];
+		rd.read_exact(&mut header)?;
+		let dims: Vec<i32> = header.chunks(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect();
+		if dims != [THETA_H_RES as i32, THETA_D_RES as i32, PHI_D_RES as i32] {
+			return Err(invalid("not a MERL BRDF"));
+		}
+		let mut bytes = Vec::new();
+		rd.read_to_end(&mut bytes)?;
+		if bytes.len() != SAMPLES * 3 * 8 {
+			return Err(invalid("truncated MERL BRDF"));
+		}
+		let data = bytes.chunks(8).map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])).collect();
+		Ok(Self::new(data))
+	}
+
+	// data is the three channels one after the other, as in the file.
+	pub fn new(data: Vec<f64>) -> Self {
+		let mut brdf = Self {
+			data,
+			pmf: Vec::new(),
+			cdf: Vec::new(),
+			albedo: Color::new(0.0, 0.0, 0.0),
+		};
+		brdf.build_tables();
+		brdf
+	}
+
+	// The BRDF for two directions in the shading frame.
+	pub fn value(&self, wo: &Vec3, wi: &Vec3) -> Color {
+		let h = (*wo + wi).unit();
+		let theta_h = h.z().clamp(-1.0, 1.0).acos();
+		let phi_h = h.y().atan2(h.x());
+		// The incoming direction, seen from the half vector.
+		let d = rotate_y(&rotate_z(wi, -phi_h), -theta_h);
+		let theta_d = d.z().clamp(-1.0, 1.0).acos();
+		let phi_d = d.y().atan2(d.x());
+		let index = phi_d_index(phi_d) + theta_d_index(theta_d) * PHI_D_RES + theta_h_index(theta_h) * PHI_D_RES * THETA_D_RES;
+		// Unmeasured values are negative.
+		Color::new((self.data[index] * SCALE[0]).max(0.0), (self.data[index + SAMPLES] * SCALE[1]).max(0.0), (self.data[index + 2 * SAMPLES] * SCALE[2]).max(0.0))
+	}
+
+	fn build_tables(&mut self) {
+		let cells = THETA_BINS * PHI_BINS;
+		let (d_theta, d_phi) = (0.5 * PI / THETA_BINS as f64, 2.0 * PI / PHI_BINS as f64);
+		let mut pmf = vec![0.0; THETA_O_BINS * cells];
+		let mut cdf = vec![0.0; THETA_O_BINS * cells];
+		for k in 0..THETA_O_BINS {
+			let theta_o = (k as f64 + 0.5) * 0.5 * PI / THETA_O_BINS as f64;
+			let wo = Vec3::new(theta_o.sin(), 0.0, theta_o.cos());
+			let row = &mut pmf[k * cells..(k + 1) * cells];
+			let mut reflected = Color::new(0.0, 0.0, 0.0);
+			for i in 0..THETA_BINS {
+				let theta = (i as f64 + 0.5) * d_theta;
+				for j in 0..PHI_BINS {
+					let phi = (j as f64 + 0.5) * d_phi;
+					let wi = Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos());
+					// The light reflected from this cell, cos theta times its
+					// solid angle.
+					let f = self.value(&wo, &wi) * (theta.cos() * theta.sin() * d_theta * d_phi);
+					row[i * PHI_BINS + j] = f.luminance().max(0.0);
+					reflected += &f;
+				}
+			}
+			if k == 0 {
+				self.albedo = Color::new(reflected.r().min(1.0), reflected.g().min(1.0), reflected.b().min(1.0));
+			}
+			let total: f64 = row.iter().sum();
+			for i in 0..THETA_BINS {
+				let theta = (i as f64 + 0.5) * d_theta;
+				let cosine = theta.cos() * theta.sin() * d_theta * d_phi / PI;
+				for j in 0..PHI_BINS {
+					let p = &mut row[i * PHI_BINS + j];
+					*p = if total > 0.0 {
+						(1.0 - COSINE_FRACTION) * *p / total + COSINE_FRACTION * cosine
+					} else {
+						cosine
+					};
+				}
+			}
+			let mut sum = 0.0;
+			for c in 0..cells {
+				sum += pmf[k * cells + c];
+				cdf[k * cells + c] = sum;
+			}
+		}
+		self.pmf = pmf;
+		self.cdf = cdf;
+	}
+
+	fn theta_o_bin(wo: &Vec3) -> usize {
+		let theta_o = wo.z().clamp(0.0, 1.0).acos();
+		((theta_o / (0.5 * PI) * THETA_O_BINS as f64) as usize).min(THETA_O_BINS - 1)
+	}
+
+	fn sample_local(&self, wo: &Vec3) -> Vec3 {
+		let cells = THETA_BINS * PHI_BINS;
+		let k = Self::theta_o_bin(wo);
+		let cdf = &self.cdf[k * cells..(k + 1) * cells];
+		let u = random_f64() * cdf[cells - 1];
+		let cell = cdf.partition_point(|&c| c <= u).min(cells - 1);
+		let (d_theta, d_phi) = (0.5 * PI / THETA_BINS as f64, 2.0 * PI / PHI_BINS as f64);
+		let theta = ((cell / PHI_BINS) as f64 + random_f64()) * d_theta;
+		let phi = ((cell % PHI_BINS) as f64 + random_f64()) * d_phi + wo.y().atan2(wo.x());
+		Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+	}
+
+	// Cells are sampled uniformly in theta and phi, so the density over solid
+	// angle falls off with sin theta.
+	fn pdf_local(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+		if wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return 0.0;
+		}
+		let (d_theta, d_phi) = (0.5 * PI / THETA_BINS as f64, 2.0 * PI / PHI_BINS as f64);
+		let theta = wi.z().min(1.0).acos();
+		let sin_theta = theta.sin();
+		if sin_theta <= 0.0 {
+			return 0.0;
+		}
+		let phi = (wi.y().atan2(wi.x()) - wo.y().atan2(wo.x())).rem_euclid(2.0 * PI);
+		let i = ((theta / d_theta) as usize).min(THETA_BINS - 1);
+		let j = ((phi / d_phi) as usize).min(PHI_BINS - 1);
+		let k = Self::theta_o_bin(wo);
+		self.pmf[(k * THETA_BINS + i) * PHI_BINS + j] / (d_theta * d_phi * sin_theta)
+	}
+}
+
+impl Material for MerlBrdf {
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		if wo.z() <= 0.0 {
+			return None;
+		}
+		let wi = self.sample_local(&wo);
+		let pdf = self.pdf_local(&wo, &wi);
+		if pdf <= 0.0 {
+			return None;
+		}
+		let attenuation = self.value(&wo, &wi) * (wi.z() / pdf);
+		Some((attenuation, Ray::new(*rec.point(), frame.to_world(&wi), ray.time())))
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		let frame = shading_frame(rec);
+		self.pdf_local(&frame.to_local(&-ray.direction().unit()), &frame.to_local(&direction.unit()))
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		let wi = frame.to_local(&direction.unit());
+		if wo.z() <= 0.0 || wi.z() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		self.value(&wo, &wi) * wi.z()
+	}
+
+	fn has_pdf(&self, _rec: &HitRecord) -> bool {
+		true
+	}
+
+	// How much it reflects when lit from straight above.
+	fn albedo(&self, _u: f64, _v: f64, _point: &Vec3) -> Color {
+		self.albedo
+	}
+}
+
+fn rotate_z(v: &Vec3, angle: f64) -> Vec3 {
+	let (s, c) = angle.sin_cos();
+	Vec3::new(v.x() * c - v.y() * s, v.x() * s + v.y() * c, v.z())
+}
+
+fn rotate_y(v: &Vec3, angle: f64) -> Vec3 {
+	let (s, c) = angle.sin_cos();
+	Vec3::new(v.x() * c + v.z() * s, v.y(), v.z() * c - v.x() * s)
+}
+
+// The half angle is stored more densely near the normal, where highlights are.
+fn theta_h_index(theta_h: f64) -> usize {
+	if theta_h <= 0.0 {
+		return 0;
+	}
+	let index = (theta_h / (0.5 * PI) * THETA_H_RES as f64 * THETA_H_RES as f64).sqrt();
+	(index as usize).min(THETA_H_RES - 1)
+}
+
+fn theta_d_index(theta_d: f64) -> usize {
+	((theta_d / (0.5 * PI) * THETA_D_RES as f64).max(0.0) as usize).min(THETA_D_RES - 1)
+}
+
+// Isotropy makes phi_d and phi_d + pi the same, so only half is stored.
+fn phi_d_index(phi_d: f64) -> usize {
+	let phi_d = if phi_d < 0.0 {
+		phi_d + PI
+	} else {
+		phi_d
+	};
+	((phi_d / PI * PHI_D_RES as f64).max(0.0) as usize).min(PHI_D_RES - 1)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::tests::{assert_sampling_matches_pdf, hit, reflected_energy};
+	use crate::random;
+	use std::sync::Arc;
+
+	// A table filled in by the half angle, difference angle and difference
+	// azimuth each entry stands for.
+	fn table(f: impl Fn(f64, f64, f64) -> f64) -> Vec<f64> {
+		let mut data = vec![0.0; 3 * SAMPLES];
+		for h in 0..THETA_H_RES {
+			let theta_h = ((h as f64 + 0.5) / THETA_H_RES as f64).powi(2) * 0.5 * PI;
+			for d in 0..THETA_D_RES {
+				let theta_d = (d as f64 + 0.5) / THETA_D_RES as f64 * 0.5 * PI;
+				for p in 0..PHI_D_RES {
+					let phi_d = (p as f64 + 0.5) / PHI_D_RES as f64 * PI;
+					let index = p + d * PHI_D_RES + h * PHI_D_RES * THETA_D_RES;
+					let value = f(theta_h, theta_d, phi_d);
+					for (c, scale) in SCALE.iter().enumerate() {
+						data[index + c * SAMPLES] = value / scale;
+					}
+				}
+			}
+		}
+		data
+	}
+
+	fn direction(theta: f64, phi: f64) -> Vec3 {
+		Vec3::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos())
+	}
+
+	// Rodrigues' rotation, as rotate_vector in MERL's BRDFRead.cpp.
+	fn rotate_vector(v: &Vec3, axis: &Vec3, angle: f64) -> Vec3 {
+		let (s, c) = angle.sin_cos();
+		*v * c + &(*axis * (axis.dot(v) * (1.0 - c))) + &(axis.cross(v) * s)
+	}
+
+	// The index lookup_brdf_val in BRDFRead.cpp reads, ported as it is, with
+	// its full 360 step phi_d resolution.
+	fn reference_index(theta_in: f64, fi_in: f64, theta_out: f64, fi_out: f64) -> usize {
+		let half = (direction(theta_in, fi_in) + &direction(theta_out, fi_out)).unit();
+		let theta_half = half.z().acos();
+		let fi_half = half.y().atan2(half.x());
+		let temp = rotate_vector(&direction(theta_in, fi_in), &Vec3::new(0.0, 0.0, 1.0), -fi_half);
+		let diff = rotate_vector(&temp, &Vec3::new(0.0, 1.0, 0.0), -theta_half);
+		let theta_diff = diff.z().acos();
+		let mut fi_diff = diff.y().atan2(diff.x());
+
+		let theta_half_index = if theta_half <= 0.0 {
+			0
+		} else {
+			((theta_half / (PI / 2.0) * 90.0 * 90.0).sqrt() as i64).clamp(0, 89) as usize
+		};
+		let theta_diff_index = ((theta_diff / (PI * 0.5) * 90.0) as i64).clamp(0, 89) as usize;
+		if fi_diff < 0.0 {
+			fi_diff += PI;
+		}
+		let phi_diff_index = ((fi_diff / PI * 360.0 / 2.0) as i64).clamp(0, 179) as usize;
+		phi_diff_index + theta_diff_index * 180 + theta_half_index * 180 * 90
+	}
+
+	#[test]
+	fn looks_up_the_reference_index() {
+		// Every entry holds its own index, offset by channel, so a lookup
+		// gives back where it read from.
+		let mut data = vec![0.0; 3 * SAMPLES];
+		for (i, value) in data.iter_mut().enumerate() {
+			*value = i as f64;
+		}
+		let brdf = MerlBrdf::new(data);
+		random::seed(3);
+		for _ in 0..20_000 {
+			let (theta_in, fi_in) = (random_f64() * 0.5 * PI, random_f64() * 2.0 * PI);
+			let (theta_out, fi_out) = (random_f64() * 0.5 * PI, random_f64() * 2.0 * PI);
+			let expected = reference_index(theta_in, fi_in, theta_out, fi_out);
+			let value = brdf.value(&direction(theta_out, fi_out), &direction(theta_in, fi_in));
+			assert_eq!((value.r() / SCALE[0]).round() as usize, expected);
+			assert_eq!((value.g() / SCALE[1]).round() as usize, expected + SAMPLES);
+			assert_eq!((value.b() / SCALE[2]).round() as usize, expected + 2 * SAMPLES);
+		}
+	}
+
+	#[test]
+	fn samples_its_pdf() {
+		let glossy = table(|theta_h, _, _| 0.3 / PI + 2.0 * (-(theta_h / 0.2).powi(2)).exp());
+		let brdf: Arc<dyn Material + Send + Sync> = Arc::new(MerlBrdf::new(glossy));
+		for incoming in [Vec3::new(0.0, 0.1, -1.0), Vec3::new(1.0, 0.3, -0.8), Vec3::new(-1.0, 0.0, -0.2)] {
+			let (ray, rec) = hit(brdf.clone(), incoming, true);
+			assert_sampling_matches_pdf(brdf.as_ref(), &ray, &rec, 200_000);
+		}
+	}
+
+	#[test]
+	fn white_diffuse_reflects_everything() {
+		let brdf: Arc<dyn Material + Send + Sync> = Arc::new(MerlBrdf::new(table(|_, _, _| 1.0 / PI)));
+		for incoming in [Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -0.1)] {
+			let (ray, rec) = hit(brdf.clone(), incoming, true);
+			let energy = reflected_energy(brdf.as_ref(), &ray, &rec, 50_000);
+			assert!(energy <= 1.01 && energy > 0.98, "reflects {}", energy);
+		}
+	}
+}
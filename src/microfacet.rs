@@ -0,0 +1,433 @@
+// microfacet.rs
+// GGX (Trowbridge-Reitz) microfacet distribution and Fresnel terms
+
+use crate::random::random_f64;
+use crate::spectrum::reflectance_to_rgb;
+use crate::vector::{Color, Vec3};
+use std::f64::consts::PI;
+use std::ops::{Add, Div, Mul, Sub};
+
+// Below this, a surface is treated as perfectly smooth.
+pub const MIN_ALPHA: f64 = 1.0e-3;
+
+// A shading frame, with the normal along z.
+pub struct Frame {
+	t: Vec3,
+	b: Vec3,
+	n: Vec3,
+}
+
+impl Frame {
+	pub fn new(normal: &Vec3) -> Self {
+		let n = normal.unit();
+		let (t, b) = n.basis();
+		Self {
+			t,
+			b,
+			n,
+		}
+	}
+
+	// A frame with x along the tangent, or as close to it as the normal
+	// allows. Any tangent will do if there isn't one.
+	pub fn from_tangent(normal: &Vec3, tangent: &Vec3) -> Self {
+		let n = normal.unit();
+		let t = *tangent - &(n * n.dot(tangent));
+		if t.len2() < 1.0e-12 {
+			return Self::new(normal);
+		}
+		let t = t.unit();
+		let b = n.cross(&t);
+		Self {
+			t,
+			b,
+			n,
+		}
+	}
+
+	pub fn to_local(&self, v: &Vec3) -> Vec3 {
+		Vec3::new(v.dot(&self.t), v.dot(&self.b), v.dot(&self.n))
+	}
+
+	pub fn to_world(&self, v: &Vec3) -> Vec3 {
+		self.t * v.x() + &(self.b * v.y()) + &(self.n * v.z())
+	}
+}
+
+// Roughness is perceptual, alpha is roughness squared.
+pub fn roughness_to_alpha(roughness: f64) -> f64 {
+	(roughness * roughness).max(MIN_ALPHA)
+}
+
+// The GGX distribution of microfacet normals. Directions are in a local
+// frame with the macro surface normal along z. Anisotropic surfaces are
+// rougher along x than y, or the other way around.
+#[derive(Copy, Clone)]
+pub struct Ggx {
+	alpha_x: f64,
+	alpha_y: f64,
+}
+
+impl Ggx {
+	pub fn new(alpha: f64) -> Self {
+		Self::new_anisotropic(alpha, alpha)
+	}
+
+	pub fn new_anisotropic(alpha_x: f64, alpha_y: f64) -> Self {
+		Self {
+			alpha_x: alpha_x.max(MIN_ALPHA),
+			alpha_y: alpha_y.max(MIN_ALPHA),
+		}
+	}
+
+	// Close enough to a mirror that sampling the distribution is pointless.
+	pub fn is_smooth(&self) -> bool {
+		self.alpha_x.max(self.alpha_y) <= MIN_ALPHA
+	}
+
+	// The density of microfacets facing h, per unit projected area.
+	pub fn d(&self, h: &Vec3) -> f64 {
+		if h.z() <= 0.0 {
+			return 0.0;
+		}
+		let x = h.x() / self.alpha_x;
+		let y = h.y() / self.alpha_y;
+		let e = x * x + y * y + h.z() * h.z();
+		1.0 / (PI * self.alpha_x * self.alpha_y * e * e)
+	}
+
+	// Smith's auxiliary function.
+	pub fn lambda(&self, w: &Vec3) -> f64 {
+		let cos2 = w.z() * w.z();
+		if cos2 <= 0.0 {
+			return f64::INFINITY;
+		}
+		let x = self.alpha_x * w.x();
+		let y = self.alpha_y * w.y();
+		((1.0 + (x * x + y * y) / cos2).sqrt() - 1.0) / 2.0
+	}
+
+	// The fraction of microfacets visible from w.
+	pub fn g1(&self, w: &Vec3) -> f64 {
+		1.0 / (1.0 + self.lambda(w))
+	}
+
+	// The fraction visible from both directions, height correlated.
+	pub fn g(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+		1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+	}
+
+	// The distribution of normals visible from wo.
+	pub fn d_visible(&self, wo: &Vec3, h: &Vec3) -> f64 {
+		if wo.z() == 0.0 {
+			return 0.0;
+		}
+		self.g1(wo) * wo.dot(h).max(0.0) * self.d(h) / wo.z().abs()
+	}
+
+	// A microfacet normal visible from wo (Heitz 2018). wo has to be above
+	// the surface.
+	pub fn sample_visible(&self, wo: &Vec3) -> Vec3 {
+		// Stretch the view into the configuration of a unit hemisphere.
+		let vh = Vec3::new(self.alpha_x * wo.x(), self.alpha_y * wo.y(), wo.z()).unit();
+		let lensq = vh.x() * vh.x() + vh.y() * vh.y();
+		let t1 = if lensq > 0.0 {
+			Vec3::new(-vh.y(), vh.x(), 0.0) / lensq.sqrt()
+		} else {
+			Vec3::new(1.0, 0.0, 0.0)
+		};
+		let t2 = vh.cross(&t1);
+
+		// A point on the projected disk, squashed toward the visible half.
+		let r = random_f64().sqrt();
+		let phi = 2.0 * PI * random_f64();
+		let p1 = r * phi.cos();
+		let s = 0.5 * (1.0 + vh.z());
+		let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+		let nh = t1 * p1 + &(t2 * p2) + &(vh * (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt());
+
+		// And back out of it.
+		Vec3::new(self.alpha_x * nh.x(), self.alpha_y * nh.y(), nh.z().max(1.0e-6)).unit()
+	}
+}
+
+// Reflectance of a dielectric boundary, where eta is the index of refraction
+// on the far side over the one on the near side, for unpolarized light. The
+// cosine is negative from the far side.
+pub fn fresnel_dielectric(cos_theta: f64, eta: f64) -> f64 {
+	let (cos_i, eta) = if cos_theta < 0.0 {
+		(-cos_theta, 1.0 / eta)
+	} else {
+		(cos_theta, eta)
+	};
+	let cos_i = cos_i.min(1.0);
+	let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+	if sin2_t >= 1.0 {
+		// Total internal reflection.
+		return 1.0;
+	}
+	let cos_t = (1.0 - sin2_t).sqrt();
+	let r_parallel = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+	let r_perpendicular = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+	0.5 * (r_parallel * r_parallel + r_perpendicular * r_perpendicular)
+}
+
+// The direction wo refracts into through a surface with normal n, on the same
+// side as wo, or None for total internal reflection.
+pub fn refract(wo: &Vec3, n: &Vec3, eta: f64) -> Option<Vec3> {
+	let cos_i = wo.dot(n);
+	let sin2_t = (1.0 - cos_i * cos_i).max(0.0) / (eta * eta);
+	if sin2_t >= 1.0 {
+		return None;
+	}
+	let cos_t = (1.0 - sin2_t).sqrt();
+	Some(-*wo / eta + &(*n * (cos_i / eta - cos_t)))
+}
+
+// The microfacet normal that turns wo into wi (Walter et al. 2007), by
+// reflection when they are on the same side and refraction otherwise. eta is
+// the index of refraction below the surface over the one above it. None when
+// no microfacet facing wo can do it.
+pub fn dielectric_half(wo: &Vec3, wi: &Vec3, eta: f64) -> Option<Vec3> {
+	let reflect = wi.z() > 0.0;
+	let h = if reflect {
+		*wo + wi
+	} else {
+		*wi * eta + wo
+	};
+	if h.len2() == 0.0 {
+		return None;
+	}
+	let h = if h.z() < 0.0 {
+		-h.unit()
+	} else {
+		h.unit()
+	};
+	if wo.dot(&h) <= 0.0 || (!reflect && wi.dot(&h) >= 0.0) {
+		return None;
+	}
+	Some(h)
+}
+
+// The change of variables from the microfacet normal h to the direction wo
+// refracts into through it.
+pub fn refraction_jacobian(wo: &Vec3, wi: &Vec3, h: &Vec3, eta: f64) -> f64 {
+	let denom = wi.dot(h) + wo.dot(h) / eta;
+	wi.dot(h).abs() / (denom * denom)
+}
+
+// The GTR1 distribution Disney uses for clearcoat, which has a longer tail
+// than GGX.
+pub fn gtr1_d(h: &Vec3, alpha: f64) -> f64 {
+	if h.z() <= 0.0 {
+		return 0.0;
+	}
+	let a2 = alpha * alpha;
+	let t = 1.0 + (a2 - 1.0) * h.z() * h.z();
+	(a2 - 1.0) / (PI * a2.ln() * t)
+}
+
+pub fn gtr1_sample(alpha: f64) -> Vec3 {
+	let a2 = alpha * alpha;
+	let cos_theta = ((1.0 - a2.powf(1.0 - random_f64())) / (1.0 - a2)).max(0.0).sqrt();
+	let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+	let phi = 2.0 * PI * random_f64();
+	Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+// Estevez and Kulla's "Charlie" sheen distribution, for the fibers of cloth,
+// which mostly stand up from the surface.
+pub fn charlie_d(h: &Vec3, alpha: f64) -> f64 {
+	let inv_alpha = 1.0 / alpha;
+	let sin_theta = (1.0 - h.z() * h.z()).max(0.0).sqrt();
+	(2.0 + inv_alpha) * sin_theta.powf(inv_alpha) / (2.0 * PI)
+}
+
+// Neubelt and Pettineo's visibility term for sheen, which replaces both the
+// shadowing and the 4 cos cos denominator.
+pub fn neubelt_visibility(cos_o: f64, cos_i: f64) -> f64 {
+	1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o))
+}
+
+// Schlick's approximation of the Fresnel weight, (1 - cos)^5.
+pub fn schlick_weight(cos_theta: f64) -> f64 {
+	let m = (1.0 - cos_theta).clamp(0.0, 1.0);
+	let m2 = m * m;
+	m2 * m2 * m
+}
+
+// Reflectance of a conductor with complex index of refraction eta + ik, per
+// color channel, for unpolarized light.
+pub fn fresnel_conductor(cos_theta: f64, eta: &Color, k: &Color) -> Color {
+	let mut f = Color::new(0.0, 0.0, 0.0);
+	for c in 0..3 {
+		f[c] = fresnel_conductor_channel(cos_theta, eta[c], k[c]);
+	}
+	f
+}
+
+fn fresnel_conductor_channel(cos_theta: f64, eta: f64, k: f64) -> f64 {
+	let cos_theta = cos_theta.clamp(0.0, 1.0);
+	let cos2 = cos_theta * cos_theta;
+	let sin2 = 1.0 - cos2;
+	let eta2 = eta * eta;
+	let k2 = k * k;
+
+	let t0 = eta2 - k2 - sin2;
+	let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+	let t1 = a2_plus_b2 + cos2;
+	let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+	let t2 = 2.0 * cos_theta * a;
+	let rs = (t1 - t2) / (t1 + t2);
+
+	let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+	let t4 = t2 * sin2;
+	let rp = rs * (t3 - t4) / (t3 + t4);
+	0.5 * (rp + rs)
+}
+
+// A thin transparent film on a surface, like a soap bubble, an oil slick or
+// an anti-reflective coating. Light reflected off its top and bottom
+// interferes, so some colors are reflected more than others. thickness is in
+// nanometers.
+#[derive(Copy, Clone, Debug)]
+pub struct ThinFilm {
+	thickness: f64,
+	ior: f64,
+}
+
+impl ThinFilm {
+	pub fn new(thickness: f64, ior: f64) -> Self {
+		Self {
+			thickness,
+			ior,
+		}
+	}
+
+	// Reflectance of a film between two dielectrics, with indices n1 on the
+	// side the light comes from and n3 on the other.
+	pub fn reflectance_dielectric(&self, cos_theta: f64, n1: f64, n3: f64) -> Color {
+		reflectance_to_rgb(|lambda| self.airy(cos_theta, n1, Complex::new(n3, 0.0), lambda))
+	}
+
+	// Reflectance of a film on a conductor, seen from the air. Like
+	// fresnel_conductor, eta and k are at 650, 550 and 450 nm, and everything
+	// in between is interpolated.
+	pub fn reflectance_conductor(&self, cos_theta: f64, eta: &Color, k: &Color) -> Color {
+		reflectance_to_rgb(|lambda| {
+			let n3 = Complex::new(interpolate_rgb(eta, lambda), interpolate_rgb(k, lambda));
+			self.airy(cos_theta, 1.0, n3, lambda)
+		})
+	}
+
+	// The Airy sum of every reflection inside the film, for unpolarized light.
+	fn airy(&self, cos_theta: f64, n1: f64, n3: Complex, lambda: f64) -> f64 {
+		let cos1 = Complex::new(cos_theta.clamp(0.0, 1.0), 0.0);
+		let sin2_1 = 1.0 - cos_theta * cos_theta;
+		let n1 = Complex::new(n1, 0.0);
+		let n2 = Complex::new(self.ior, 0.0);
+		// Snell's law, where the angles may be complex inside a conductor or
+		// past the critical angle.
+		let cos2 = (Complex::new(1.0, 0.0) - n1 * n1 * sin2_1 / (n2 * n2)).sqrt();
+		let cos3 = (Complex::new(1.0, 0.0) - n1 * n1 * sin2_1 / (n3 * n3)).sqrt();
+
+		let r12_s = (n1 * cos1 - n2 * cos2) / (n1 * cos1 + n2 * cos2);
+		let r12_p = (n2 * cos1 - n1 * cos2) / (n2 * cos1 + n1 * cos2);
+		let r23_s = (n2 * cos2 - n3 * cos3) / (n2 * cos2 + n3 * cos3);
+		let r23_p = (n3 * cos2 - n2 * cos3) / (n3 * cos2 + n2 * cos3);
+
+		// The extra distance light travels through the film and back.
+		let delta = n2 * cos2 * (4.0 * PI * self.thickness / lambda);
+		let phase = (Complex::new(0.0, 1.0) * delta).exp();
+		let r_s = (r12_s + r23_s * phase) / (Complex::new(1.0, 0.0) + r12_s * r23_s * phase);
+		let r_p = (r12_p + r23_p * phase) / (Complex::new(1.0, 0.0) + r12_p * r23_p * phase);
+		(0.5 * (r_s.norm2() + r_p.norm2())).min(1.0)
+	}
+}
+
+// A value given at 650, 550 and 450 nm, at any wavelength.
+fn interpolate_rgb(c: &Color, lambda: f64) -> f64 {
+	if lambda >= 650.0 {
+		c.r()
+	} else if lambda >= 550.0 {
+		let t = (lambda - 550.0) / 100.0;
+		c.g() + (c.r() - c.g()) * t
+	} else if lambda >= 450.0 {
+		let t = (lambda - 450.0) / 100.0;
+		c.b() + (c.g() - c.b()) * t
+	} else {
+		c.b()
+	}
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Complex {
+	re: f64,
+	im: f64,
+}
+
+impl Complex {
+	fn new(re: f64, im: f64) -> Self {
+		Self {
+			re,
+			im,
+		}
+	}
+
+	fn norm2(&self) -> f64 {
+		self.re * self.re + self.im * self.im
+	}
+
+	// The principal square root.
+	fn sqrt(self) -> Self {
+		let r = self.norm2().sqrt();
+		let re = (0.5 * (r + self.re)).max(0.0).sqrt();
+		let im = (0.5 * (r - self.re)).max(0.0).sqrt();
+		Self::new(re, if self.im < 0.0 {
+			-im
+		} else {
+			im
+		})
+	}
+
+	fn exp(self) -> Self {
+		let m = self.re.exp();
+		Self::new(m * self.im.cos(), m * self.im.sin())
+	}
+}
+
+impl Add for Complex {
+	type Output = Self;
+	fn add(self, o: Self) -> Self {
+		Self::new(self.re + o.re, self.im + o.im)
+	}
+}
+
+impl Sub for Complex {
+	type Output = Self;
+	fn sub(self, o: Self) -> Self {
+		Self::new(self.re - o.re, self.im - o.im)
+	}
+}
+
+impl Mul for Complex {
+	type Output = Self;
+	fn mul(self, o: Self) -> Self {
+		Self::new(self.re * o.re - self.im * o.im, self.re * o.im + self.im * o.re)
+	}
+}
+
+impl Mul<f64> for Complex {
+	type Output = Self;
+	fn mul(self, s: f64) -> Self {
+		Self::new(self.re * s, self.im * s)
+	}
+}
+
+impl Div for Complex {
+	type Output = Self;
+	fn div(self, o: Self) -> Self {
+		let d = o.norm2();
+		Self::new((self.re * o.re + self.im * o.im) / d, (self.im * o.re - self.re * o.im) / d)
+	}
+}
@@ -0,0 +1,472 @@
+// nodes.rs
+// Texture nodes that combine other textures
+
+use crate::texture::{AlphaTexture, CheckeredTexture, ImageTexture, NoiseTexture, SolidColor, Texture};
+use crate::vector::{Color, Vec3};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+// Every node is a texture whose inputs are textures, so they plug into each
+// other and into any material. Scalar inputs, like a mix factor, read the
+// first channel. Constants are SolidColors.
+
+// Blends from a to b as factor goes from 0 to 1.
+pub struct Mix {
+	a: Arc<dyn Texture + Send + Sync>,
+	b: Arc<dyn Texture + Send + Sync>,
+	factor: Arc<dyn Texture + Send + Sync>,
+}
+
+impl Mix {
+	pub fn new(a: Arc<dyn Texture + Send + Sync>, b: Arc<dyn Texture + Send + Sync>, factor: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			a,
+			b,
+			factor,
+		}
+	}
+}
+
+impl Texture for Mix {
+	fn value(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		let t = self.factor.value(u, v, point).r().clamp(0.0, 1.0);
+		self.a.value(u, v, point) * (1.0 - t) + &(self.b.value(u, v, point) * t)
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum MathOp {
+	Add,
+	Subtract,
+	Multiply,
+	// Dividing by zero gives zero.
+	Divide,
+	Minimum,
+	Maximum,
+	Power,
+}
+
+impl MathOp {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name {
+			"add" => Some(MathOp::Add),
+			"subtract" => Some(MathOp::Subtract),
+			"multiply" => Some(MathOp::Multiply),
+			"divide" => Some(MathOp::Divide),
+			"minimum" => Some(MathOp::Minimum),
+			"maximum" => Some(MathOp::Maximum),
+			"power" => Some(MathOp::Power),
+			_ => None,
+		}
+	}
+
+	fn apply(self, a: f64, b: f64) -> f64 {
+		match self {
+			MathOp::Add => a + b,
+			MathOp::Subtract => a - b,
+			MathOp::Multiply => a * b,
+			MathOp::Divide => {
+				if b != 0.0 {
+					a / b
+				} else {
+					0.0
+				}
+			}
+			MathOp::Minimum => a.min(b),
+			MathOp::Maximum => a.max(b),
+			MathOp::Power => a.max(0.0).powf(b),
+		}
+	}
+}
+
+// a op b, one channel at a time.
+pub struct Math {
+	op: MathOp,
+	a: Arc<dyn Texture + Send + Sync>,
+	b: Arc<dyn Texture + Send + Sync>,
+}
+
+impl Math {
+	pub fn new(op: MathOp, a: Arc<dyn Texture + Send + Sync>, b: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			op,
+			a,
+			b,
+		}
+	}
+}
+
+impl Texture for Math {
+	fn value(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		let a = self.a.value(u, v, point);
+		let b = self.b.value(u, v, point);
+		Color::new(self.op.apply(a.r(), b.r()), self.op.apply(a.g(), b.g()), self.op.apply(a.b(), b.b()))
+	}
+}
+
+// Maps each channel from one range to another, such as noise from -1..1 to
+// 0..1. Values outside the range are extrapolated unless clamped.
+pub struct Remap {
+	input: Arc<dyn Texture + Send + Sync>,
+	from: (f64, f64),
+	to: (f64, f64),
+	clamp: bool,
+}
+
+impl Remap {
+	pub fn new(input: Arc<dyn Texture + Send + Sync>, from_min: f64, from_max: f64, to_min: f64, to_max: f64) -> Self {
+		Self {
+			input,
+			from: (from_min, from_max),
+			to: (to_min, to_max),
+			clamp: false,
+		}
+	}
+
+	pub fn clamped(mut self) -> Self {
+		self.clamp = true;
+		self
+	}
+
+	fn remap(&self, x: f64) -> f64 {
+		let span = self.from.1 - self.from.0;
+		let mut t = if span != 0.0 {
+			(x - self.from.0) / span
+		} else {
+			0.0
+		};
+		if self.clamp {
+			t = t.clamp(0.0, 1.0);
+		}
+		self.to.0 + t * (self.to.1 - self.to.0)
+	}
+}
+
+impl Texture for Remap {
+	fn value(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		let c = self.input.value(u, v, point);
+		Color::new(self.remap(c.r()), self.remap(c.g()), self.remap(c.b()))
+	}
+}
+
+// Colors the first channel of its input by interpolating between stops,
+// given as positions and colors. Before the first stop and after the last,
+// the ramp holds their colors.
+pub struct ColorRamp {
+	input: Arc<dyn Texture + Send + Sync>,
+	stops: Vec<(f64, Color)>,
+}
+
+impl ColorRamp {
+	pub fn new(input: Arc<dyn Texture + Send + Sync>, mut stops: Vec<(f64, Color)>) -> Self {
+		stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+		Self {
+			input,
+			stops,
+		}
+	}
+}
+
+impl Texture for ColorRamp {
+	fn value(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		let (first, last) = match (self.stops.first(), self.stops.last()) {
+			(Some(first), Some(last)) => (first, last),
+			_ => return Color::new(0.0, 0.0, 0.0),
+		};
+		let t = self.input.value(u, v, point).r();
+		if t <= first.0 {
+			return first.1;
+		}
+		if t >= last.0 {
+			return last.1;
+		}
+		let next = self.stops.partition_point(|stop| stop.0 <= t);
+		let (p0, c0) = self.stops[next - 1];
+		let (p1, c1) = self.stops[next];
+		let s = if p1 > p0 {
+			(t - p0) / (p1 - p0)
+		} else {
+			0.0
+		};
+		c0 * (1.0 - s) + &(c1 * s)
+	}
+}
+
+// Looks up its input at transformed texture coordinates. The coordinates are
+// scaled, then rotated about the origin, then offset, and optionally wrapped
+// back into 0..1 so that images tile.
+pub struct UvTransform {
+	input: Arc<dyn Texture + Send + Sync>,
+	scale: (f64, f64),
+	rotation: f64,
+	offset: (f64, f64),
+	repeat: bool,
+}
+
+impl UvTransform {
+	pub fn new(input: Arc<dyn Texture + Send + Sync>) -> Self {
+		Self {
+			input,
+			scale: (1.0, 1.0),
+			rotation: 0.0,
+			offset: (0.0, 0.0),
+			repeat: false,
+		}
+	}
+
+	pub fn with_scale(mut self, u: f64, v: f64) -> Self {
+		self.scale = (u, v);
+		self
+	}
+
+	// In degrees, counterclockwise.
+	pub fn with_rotation(mut self, degrees: f64) -> Self {
+		self.rotation = degrees.to_radians();
+		self
+	}
+
+	pub fn with_offset(mut self, u: f64, v: f64) -> Self {
+		self.offset = (u, v);
+		self
+	}
+
+	pub fn repeat(mut self) -> Self {
+		self.repeat = true;
+		self
+	}
+}
+
+impl Texture for UvTransform {
+	fn value(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		let (su, sv) = (u * self.scale.0, v * self.scale.1);
+		let (sin, cos) = self.rotation.sin_cos();
+		let mut tu = su * cos - sv * sin + self.offset.0;
+		let mut tv = su * sin + sv * cos + self.offset.1;
+		if self.repeat {
+			tu = tu.rem_euclid(1.0);
+			tv = tv.rem_euclid(1.0);
+		}
+		self.input.value(tu, tv, point)
+	}
+}
+
+// Looks up its input at a scaled and offset point, for textures of the point
+// like noise. A larger scale makes smaller features.
+pub struct PointTransform {
+	input: Arc<dyn Texture + Send + Sync>,
+	scale: f64,
+	offset: Vec3,
+}
+
+impl PointTransform {
+	pub fn new(input: Arc<dyn Texture + Send + Sync>, scale: f64, offset: Vec3) -> Self {
+		Self {
+			input,
+			scale,
+			offset,
+		}
+	}
+}
+
+impl Texture for PointTransform {
+	fn value(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.input.value(u, v, &(*point * self.scale + &self.offset))
+	}
+}
+
+// GRAPH FILES
+
+// A texture graph written out one node per line, as "name = kind inputs...",
+// for instance:
+//
+//   # Blotches of rust over paint.
+//   paint = color 0.1 0.3 0.6
+//   rust = color 0.45 0.2 0.05
+//   noise = noise
+//   fine = point noise 4 0 0 0
+//   amount = remap fine -1 1 0 1 clamped
+//   out = mix paint rust amount
+//
+// An input is the name of an earlier node, or a number for a gray constant.
+// The last node is the texture. The kinds are:
+//
+//   color r g b
+//   checker odd even
+//   noise
+//   image file.png
+//   alpha file.png
+//   mix a b factor
+//   math add|subtract|multiply|divide|minimum|maximum|power a b
+//   remap input from_min from_max to_min to_max [clamped]
+//   ramp input position r g b [position r g b ...]
+//   uv input [scale u v] [rotate degrees] [offset u v] [repeat]
+//   point input scale x y z
+//
+// Anything after a # is a comment.
+pub fn read_graph(fname: &str) -> Result<Arc<dyn Texture + Send + Sync>, Error> {
+	parse_graph(&read_to_string(fname)?)
+}
+
+pub fn parse_graph(text: &str) -> Result<Arc<dyn Texture + Send + Sync>, Error> {
+	let mut nodes: HashMap<&str, Arc<dyn Texture + Send + Sync>> = HashMap::new();
+	let mut last = None;
+	for (number, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap_or("").trim();
+		if line.is_empty() {
+			continue;
+		}
+		let fail = |msg: String| Error::new(ErrorKind::InvalidData, format!("line {}: {}", number + 1, msg));
+		let (name, node) = match line.split_once('=') {
+			Some((name, node)) if !name.trim().is_empty() => (name.trim(), node),
+			_ => return Err(fail(String::from("expected name = node"))),
+		};
+		let texture = parse_node(&nodes, &node.split_whitespace().collect::<Vec<&str>>()).map_err(fail)?;
+		nodes.insert(name, texture.clone());
+		last = Some(texture);
+	}
+	last.ok_or_else(|| Error::new(ErrorKind::InvalidData, "the graph has no nodes"))
+}
+
+fn parse_node(nodes: &HashMap<&str, Arc<dyn Texture + Send + Sync>>, words: &[&str]) -> Result<Arc<dyn Texture + Send + Sync>, String> {
+	let input = |word: &str| -> Result<Arc<dyn Texture + Send + Sync>, String> {
+		if let Ok(value) = word.parse::<f64>() {
+			return Ok(Arc::new(SolidColor::from_rgb(value, value, value)));
+		}
+		nodes.get(word).cloned().ok_or_else(|| format!("no node named '{}'", word))
+	};
+	let (kind, args) = match words.split_first() {
+		Some((kind, args)) => (*kind, args),
+		None => return Err(String::from("missing the node kind")),
+	};
+	let count = |n: usize| {
+		if args.len() == n {
+			Ok(())
+		} else {
+			Err(format!("{} takes {} inputs, not {}", kind, n, args.len()))
+		}
+	};
+	let texture: Arc<dyn Texture + Send + Sync> = match kind {
+		"color" => {
+			count(3)?;
+			Arc::new(SolidColor::from_rgb(number(args[0])?, number(args[1])?, number(args[2])?))
+		}
+		"checker" => {
+			count(2)?;
+			Arc::new(CheckeredTexture::new(input(args[0])?, input(args[1])?))
+		}
+		"noise" => {
+			count(0)?;
+			Arc::new(NoiseTexture::new())
+		}
+		"image" | "alpha" => {
+			count(1)?;
+			let image = Arc::new(ImageTexture::open(args[0]).map_err(|e| format!("can't read image '{}': {}", args[0], e))?);
+			if kind == "alpha" {
+				Arc::new(AlphaTexture::new(image))
+			} else {
+				image
+			}
+		}
+		"mix" => {
+			count(3)?;
+			Arc::new(Mix::new(input(args[0])?, input(args[1])?, input(args[2])?))
+		}
+		"math" => {
+			count(3)?;
+			let op = MathOp::from_name(args[0]).ok_or_else(|| format!("unknown math operation '{}'", args[0]))?;
+			Arc::new(Math::new(op, input(args[1])?, input(args[2])?))
+		}
+		"remap" => {
+			let clamped = args.len() == 6 && args[5] == "clamped";
+			if !clamped {
+				count(5)?;
+			}
+			let remap = Remap::new(input(args[0])?, number(args[1])?, number(args[2])?, number(args[3])?, number(args[4])?);
+			if clamped {
+				Arc::new(remap.clamped())
+			} else {
+				Arc::new(remap)
+			}
+		}
+		"ramp" => {
+			if args.len() < 5 || (args.len() - 1) % 4 != 0 {
+				return Err(String::from("ramp takes an input and stops of position r g b"));
+			}
+			let mut stops = Vec::new();
+			for stop in args[1..].chunks(4) {
+				stops.push((number(stop[0])?, Color::new(number(stop[1])?, number(stop[2])?, number(stop[3])?)));
+			}
+			Arc::new(ColorRamp::new(input(args[0])?, stops))
+		}
+		"uv" => {
+			let (first, mut rest) = match args.split_first() {
+				Some(split) => split,
+				None => return Err(String::from("uv takes an input")),
+			};
+			let mut transform = UvTransform::new(input(first)?);
+			while let Some((option, more)) = rest.split_first() {
+				let values = match *option {
+					"scale" | "offset" => 2,
+					"rotate" => 1,
+					"repeat" => 0,
+					_ => return Err(format!("unknown uv option '{}'", option)),
+				};
+				if more.len() < values {
+					return Err(format!("{} takes {} values", option, values));
+				}
+				transform = match *option {
+					"scale" => transform.with_scale(number(more[0])?, number(more[1])?),
+					"offset" => transform.with_offset(number(more[0])?, number(more[1])?),
+					"rotate" => transform.with_rotation(number(more[0])?),
+					_ => transform.repeat(),
+				};
+				rest = &more[values..];
+			}
+			Arc::new(transform)
+		}
+		"point" => {
+			count(5)?;
+			Arc::new(PointTransform::new(input(args[0])?, number(args[1])?, Vec3::new(number(args[2])?, number(args[3])?, number(args[4])?)))
+		}
+		_ => return Err(format!("unknown node kind '{}'", kind)),
+	};
+	Ok(texture)
+}
+
+fn number(word: &str) -> Result<f64, String> {
+	word.parse::<f64>().map_err(|_| format!("'{}' is not a number", word))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn builds_a_graph() {
+		let texture = parse_graph("# comment\nred = color 1 0 0\nhalf = math multiply red 0.5\nout = remap half 0 1 0.2 0.4 clamped  # trailing\n").unwrap();
+		let color = texture.value(0.0, 0.0, &Vec3::new(0.0, 0.0, 0.0));
+		assert!((color.x() - 0.3).abs() < 1e-12);
+		assert!((color.y() - 0.2).abs() < 1e-12);
+		assert!((color.z() - 0.2).abs() < 1e-12);
+	}
+
+	#[test]
+	fn reports_the_bad_line() {
+		let error = parse_graph("a = color 1 1 1\nb = mix a c 0.5\n").err().unwrap();
+		assert_eq!(error.to_string(), "line 2: no node named 'c'");
+		assert!(parse_graph("a = wobble").is_err());
+		assert!(parse_graph("a = uv 0.5 scale 2").is_err());
+		assert!(parse_graph("# nothing\n").is_err());
+	}
+
+	#[test]
+	fn reports_bad_images() {
+		let error = parse_graph("a = color 1 1 1\nb = image no-such.png\n").err().unwrap();
+		assert!(error.to_string().starts_with("line 2: can't read image 'no-such.png': "), "{}", error);
+		let error = parse_graph("a = alpha Cargo.toml\n").err().unwrap();
+		assert_eq!(error.kind(), ErrorKind::InvalidData);
+		assert!(error.to_string().starts_with("line 1: can't read image 'Cargo.toml': "), "{}", error);
+	}
+}
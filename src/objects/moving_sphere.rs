@@ -10,6 +10,7 @@ use crate::{
 	ray::Ray,
 	vector::Vec3,
 };
+use std::sync::Arc;
 use std::ops::{Add, Sub};
 
 pub struct MovingSphere {
@@ -18,11 +19,11 @@ pub struct MovingSphere {
 	time0: f64,
 	time1: f64,
 	radius: f64,
-	material: Material,
+	material: Arc<dyn Material + Send + Sync>,
 }
 
 impl MovingSphere {
-	pub fn new(center0: Vec3, center1: Vec3, time0: f64, time1: f64, radius: f64, material: Material) -> Self {
+	pub fn new(center0: Vec3, center1: Vec3, time0: f64, time1: f64, radius: f64, material: Arc<dyn Material + Send + Sync>) -> Self {
 		Self {
 			center0,
 			center1,
@@ -46,6 +47,17 @@ impl MovingSphere {
 		let v = theta / pi;
 		(u, v)
 	}
+
+	// How a point moves with get_uv()'s u (around the sphere) and v (from
+	// the bottom to the top).
+	fn derivatives(&self, point: &Vec3) -> (Vec3, Vec3) {
+		let pi = std::f64::consts::PI;
+		let phi = (-point.z()).atan2(point.x());
+		let (cos_theta, sin_theta) = (-point.y(), (1.0 - point.y() * point.y()).max(0.0).sqrt());
+		let dpdu = Vec3::new(point.z(), 0.0, -point.x()) * (2.0 * pi * self.radius);
+		let dpdv = Vec3::new(cos_theta * phi.cos(), sin_theta, -cos_theta * phi.sin()) * (pi * self.radius);
+		(dpdu, dpdv)
+	}
 }
 
 impl Hitable for MovingSphere {
@@ -61,24 +73,35 @@ impl Hitable for MovingSphere {
 		}
 		let sqrtd = discriminant.sqrt();
 
-		// Find the nearest root that lies in the acceptable range.
-		let root = (-half_b - sqrtd) / a;
-		if root < t_min || t_max < root {
-			let root = (-half_b + sqrtd) / a;
+		// Find the nearest root that lies in the acceptable range, and that
+		// the material doesn't let the ray through at.
+		for root in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
 			if root < t_min || t_max < root {
-				return None;
+				continue;
 			}
-		}
 
-		let t = root;
-		let point = ray.at(t);
-		let outward_normal = (point - &self.center(ray.time())) / self.radius;
-		let front_face = ray.direction().dot(&outward_normal) < 0.0;
-		let material = self.material.clone();
-		let (u, v) = self.get_uv(&outward_normal);
-		let rec = HitRecord::new(point, outward_normal, t, front_face, material, u, v);
+			let t = root;
+			let point = ray.at(t);
+			let outward_normal = (point - &self.center(ray.time())) / self.radius;
+			let front_face = ray.direction().dot(&outward_normal) < 0.0;
+			let (u, v) = self.get_uv(&outward_normal);
+			if self.material.passes_through(u, v, &point) {
+				continue;
+			}
+			let material = self.material.clone();
+			let normal = if front_face {
+				outward_normal
+			} else {
+				-outward_normal
+			};
+			let mut rec = HitRecord::new(point, normal, t, front_face, material, u, v);
+			let (dpdu, dpdv) = self.derivatives(&outward_normal);
+			rec.set_derivatives(dpdu, dpdv);
+			rec.set_object(self);
 
-		Some(rec)
+			return Some(rec);
+		}
+		None
 	}
 
 	fn bounding_box(&self, time0: f64, time1: f64) -> Option<AxisAlignedBoundingBox> {
@@ -102,3 +125,21 @@ impl Hitable for MovingSphere {
 		self.center1[2] += z;
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::Lambertian;
+	use crate::texture::SolidColor;
+
+	#[test]
+	fn faces_against_rays_from_inside() {
+		let material = Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(0.5, 0.5, 0.5))));
+		let sphere = MovingSphere::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0, 1.0, 2.0, material);
+		let ray = Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.5);
+		let rec = sphere.hit(&ray, 0.001, f64::INFINITY).unwrap();
+		assert!(!rec.front_face());
+		assert!(rec.normal().dot(ray.direction()) < 0.0);
+		assert!(rec.geometric_normal().dot(ray.direction()) < 0.0);
+	}
+}
@@ -1,9 +1,12 @@
 use crate::bounding_box::AxisAlignedBoundingBox;
 use crate::hitable::{HitRecord, Hitable};
-use crate::material::Material;
+use crate::light_sampler::LightBounds;
+use crate::material::{Lambertian, Material};
+use crate::random::{random_f64, random_unit_vector};
 use crate::ray::Ray;
 use crate::texture::SolidColor;
 use crate::vector::Vec3;
+use std::f64::consts::PI;
 use std::ops::{Add, Neg, Sub};
 use std::sync::Arc;
 
@@ -11,12 +14,12 @@ use std::sync::Arc;
 pub struct Sphere {
 	center: Vec3,
 	radius: f64,
-	material: Material,
+	material: Arc<dyn Material + Send + Sync>,
 }
 
 impl Default for Sphere {
 	fn default() -> Self {
-		Self::new(Vec3::new(0.0, 0.0, 0.0), 1.0, Material::new_lambertian(Arc::new(SolidColor::from_rgb(1.0, 1.0, 1.0))))
+		Self::new(Vec3::new(0.0, 0.0, 0.0), 1.0, Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(1.0, 1.0, 1.0)))))
 	}
 }
 
@@ -24,7 +27,7 @@ unsafe impl Send for Sphere {}
 unsafe impl Sync for Sphere {}
 
 impl Sphere {
-	pub fn new(center: Vec3, radius: f64, material: Material) -> Self {
+	pub fn new(center: Vec3, radius: f64, material: Arc<dyn Material + Send + Sync>) -> Self {
 		Self {
 			center,
 			radius,
@@ -57,10 +60,21 @@ impl Sphere {
 		let v = theta / pi;
 		(u, v)
 	}
-}
 
-impl Hitable for Sphere {
-	fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+	// How a point moves with get_uv()'s u (around the sphere) and v (from
+	// the bottom to the top).
+	fn derivatives(&self, point: &Vec3) -> (Vec3, Vec3) {
+		let pi = std::f64::consts::PI;
+		let phi = point.z().neg().atan2(point.x());
+		let (cos_theta, sin_theta) = (point.y().neg(), (1.0 - point.y() * point.y()).max(0.0).sqrt());
+		let dpdu = Vec3::new(point.z(), 0.0, point.x().neg()) * (2.0 * pi * self.radius);
+		let dpdv = Vec3::new(cos_theta * phi.cos(), sin_theta, -cos_theta * phi.sin()) * (pi * self.radius);
+		(dpdu, dpdv)
+	}
+
+	// The nearest hit, where cutouts says whether to skip the places the
+	// material lets rays through.
+	fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64, cutouts: bool) -> Option<HitRecord> {
 		let oc = ray.origin().sub(self.center());
 		let a = ray.direction().len2();
 		let half_b = oc.dot(ray.direction());
@@ -72,25 +86,42 @@ impl Hitable for Sphere {
 		}
 		let sqrtd = discriminant.sqrt();
 
-		// Find the nearest root that lies in the acceptable range.
-		let root = (-half_b - sqrtd) / a;
-		if root < t_min || root > t_max {
-			let root = (-half_b + sqrtd) / a;
+		// Find the nearest root that lies in the acceptable range, and that
+		// the material doesn't let the ray through at unless cutouts are off.
+		for root in [(-half_b - sqrtd) / a, (-half_b + sqrtd) / a] {
 			if root < t_min || root > t_max {
-				return None;
+				continue;
+			}
+			let t = root;
+			let point = ray.at(t);
+			let outward_normal = (point - self.center()) / self.radius;
+			let front_face = ray.direction().dot(&outward_normal) < 0.0;
+			let normal = if front_face {
+				outward_normal
+			} else {
+				-outward_normal
+			};
+			let (u, v) = self.get_uv(&outward_normal);
+			if cutouts && self.material.passes_through(u, v, &point) {
+				continue;
 			}
+			let mut rec = HitRecord::new(point, normal, t, front_face, self.material.clone(), u, v);
+			let (dpdu, dpdv) = self.derivatives(&outward_normal);
+			rec.set_derivatives(dpdu, dpdv);
+			rec.set_object(self);
+			return Some(rec);
 		}
-		let t = root;
-		let point = ray.at(t);
-		let outward_normal = (point - self.center()) / self.radius;
-		let front_face = ray.direction().dot(&outward_normal) < 0.0;
-		let normal = if front_face {
-			outward_normal
-		} else {
-			-outward_normal
-		};
-		let (u, v) = self.get_uv(&normal);
-		Some(HitRecord::new(point, normal, t, front_face, self.material.clone(), u, v))
+		None
+	}
+}
+
+impl Hitable for Sphere {
+	fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+		self.intersect(ray, t_min, t_max, true)
+	}
+
+	fn hit_surface(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+		self.intersect(ray, t_min, t_max, false)
 	}
 
 	fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AxisAlignedBoundingBox> {
@@ -106,4 +137,44 @@ impl Hitable for Sphere {
 		self.center[1] += y;
 		self.center[2] += z;
 	}
+
+	// A sphere shines equally in every direction, with the intensity of a disk.
+	fn light_bounds(&self) -> Option<LightBounds> {
+		if !self.material.is_emissive() {
+			return None;
+		}
+		let radiance = self.material.emitted(0.5, 0.5, &self.center).luminance();
+		let bbox = self.bounding_box(0.0, 0.0)?;
+		Some(LightBounds::new(bbox, Vec3::new(0.0, 0.0, 1.0), radiance * PI * self.radius * self.radius, -1.0, 0.0, false))
+	}
+
+	// Directions are picked uniformly within the cone the sphere covers.
+	fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+		if self.hit_surface(&Ray::new(*origin, *direction, 0.0), 0.001, f64::INFINITY).is_none() {
+			return 0.0;
+		}
+		let dist2 = (self.center - origin).len2();
+		let r2 = self.radius * self.radius;
+		if dist2 <= r2 {
+			return 1.0 / (4.0 * PI);
+		}
+		let cos_theta_max = (1.0 - r2 / dist2).sqrt();
+		1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+	}
+
+	fn random(&self, origin: &Vec3) -> Vec3 {
+		let direction = self.center - origin;
+		let dist2 = direction.len2();
+		let r2 = self.radius * self.radius;
+		// From the inside, the sphere is everywhere.
+		if dist2 <= r2 {
+			return random_unit_vector();
+		}
+		let z = 1.0 + random_f64() * ((1.0 - r2 / dist2).sqrt() - 1.0);
+		let phi = 2.0 * PI * random_f64();
+		let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+		let w = direction.unit();
+		let (u, v) = w.basis();
+		u * (phi.cos() * sin_theta) + &(v * (phi.sin() * sin_theta)) + &(w * z)
+	}
 }
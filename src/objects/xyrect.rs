@@ -4,9 +4,12 @@ use crate::material::Material;
 use crate::hitable::{Hitable, HitRecord};
 use crate::bounding_box::AxisAlignedBoundingBox;
 use crate::ray::Ray;
+use crate::light_sampler::LightBounds;
+use crate::random::random_double;
+use std::sync::Arc;
 
 pub struct XyRect {
-    material: Material,
+    material: Arc<dyn Material + Send + Sync>,
     x0: f64,
     x1: f64,
     y0: f64,
@@ -15,7 +18,7 @@ pub struct XyRect {
 }
 
 impl XyRect {
-    pub fn new(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, material: Material) -> Self {
+    pub fn new(x0: f64, x1: f64, y0: f64, y1: f64, k: f64, material: Arc<dyn Material + Send + Sync>) -> Self {
         Self {
             material,
             x0,
@@ -25,10 +28,14 @@ impl XyRect {
             k
         }
     }
-}
 
-impl Hitable for XyRect {
-	fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    fn area(&self) -> f64 {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+
+	// The hit, where cutouts says whether to skip the places the material
+	// lets rays through.
+	fn intersect(&self, r: &Ray, t_min: f64, t_max: f64, cutouts: bool) -> Option<HitRecord> {
         let t = (self.k - r.origin().z()) / r.direction().z();
         if t < t_min || t > t_max {
             return None;
@@ -49,7 +56,23 @@ impl Hitable for XyRect {
 		};
         
         let p = r.at(t);
-        Some(HitRecord::new(p, normal, t, front_face, self.material.clone(), u, v))
+        if cutouts && self.material.passes_through(u, v, &p) {
+            return None;
+        }
+        let mut rec = HitRecord::new(p, normal, t, front_face, self.material.clone(), u, v);
+        rec.set_derivatives(Vec3::new(self.x1 - self.x0, 0.0, 0.0), Vec3::new(0.0, self.y1 - self.y0, 0.0));
+        rec.set_object(self);
+        Some(rec)
+	}
+}
+
+impl Hitable for XyRect {
+	fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+		self.intersect(r, t_min, t_max, true)
+	}
+
+	fn hit_surface(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+		self.intersect(r, t_min, t_max, false)
 	}
 
 	fn bounding_box(&self, _time0: f64, _time1: f64) -> Option<AxisAlignedBoundingBox> {
@@ -67,4 +90,33 @@ impl Hitable for XyRect {
         self.y1 += y;
         self.k += z;
 	}
+
+	// Lights out of the front face, and the back one too unless its material
+	// is one-sided, with the most intensity straight out.
+	fn light_bounds(&self) -> Option<LightBounds> {
+		if !self.material.is_emissive() {
+			return None;
+		}
+		let center = Vec3::new(0.5 * (self.x0 + self.x1), 0.5 * (self.y0 + self.y1), self.k);
+		let radiance = self.material.emitted(0.5, 0.5, &center).luminance();
+		let bbox = self.bounding_box(0.0, 0.0)?;
+		Some(LightBounds::new(bbox, Vec3::new(0.0, 0.0, 1.0), radiance * self.area(), 1.0, 0.0, self.material.is_two_sided()))
+	}
+
+	// Points are picked uniformly over the area, and converted to solid angle.
+	fn pdf_value(&self, origin: &Vec3, direction: &Vec3) -> f64 {
+		if let Some(rec) = self.hit_surface(&Ray::new(*origin, *direction, 0.0), 0.001, f64::INFINITY) {
+			let dist2 = rec.t() * rec.t() * direction.len2();
+			let cosine = (direction.z() / direction.len()).abs();
+			if cosine > 0.0 {
+				return dist2 / (cosine * self.area());
+			}
+		}
+		0.0
+	}
+
+	fn random(&self, origin: &Vec3) -> Vec3 {
+		let point = Vec3::new(random_double(self.x0, self.x1), random_double(self.y0, self.y1), self.k);
+		point - origin
+	}
 }
@@ -49,10 +49,10 @@ impl Perlin {
 		let k = p.z().floor() as usize;
 		let mut c = [[[0.0; 2]; 2]; 2];
 
-		for di in 0..2 {
-			for dj in 0..2 {
-				for dk in 0..2 {
-					c[di][dj][dk] = self.ranfloat[self.perm[0][(i + di) & 255] ^ self.perm[1][(j + dj) & 255] ^ self.perm[2][(k + dk) & 255]];
+		for (di, plane) in c.iter_mut().enumerate() {
+			for (dj, row) in plane.iter_mut().enumerate() {
+				for (dk, corner) in row.iter_mut().enumerate() {
+					*corner = self.ranfloat[self.perm[0][(i + di) & 255] ^ self.perm[1][(j + dj) & 255] ^ self.perm[2][(k + dk) & 255]];
 				}
 			}
 		}
@@ -80,13 +80,13 @@ impl Perlin {
 
 	fn trilinear_interpolation(perm: [[[f64; 2]; 2]; 2], u: f64, v: f64, w: f64) -> f64 {
 		let mut accum = 0.0;
-		for i in 0usize..2 {
-			for j in 0usize..2 {
-				for k in 0usize..2 {
+		for (i, plane) in perm.iter().enumerate() {
+			for (j, row) in plane.iter().enumerate() {
+				for (k, corner) in row.iter().enumerate() {
 					let a = i as f64;
 					let b = j as f64;
 					let c = k as f64;
-					accum += (a * u + (1.0 - a) * (1.0 - u)) * (b * v + (1.0 - b) * (1.0 - v)) * (c * w + (1.0 - c) * (1.0 - w)) * perm[i][j][k];
+					accum += (a * u + (1.0 - a) * (1.0 - u)) * (b * v + (1.0 - b) * (1.0 - v)) * (c * w + (1.0 - c) * (1.0 - w)) * corner;
 				}
 			}
 		}
@@ -3,19 +3,23 @@
 // Stephen Marz
 // 9 Dec 2020
 
+use crate::checkpoint::{read_f64, read_vec3, write_f64, write_vec3};
+use crate::denoise::{Denoiser, GuideBuffer};
 use crate::vector::Color;
 use std::fs::File;
-use std::io::{BufWriter, Error, Write};
+use std::io::{BufWriter, Error, Read, Write};
 use std::vec::Vec;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct Row {
 	cols: Vec<Color>,
+	weights: Vec<f64>,
 }
 
 impl Row {
 	pub fn resize_width(&mut self, new_width: usize) {
 		self.cols.resize_with(new_width, Default::default);
+		self.weights.resize_with(new_width, Default::default);
 	}
 }
 
@@ -29,17 +33,19 @@ fn clamp(val: f64, min: f64, max: f64) -> f64 {
 	}
 }
 
+// The picture accumulates filter-weighted samples. Each pixel keeps the sum
+// of weighted colors and the sum of weights, and the pixel's color is the
+// ratio of the two.
+#[derive(Clone)]
 pub struct PngPicture {
-	samples: u32,
 	width: u32,
 	height: u32,
 	rows: Vec<Row>,
 }
 
 impl PngPicture {
-	pub fn new(width: u32, height: u32, samples: u32) -> Self {
+	pub fn new(width: u32, height: u32) -> Self {
 		let mut r = Self {
-			samples,
 			width,
 			height,
 			rows: Vec::new(),
@@ -52,8 +58,6 @@ impl PngPicture {
 		let wd = BufWriter::new(File::create(fname)?);
 		let mut bytes_written = 0;
 
-		let scale = 1.0 / self.samples as f64;
-
 		let mut encoder = png::Encoder::new(wd, self.width, self.height);
 		encoder.set_color(png::ColorType::RGB);
 		encoder.set_depth(png::BitDepth::Eight);
@@ -63,12 +67,9 @@ impl PngPicture {
 		for row in (0..self.height).rev() {
 			for col in 0..self.width {
 				let px = self.get_pixel(col, row);
-				let pxr = scale * px.r();
-				let pxg = scale * px.g();
-				let pxb = scale * px.b();
-				let r = (255.0 * clamp(pxr, 0.0, 1.0)) as u8;
-				let g = (255.0 * clamp(pxg, 0.0, 1.0)) as u8;
-				let b = (255.0 * clamp(pxb, 0.0, 1.0)) as u8;
+				let r = (255.0 * clamp(px.r(), 0.0, 1.0)) as u8;
+				let g = (255.0 * clamp(px.g(), 0.0, 1.0)) as u8;
+				let b = (255.0 * clamp(px.b(), 0.0, 1.0)) as u8;
 				let data = [r, g, b];
 				bytes_written += swriter.write(&data).unwrap();
 			}
@@ -76,15 +77,79 @@ impl PngPicture {
 		Ok(bytes_written)
 	}
 
+	// Run the denoiser over the reconstructed pixels. This has to happen
+	// before write_file, which is where the colors get clamped.
+	pub fn denoise(&mut self, denoiser: &Denoiser, guides: &GuideBuffer) {
+		let mut color = Vec::with_capacity((self.width * self.height) as usize);
+		for row in 0..self.height {
+			for col in 0..self.width {
+				color.push(self.get_pixel(col, row));
+			}
+		}
+		denoiser.denoise(&mut color, guides);
+		for (i, px) in color.iter().enumerate() {
+			let x = i as u32 % self.width;
+			let y = i as u32 / self.width;
+			self.set_pixel(x, y, px);
+		}
+	}
+
+	// Save the raw accumulated samples and weights, not the reconstructed colors.
+	pub fn write_checkpoint<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+		for row in self.rows.iter() {
+			for (col, weight) in row.cols.iter().zip(row.weights.iter()) {
+				write_vec3(w, col)?;
+				write_f64(w, *weight)?;
+			}
+		}
+		Ok(())
+	}
+
+	pub fn read_checkpoint<R: Read>(r: &mut R, width: u32, height: u32) -> Result<Self, Error> {
+		let mut picture = Self::new(width, height);
+		for row in picture.rows.iter_mut() {
+			for (col, weight) in row.cols.iter_mut().zip(row.weights.iter_mut()) {
+				*col = read_vec3(r)?;
+				*weight = read_f64(r)?;
+			}
+		}
+		Ok(picture)
+	}
+
+	// Add all of the samples of another picture (such as one progressive
+	// pass) into this one. Both pictures must be the same size.
+	pub fn merge(&mut self, other: &PngPicture) {
+		for (row, other_row) in self.rows.iter_mut().zip(other.rows.iter()) {
+			for (col, other_col) in row.cols.iter_mut().zip(other_row.cols.iter()) {
+				*col += other_col;
+			}
+			for (weight, other_weight) in row.weights.iter_mut().zip(other_row.weights.iter()) {
+				*weight += other_weight;
+			}
+		}
+	}
+
+	// Add a sample (already multiplied by its filter weight) to a pixel.
+	pub fn add_sample(&mut self, x: u32, y: u32, weighted: &Color, weight: f64) {
+		let row = &mut self.rows[y as usize];
+		row.cols[x as usize] += weighted;
+		row.weights[x as usize] += weight;
+	}
+
 	pub fn get_pixel(&self, x: u32, y: u32) -> Color {
-		let p = &self.rows[y as usize].cols[x as usize];
-		Color::new(p[0], p[1], p[2])
+		let row = &self.rows[y as usize];
+		let weight = row.weights[x as usize];
+		// Pixels that received no (or only negative lobe) samples stay black.
+		if weight <= 0.0 {
+			Color::default()
+		} else {
+			row.cols[x as usize] / weight
+		}
 	}
 	pub fn set_pixel(&mut self, x: u32, y: u32, pixel: &Color) {
-		let p = &mut self.rows[y as usize].cols[x as usize];
-		p[0] = pixel[0];
-		p[1] = pixel[1];
-		p[2] = pixel[2];
+		let row = &mut self.rows[y as usize];
+		row.cols[x as usize] = *pixel;
+		row.weights[x as usize] = 1.0;
 	}
 	pub fn get_width(&self) -> u32 {
 		self.width
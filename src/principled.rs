@@ -0,0 +1,418 @@
+// principled.rs
+// Disney principled BSDF
+
+use crate::hitable::HitRecord;
+use crate::material::{shading_frame, Material};
+use crate::microfacet::{dielectric_half, fresnel_dielectric, gtr1_d, gtr1_sample, refract, refraction_jacobian, schlick_weight, Ggx, MIN_ALPHA};
+use crate::random::{random_f64, random_unit_vector};
+use crate::ray::Ray;
+use crate::texture::{SolidColor, Texture};
+use crate::vector::{Color, Vec3};
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+// One material for almost everything, after Burley's 2012 and 2015 Disney
+// BRDF/BSDF. Every parameter is a texture, scalar ones read their first
+// channel and are between 0 and 1.
+#[derive(Clone)]
+pub struct Principled {
+	pub base_color: Arc<dyn Texture + Send + Sync>,
+	// 0 is a dielectric, 1 a metal colored by the base color.
+	pub metallic: Arc<dyn Texture + Send + Sync>,
+	pub roughness: Arc<dyn Texture + Send + Sync>,
+	// Dielectric reflectance at normal incidence, where 0.5 is 4%, like
+	// glass or plastic. It also sets the index of refraction.
+	pub specular: Arc<dyn Texture + Send + Sync>,
+	// Tints the dielectric reflection toward the base color.
+	pub specular_tint: Arc<dyn Texture + Send + Sync>,
+	// Extra reflection at grazing angles, for cloth.
+	pub sheen: Arc<dyn Texture + Send + Sync>,
+	pub sheen_tint: Arc<dyn Texture + Send + Sync>,
+	// A second, clear specular layer on top.
+	pub clearcoat: Arc<dyn Texture + Send + Sync>,
+	pub clearcoat_gloss: Arc<dyn Texture + Send + Sync>,
+	// How much of the diffuse part is replaced by refraction, for glass.
+	pub transmission: Arc<dyn Texture + Send + Sync>,
+	// Stretches the highlight along the tangent of the shading frame.
+	pub anisotropic: Arc<dyn Texture + Send + Sync>,
+}
+
+fn constant(value: f64) -> Arc<dyn Texture + Send + Sync> {
+	Arc::new(SolidColor::from_rgb(value, value, value))
+}
+
+impl Default for Principled {
+	// Disney's defaults, a gray plastic.
+	fn default() -> Self {
+		Self {
+			base_color: Arc::new(SolidColor::from_rgb(0.8, 0.8, 0.8)),
+			metallic: constant(0.0),
+			roughness: constant(0.5),
+			specular: constant(0.5),
+			specular_tint: constant(0.0),
+			sheen: constant(0.0),
+			sheen_tint: constant(0.5),
+			clearcoat: constant(0.0),
+			clearcoat_gloss: constant(1.0),
+			transmission: constant(0.0),
+			anisotropic: constant(0.0),
+		}
+	}
+}
+
+// The parameters looked up at one point.
+struct Parameters {
+	base_color: Color,
+	metallic: f64,
+	specular: f64,
+	specular_tint: f64,
+	sheen: f64,
+	sheen_tint: f64,
+	clearcoat: f64,
+	transmission: f64,
+	roughness: f64,
+	ggx: Ggx,
+	clearcoat_alpha: f64,
+	eta: f64,
+}
+
+// How likely scatter() is to pick each lobe.
+struct LobeWeights {
+	diffuse: f64,
+	specular: f64,
+	transmission: f64,
+	clearcoat: f64,
+}
+
+impl Parameters {
+	fn tint(&self) -> Color {
+		let lum = self.base_color.luminance();
+		if lum > 0.0 {
+			self.base_color / lum
+		} else {
+			Color::new(1.0, 1.0, 1.0)
+		}
+	}
+
+	// Reflectance of the dielectric at normal incidence.
+	fn dielectric_f0(&self) -> Color {
+		lerp(&Color::new(1.0, 1.0, 1.0), &self.tint(), self.specular_tint) * (0.08 * self.specular)
+	}
+
+	// Reflectance of the specular lobe at normal incidence.
+	fn specular_f0(&self) -> Color {
+		lerp(&self.dielectric_f0(), &self.base_color, self.metallic)
+	}
+
+	// Reflectance of the specular lobe. The part of the dielectric that
+	// transmits uses the exact Fresnel term, the same one as the refraction,
+	// so that the two add up and total internal reflection isn't lost.
+	fn specular_fresnel(&self, cos_theta: f64) -> Color {
+		let white = Color::new(1.0, 1.0, 1.0);
+		let weight = schlick_weight(cos_theta);
+		let mut dielectric = lerp(&self.dielectric_f0(), &white, weight);
+		if self.transmission > 0.0 {
+			let exact = fresnel_dielectric(cos_theta, self.eta);
+			dielectric = lerp(&dielectric, &Color::new(exact, exact, exact), self.transmission);
+		}
+		lerp(&dielectric, &lerp(&self.base_color, &white, weight), self.metallic)
+	}
+
+	fn lobe_weights(&self, wo: &Vec3) -> LobeWeights {
+		let fresnel = self.specular_fresnel(wo.z());
+		let mut weights = LobeWeights {
+			diffuse: (1.0 - self.metallic) * (1.0 - self.transmission),
+			specular: fresnel.luminance().max(0.0),
+			transmission: (1.0 - self.metallic) * self.transmission,
+			clearcoat: 0.25 * self.clearcoat,
+		};
+		let total = weights.diffuse + weights.specular + weights.transmission + weights.clearcoat;
+		if total <= 0.0 {
+			weights.diffuse = 1.0;
+			return weights;
+		}
+		weights.diffuse /= total;
+		weights.specular /= total;
+		weights.transmission /= total;
+		weights.clearcoat /= total;
+		weights
+	}
+}
+
+fn lerp(a: &Color, b: &Color, t: f64) -> Color {
+	*a * (1.0 - t) + &(*b * t)
+}
+
+impl Principled {
+	fn parameters(&self, rec: &HitRecord) -> Parameters {
+		let (u, v, p) = (rec.u(), rec.v(), rec.point());
+		let scalar = |t: &Arc<dyn Texture + Send + Sync>| t.value(u, v, p).r().clamp(0.0, 1.0);
+		let roughness = scalar(&self.roughness);
+		let anisotropic = scalar(&self.anisotropic);
+		let specular = scalar(&self.specular);
+
+		let aspect = (1.0 - 0.9 * anisotropic).sqrt();
+		let alpha = (roughness * roughness).max(MIN_ALPHA);
+		// The index of refraction that reflects as much as specular says.
+		let sqrt_f0 = (0.08 * specular).sqrt().min(0.99);
+		let eta = ((1.0 + sqrt_f0) / (1.0 - sqrt_f0)).max(1.0 + 1.0e-4);
+		let eta = if rec.front_face() {
+			eta
+		} else {
+			1.0 / eta
+		};
+		Parameters {
+			base_color: self.base_color.value(u, v, p),
+			metallic: scalar(&self.metallic),
+			specular,
+			specular_tint: scalar(&self.specular_tint),
+			sheen: scalar(&self.sheen),
+			sheen_tint: scalar(&self.sheen_tint),
+			clearcoat: scalar(&self.clearcoat),
+			transmission: scalar(&self.transmission),
+			roughness,
+			ggx: Ggx::new_anisotropic(alpha / aspect, alpha * aspect),
+			clearcoat_alpha: 0.1 + (0.001 - 0.1) * scalar(&self.clearcoat_gloss),
+			eta,
+		}
+	}
+
+	fn pdf_local(&self, params: &Parameters, wo: &Vec3, wi: &Vec3) -> f64 {
+		let weights = params.lobe_weights(wo);
+		if wi.z() > 0.0 {
+			let h = (*wo + wi).unit();
+			let diffuse = wi.z() / PI;
+			let specular = params.ggx.d_visible(wo, &h) / (4.0 * wo.dot(&h));
+			let clearcoat = gtr1_d(&h, params.clearcoat_alpha) * h.z() / (4.0 * wo.dot(&h));
+			weights.diffuse * diffuse + weights.specular * specular + weights.clearcoat * clearcoat
+		} else if wi.z() < 0.0 {
+			match dielectric_half(wo, wi, params.eta) {
+				Some(h) => weights.transmission * params.ggx.d_visible(wo, &h) * refraction_jacobian(wo, wi, &h, params.eta),
+				None => 0.0,
+			}
+		} else {
+			0.0
+		}
+	}
+
+	// The BSDF times the cosine of wi.
+	fn eval_local(&self, params: &Parameters, wo: &Vec3, wi: &Vec3) -> Color {
+		let mut f = Color::new(0.0, 0.0, 0.0);
+		if wi.z() < 0.0 {
+			// Refraction, tinted by the base color on the way in and out.
+			if params.transmission > 0.0 && params.metallic < 1.0 {
+				if let Some(h) = dielectric_half(wo, wi, params.eta) {
+					let fresnel = fresnel_dielectric(wo.dot(&h), params.eta);
+					let bsdf = (1.0 - fresnel) * params.ggx.d(&h) * params.ggx.g(wo, wi) * wo.dot(&h) * refraction_jacobian(wo, wi, &h, params.eta) / wo.z();
+					let tint = Color::new(params.base_color.r().sqrt(), params.base_color.g().sqrt(), params.base_color.b().sqrt());
+					f = tint * ((1.0 - params.metallic) * params.transmission * bsdf);
+				}
+			}
+			return f;
+		}
+		if wi.z() == 0.0 {
+			return f;
+		}
+
+		let h = (*wo + wi).unit();
+		let cos_d = wi.dot(&h);
+
+		// Diffuse with retro-reflection at grazing angles, and sheen. Light the
+		// specular layer reflects at wo never reaches them. Burley's
+		// retro-reflection makes rough surfaces reflect more than they
+		// receive, so it's renormalized as in Frostbite (Lagarde and de
+		// Rousiers 2014).
+		let f0 = params.specular_f0();
+		let reflected = lerp(&f0, &Color::new(1.0, 1.0, 1.0), schlick_weight(wo.z()));
+		let below = 1.0 - reflected.r().max(reflected.g()).max(reflected.b()).min(1.0);
+		let diffuse_weight = (1.0 - params.metallic) * (1.0 - params.transmission) * below;
+		if diffuse_weight > 0.0 {
+			let fl = schlick_weight(wi.z());
+			let fv = schlick_weight(wo.z());
+			let fd90 = 0.5 * params.roughness + 2.0 * params.roughness * cos_d * cos_d;
+			let energy = 1.0 + (1.0 / 1.51 - 1.0) * params.roughness;
+			let fd = (1.0 + (fd90 - 1.0) * fl) * (1.0 + (fd90 - 1.0) * fv) * energy;
+			f += &(params.base_color * (diffuse_weight * fd * wi.z() / PI));
+		}
+		if params.sheen > 0.0 && params.metallic < 1.0 {
+			let sheen = lerp(&Color::new(1.0, 1.0, 1.0), &params.tint(), params.sheen_tint);
+			f += &(sheen * ((1.0 - params.metallic) * params.sheen * below * schlick_weight(cos_d) * wi.z()));
+		}
+
+		// Specular reflection, from the dielectric or the metal.
+		let fresnel = params.specular_fresnel(cos_d);
+		f += &(fresnel * (params.ggx.d(&h) * params.ggx.g(wo, wi) / (4.0 * wo.z())));
+
+		// Clearcoat, with a fixed index of refraction of 1.5.
+		if params.clearcoat > 0.0 {
+			let coat = Ggx::new(0.25);
+			let fresnel = 0.04 + 0.96 * schlick_weight(cos_d);
+			let value = 0.25 * params.clearcoat * gtr1_d(&h, params.clearcoat_alpha) * fresnel * coat.g1(wo) * coat.g1(wi) / (4.0 * wo.z());
+			f += &Color::new(value, value, value);
+		}
+		f
+	}
+}
+
+impl Material for Principled {
+	// Picks a lobe, samples a direction from it, and weighs it by the whole
+	// BSDF over the pdf of all of the lobes together.
+	fn scatter(&self, ray: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
+		let params = self.parameters(rec);
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		if wo.z() <= 0.0 {
+			return None;
+		}
+		let weights = params.lobe_weights(&wo);
+		let mut pick = random_f64();
+		let wi = if pick < weights.diffuse {
+			let n = Vec3::new(0.0, 0.0, 1.0);
+			let d = n + &random_unit_vector();
+			if d.near_zero() {
+				n
+			} else {
+				d.unit()
+			}
+		} else {
+			pick -= weights.diffuse;
+			let h = if pick < weights.specular + weights.transmission {
+				params.ggx.sample_visible(&wo)
+			} else {
+				gtr1_sample(params.clearcoat_alpha)
+			};
+			if wo.dot(&h) <= 0.0 {
+				return None;
+			}
+			// Directions that end up on the wrong side for their lobe are lost,
+			// the pdf doesn't count them.
+			if pick >= weights.specular && pick < weights.specular + weights.transmission {
+				let wi = refract(&wo, &h, params.eta)?;
+				if wi.z() >= 0.0 {
+					return None;
+				}
+				wi
+			} else {
+				let wi = reflect(&wo, &h);
+				if wi.z() <= 0.0 {
+					return None;
+				}
+				wi
+			}
+		};
+		let pdf = self.pdf_local(&params, &wo, &wi);
+		if pdf <= 0.0 {
+			return None;
+		}
+		let attenuation = self.eval_local(&params, &wo, &wi) / pdf;
+		Some((attenuation, Ray::new(*rec.point(), frame.to_world(&wi), ray.time())))
+	}
+
+	fn pdf(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> f64 {
+		let params = self.parameters(rec);
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		let wi = frame.to_local(&direction.unit());
+		if wo.z() <= 0.0 {
+			return 0.0;
+		}
+		self.pdf_local(&params, &wo, &wi)
+	}
+
+	fn eval(&self, ray: &Ray, rec: &HitRecord, direction: &Vec3) -> Color {
+		let params = self.parameters(rec);
+		let frame = shading_frame(rec);
+		let wo = frame.to_local(&-ray.direction().unit());
+		let wi = frame.to_local(&direction.unit());
+		if wo.z() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		self.eval_local(&params, &wo, &wi)
+	}
+
+	fn has_pdf(&self, _rec: &HitRecord) -> bool {
+		true
+	}
+
+	fn albedo(&self, u: f64, v: f64, point: &Vec3) -> Color {
+		self.base_color.value(u, v, point)
+	}
+}
+
+// wo reflected about h.
+fn reflect(wo: &Vec3, h: &Vec3) -> Vec3 {
+	*h * (2.0 * wo.dot(h)) - wo
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::material::tests::{assert_sampling_matches_pdf, hit, reflected_energy};
+
+	fn white() -> Principled {
+		Principled {
+			base_color: constant(1.0),
+			..Principled::default()
+		}
+	}
+
+	fn plastic() -> Principled {
+		Principled {
+			sheen: constant(0.5),
+			clearcoat: constant(0.5),
+			clearcoat_gloss: constant(0.5),
+			anisotropic: constant(0.5),
+			..Principled::default()
+		}
+	}
+
+	fn metal() -> Principled {
+		Principled {
+			metallic: constant(1.0),
+			roughness: constant(0.4),
+			..white()
+		}
+	}
+
+	fn glass() -> Principled {
+		Principled {
+			transmission: constant(1.0),
+			roughness: constant(0.5),
+			..white()
+		}
+	}
+
+	#[test]
+	fn samples_its_pdf() {
+		for (material, direction, front_face) in [(plastic(), Vec3::new(0.5, 0.3, -1.0), true), (metal(), Vec3::new(-1.0, 0.2, -0.7), true), (glass(), Vec3::new(0.3, 0.1, -1.0), true), (glass(), Vec3::new(0.2, 0.0, -1.0), false)] {
+			let material: Arc<dyn Material + Send + Sync> = Arc::new(material);
+			let (ray, rec) = hit(material.clone(), direction, front_face);
+			assert_sampling_matches_pdf(material.as_ref(), &ray, &rec, 200_000);
+		}
+	}
+
+	// Past the critical angle inside glass, everything the refraction can't
+	// pass is reflected.
+	#[test]
+	fn reflects_totally_inside_glass() {
+		let material: Arc<dyn Material + Send + Sync> = Arc::new(Principled {
+			roughness: constant(0.05),
+			..glass()
+		});
+		let (ray, rec) = hit(material.clone(), Vec3::new(1.0, 0.0, -0.5), false);
+		let energy = reflected_energy(material.as_ref(), &ray, &rec, 50_000);
+		assert!(energy > 0.95 && energy <= 1.0 + 1.0e-3, "reflects {}", energy);
+	}
+
+	#[test]
+	fn does_not_create_energy() {
+		for material in [white(), metal(), glass()] {
+			let material: Arc<dyn Material + Send + Sync> = Arc::new(material);
+			for direction in [Vec3::new(0.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -1.0), Vec3::new(1.0, 0.0, -0.1)] {
+				let (ray, rec) = hit(material.clone(), direction, true);
+				let energy = reflected_energy(material.as_ref(), &ray, &rec, 50_000);
+				assert!(energy <= 1.0 + 1.0e-3, "reflects {} from {}, {}, {}", energy, direction.x(), direction.y(), direction.z());
+			}
+		}
+	}
+}
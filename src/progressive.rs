@@ -0,0 +1,154 @@
+// progressive.rs
+// Progressive rendering passes and stopping criteria
+
+use crate::checkpoint::{read_f64, read_u32, write_f64, write_u32};
+use crate::png::PngPicture;
+use std::io::{Error, Read, Write};
+use std::time::Duration;
+use std::vec::Vec;
+
+// Pass size used when only a time budget or noise threshold is given.
+const DEFAULT_PASS_SAMPLES: u32 = 4;
+// Below this many passes, the variance estimate is too unreliable to stop on.
+const MIN_NOISE_PASSES: u32 = 4;
+
+// How a render is split into passes, and when to stop early. Without any
+// settings the whole sample count is rendered in a single pass. The sample
+// count given on the command line is always the upper limit.
+#[derive(Default, Clone)]
+pub struct Progressive {
+	pass_samples: Option<u32>,
+	time_budget: Option<Duration>,
+	noise_threshold: Option<f64>,
+}
+
+impl Progressive {
+	pub fn new(pass_samples: Option<u32>, time_budget: Option<Duration>, noise_threshold: Option<f64>) -> Self {
+		Self {
+			pass_samples,
+			time_budget,
+			noise_threshold,
+		}
+	}
+
+	// Make sure the render happens in passes, without changing the pass size
+	// if one was already given. Checkpoints can only be taken between passes.
+	pub fn enable(&mut self) {
+		if self.pass_samples.is_none() {
+			self.pass_samples = Some(DEFAULT_PASS_SAMPLES);
+		}
+	}
+
+	pub fn pass_samples(&self) -> Option<u32> {
+		self.pass_samples
+	}
+
+	pub fn set_pass_samples(&mut self, pass_samples: u32) {
+		self.pass_samples = Some(pass_samples.max(1));
+	}
+
+	pub fn set_time_budget(&mut self, time_budget: Duration) {
+		self.time_budget = Some(time_budget);
+	}
+
+	pub fn set_noise_threshold(&mut self, noise_threshold: f64) {
+		self.noise_threshold = Some(noise_threshold);
+	}
+
+	// Stopping early only makes sense if there is more than one pass.
+	pub fn is_progressive(&self) -> bool {
+		self.pass_samples.is_some() || self.time_budget.is_some() || self.noise_threshold.is_some()
+	}
+
+	// The number of samples per pixel to trace next, given how many are left.
+	pub fn next_pass(&self, remaining: u32) -> u32 {
+		if self.is_progressive() {
+			self.pass_samples.unwrap_or(DEFAULT_PASS_SAMPLES).min(remaining)
+		} else {
+			remaining
+		}
+	}
+
+	pub fn out_of_time(&self, elapsed: Duration) -> bool {
+		match self.time_budget {
+			Some(budget) => elapsed >= budget,
+			None => false,
+		}
+	}
+
+	pub fn converged(&self, noise: &NoiseEstimate) -> bool {
+		match (self.noise_threshold, noise.relative_error()) {
+			(Some(threshold), Some(error)) => error <= threshold,
+			_ => false,
+		}
+	}
+}
+
+// Tracks the per-pixel luminance of every pass, so the variance of the
+// pixel means can be estimated without keeping the individual samples.
+pub struct NoiseEstimate {
+	passes: u32,
+	sum: Vec<f64>,
+	sum2: Vec<f64>,
+}
+
+impl NoiseEstimate {
+	pub fn new(width: u32, height: u32) -> Self {
+		let size = (width * height) as usize;
+		Self {
+			passes: 0,
+			sum: vec![0.0; size],
+			sum2: vec![0.0; size],
+		}
+	}
+
+	pub fn add_pass(&mut self, pass: &PngPicture) {
+		let width = pass.get_width();
+		for y in 0..pass.get_height() {
+			for x in 0..width {
+				let px = pass.get_pixel(x, y);
+				let lum = 0.2126 * px.r() + 0.7152 * px.g() + 0.0722 * px.b();
+				let idx = (y * width + x) as usize;
+				self.sum[idx] += lum;
+				self.sum2[idx] += lum * lum;
+			}
+		}
+		self.passes += 1;
+	}
+
+	pub fn write_checkpoint<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+		write_u32(w, self.passes)?;
+		for (sum, sum2) in self.sum.iter().zip(self.sum2.iter()) {
+			write_f64(w, *sum)?;
+			write_f64(w, *sum2)?;
+		}
+		Ok(())
+	}
+
+	pub fn read_checkpoint<R: Read>(r: &mut R, width: u32, height: u32) -> Result<Self, Error> {
+		let mut noise = Self::new(width, height);
+		noise.passes = read_u32(r)?;
+		for (sum, sum2) in noise.sum.iter_mut().zip(noise.sum2.iter_mut()) {
+			*sum = read_f64(r)?;
+			*sum2 = read_f64(r)?;
+		}
+		Ok(noise)
+	}
+
+	// The average relative standard error of the pixels, or None until
+	// there are enough passes to say anything useful.
+	pub fn relative_error(&self) -> Option<f64> {
+		if self.passes < MIN_NOISE_PASSES || self.sum.is_empty() {
+			return None;
+		}
+		let n = self.passes as f64;
+		let mut total = 0.0;
+		for (sum, sum2) in self.sum.iter().zip(self.sum2.iter()) {
+			let mean = sum / n;
+			let variance = ((sum2 / n - mean * mean) * n / (n - 1.0)).max(0.0);
+			// The small offset keeps black pixels from dominating the average.
+			total += (variance / n).sqrt() / (mean + 0.01);
+		}
+		Some(total / self.sum.len() as f64)
+	}
+}
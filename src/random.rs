@@ -1,34 +1,63 @@
 use crate::hitable::HitList;
-use crate::material::Material;
+use crate::material::{DiElectric, Lambertian, Metal};
 use crate::objects::moving_sphere::MovingSphere;
 use crate::objects::sphere::Sphere;
 use crate::texture::{CheckeredTexture, NoiseTexture, SolidColor};
 use crate::vector::Vec3;
+use std::cell::RefCell;
 use std::sync::Arc;
 
-use rand::Rng;
+use rand::rngs::SmallRng;
+use rand::{FromEntropy, Rng, SeedableRng};
+
+// Every thread has its own generator. It starts out seeded from entropy, but
+// can be reseeded so that a render (or part of one) can be reproduced.
+thread_local! {
+	static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+pub fn seed(seed: u64) {
+	RNG.with(|r| *r.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+// A fresh seed for renders that weren't given one.
+pub fn random_seed() -> u64 {
+	rand::random::<u64>()
+}
+
+// Combine several values into one well-mixed seed (splitmix64 finalizer).
+pub fn mix_seed(values: &[u64]) -> u64 {
+	let mut h = 0x9e37_79b9_7f4a_7c15u64;
+	for v in values.iter() {
+		h ^= *v;
+		h = h.wrapping_add(0x9e37_79b9_7f4a_7c15);
+		h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+		h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+		h ^= h >> 31;
+	}
+	h
+}
 
 pub fn random_vector() -> Vec3 {
-	let mut r = rand::thread_rng();
-	let x = r.gen_range(-1.0, 1.0);
-	let y = r.gen_range(-1.0, 1.0);
-	let z = r.gen_range(-1.0, 1.0);
-	Vec3::new(x, y, z)
+	RNG.with(|r| {
+		let mut r = r.borrow_mut();
+		let x = r.gen_range(-1.0, 1.0);
+		let y = r.gen_range(-1.0, 1.0);
+		let z = r.gen_range(-1.0, 1.0);
+		Vec3::new(x, y, z)
+	})
 }
 
 pub fn random_f64() -> f64 {
-	let mut r = rand::thread_rng();
-	r.gen_range(0.0, 1.0)
+	RNG.with(|r| r.borrow_mut().gen_range(0.0, 1.0))
 }
 
 pub fn random_double(min: f64, max: f64) -> f64 {
-	let mut r = rand::thread_rng();
-	r.gen_range(min, max)
+	RNG.with(|r| r.borrow_mut().gen_range(min, max))
 }
 
 pub fn random_int(min: i32, max: i32) -> i32 {
-	let mut r = rand::thread_rng();
-	r.gen_range(min, max)
+	RNG.with(|r| r.borrow_mut().gen_range(min, max))
 }
 
 pub fn random_in_unit_sphere() -> Vec3 {
@@ -53,8 +82,8 @@ pub fn random_scene() -> HitList {
 pub fn random_perlin_scene() -> HitList {
 	let mut world = HitList::new();
 	let noise = Arc::new(NoiseTexture::new());
-	world.add(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Material::new_lambertian(noise.clone()))));
-	world.add(Arc::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, Material::new_lambertian(noise.clone()))));
+	world.add(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(noise.clone())))));
+	world.add(Arc::new(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 2.0, Arc::new(Lambertian::new(noise.clone())))));
 
 	world
 }
@@ -63,7 +92,7 @@ pub fn random_large_scene() -> HitList {
 	let mut world = HitList::new();
 
 	let checker = Arc::new(CheckeredTexture::new_color(Vec3::new(0.2, 0.3, 0.1), Vec3::new(0.9, 0.9, 0.9)));
-	world.add(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Material::new_lambertian(checker))));
+	world.add(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(checker)))));
 
 	for a in -11..11 {
 		for b in -11..11 {
@@ -74,31 +103,31 @@ pub fn random_large_scene() -> HitList {
 				if choose_mat < 0.8 {
 					// diffuse
 					let albedo = Arc::new(SolidColor::from_rgb(random_f64(), random_f64(), random_f64()));
-					let sphere_material = Material::new_lambertian(albedo);
+					let sphere_material = Arc::new(Lambertian::new(albedo));
 					let center2 = center + &Vec3::new(0.0, random_double(0.0, 0.5), 0.0);
 					world.add(Arc::new(MovingSphere::new(center, center2, 0.0, 1.0, 0.2, sphere_material)));
 				} else if choose_mat < 0.95 {
 					// metal
 					let albedo = Arc::new(SolidColor::from_rgb(random_f64(), random_f64(), random_f64()));
 					let fuzz = random_double(0.0, 0.5);
-					let sphere_material = Material::new_metal(albedo, fuzz);
+					let sphere_material = Arc::new(Metal::new(albedo, fuzz));
 					world.add(Arc::new(Sphere::new(center, 0.2, sphere_material)));
 				} else {
 					// glass
-					let sphere_material = Material::new_dielectric(1.5);
+					let sphere_material = Arc::new(DiElectric::new(1.5));
 					world.add(Arc::new(Sphere::new(center, 0.2, sphere_material)));
 				}
 			}
 		}
 	}
 
-	let material1 = Material::new_dielectric(1.5);
+	let material1 = Arc::new(DiElectric::new(1.5));
 	world.add(Arc::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, material1)));
 
-	let material2 = Material::new_lambertian(Arc::new(SolidColor::from_rgb(0.4, 0.2, 0.1)));
+	let material2 = Arc::new(Lambertian::new(Arc::new(SolidColor::from_rgb(0.4, 0.2, 0.1))));
 	world.add(Arc::new(Sphere::new(Vec3::new(-4.0, 1.0, 0.0), 1.0, material2)));
 
-	let material3 = Material::new_metal(Arc::new(SolidColor::from_rgb(0.7, 0.6, 0.5)), 0.0);
+	let material3 = Arc::new(Metal::new(Arc::new(SolidColor::from_rgb(0.7, 0.6, 0.5)), 0.0));
 	world.add(Arc::new(Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, material3)));
 
 	world
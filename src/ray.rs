@@ -1,9 +1,25 @@
-use crate::hitable::HitList;
+use crate::background::Background;
+use crate::hitable::{HitList, HitRecord};
+use crate::light::LightList;
+use crate::medium::Medium;
+use crate::random::{random_f64, random_unit_vector};
+use crate::spectrum::Wavelengths;
+use crate::stats::{count_path_segment, count_ray};
 use crate::vector::{Color, Vec3};
+use std::sync::Arc;
+
+// The most collisions a ray can have on its way through a medium.
+const MAX_WALK_STEPS: u32 = 4096;
+
 pub struct Ray {
 	origin: Vec3,
 	direction: Vec3,
 	time: f64,
+	// Set for spectral renders, where colors along the ray are radiance at
+	// these wavelengths instead of RGB.
+	wavelengths: Option<Wavelengths>,
+	// The medium the ray is travelling through, if it isn't empty space.
+	medium: Option<Arc<Medium>>,
 }
 
 impl Ray {
@@ -12,6 +28,48 @@ impl Ray {
 			origin,
 			direction,
 			time,
+			wavelengths: None,
+			medium: None,
+		}
+	}
+
+	pub fn with_wavelengths(mut self, wavelengths: Wavelengths) -> Self {
+		self.wavelengths = Some(wavelengths);
+		self
+	}
+
+	pub fn wavelengths(&self) -> Option<&Wavelengths> {
+		self.wavelengths.as_ref()
+	}
+
+	// Rays leave media through surfaces, so unlike wavelengths, scattered rays
+	// only have a medium if the surface gave them one.
+	pub fn with_medium(mut self, medium: Arc<Medium>) -> Self {
+		self.medium = Some(medium);
+		self
+	}
+
+	pub fn medium(&self) -> Option<&Arc<Medium>> {
+		self.medium.as_ref()
+	}
+
+	// Materials, lights and textures are RGB, so spectral rays upsample
+	// everything they pick up.
+	fn spectrum(&self, rgb: &Color) -> Color {
+		match self.wavelengths.as_ref() {
+			Some(wavelengths) => wavelengths.from_rgb(rgb),
+			None => *rgb,
+		}
+	}
+
+	// The scattered ray carries on with this ray's wavelengths, unless the
+	// surface dropped the secondary ones, which the attenuation has to make up
+	// for.
+	fn carry_wavelengths(&self, attenuation: Color, scattered: Ray) -> (Color, Ray) {
+		match (self.wavelengths.as_ref(), scattered.wavelengths.as_ref()) {
+			(Some(wavelengths), None) => (attenuation, scattered.with_wavelengths(*wavelengths)),
+			(Some(wavelengths), Some(next)) if next.is_terminated() && !wavelengths.is_terminated() => (attenuation * &Wavelengths::termination_weight(), scattered),
+			_ => (attenuation, scattered),
 		}
 	}
 
@@ -31,21 +89,224 @@ impl Ray {
 		self.origin + &(self.direction * t)
 	}
 
-	pub fn color(&self, background: &Color, world: &HitList, depth: i32) -> Color {
+	// The color seen along this camera ray, and the albedo, normal, and depth
+	// of the first surface it hits. Those are the guides for the denoiser, and
+	// rays that escape report all zeroes.
+	pub fn color(&self, background: &dyn Background, lights: &LightList, world: &HitList, depth: i32) -> (Color, (Color, Vec3, f64)) {
+		if depth <= 0 {
+			return (Color::new(0.0, 0.0, 0.0), (Color::default(), Vec3::default(), 0.0));
+		}
+		count_ray();
+		count_path_segment();
+		let hit = world.hit(self, 0.001, f64::INFINITY);
+		let guides = match hit.as_ref() {
+			// The camera doesn't see these surfaces at all, it looks past them.
+			Some(rec) if !rec.material().visible_to_camera() => {
+				let (color, (albedo, normal, distance)) = self.restarted(rec.point()).color(background, lights, world, depth);
+				let distance = if distance > 0.0 {
+					distance + rec.t() * self.direction().len()
+				} else {
+					0.0
+				};
+				return (color, (albedo, normal, distance));
+			}
+			Some(rec) => (rec.material().albedo(rec.u(), rec.v(), rec.point()), *rec.normal(), rec.t() * self.direction().len()),
+			None => (Color::default(), Vec3::default(), 0.0),
+		};
+		(self.follow(hit, background, lights, world, depth, None), guides)
+	}
+
+	// The same ray, starting again from a point further along it.
+	fn restarted(&self, point: &Vec3) -> Ray {
+		Self {
+			origin: *point,
+			direction: self.direction,
+			time: self.time,
+			wavelengths: self.wavelengths,
+			medium: self.medium.clone(),
+		}
+	}
+
+	fn trace(&self, background: &dyn Background, lights: &LightList, world: &HitList, depth: i32, prev: Option<(f64, Vec3)>) -> Color {
 		if depth <= 0 {
-			Color::new(0.0, 0.0, 0.0)
-        } 
-        else if let Some(rec) = world.hit(self, 0.001, std::f64::INFINITY) {
-            let emitted = rec.material().emitted(rec.u(), rec.v(), rec.point());
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		count_ray();
+		count_path_segment();
+		let hit = world.hit(self, 0.001, f64::INFINITY);
+		self.follow(hit, background, lights, world, depth, prev)
+	}
+
+	// Rays inside a medium may scatter before they reach the next surface.
+	fn follow(&self, hit: Option<HitRecord>, background: &dyn Background, lights: &LightList, world: &HitList, depth: i32, prev: Option<(f64, Vec3)>) -> Color {
+		match self.medium.as_ref() {
+			Some(medium) => self.walk(medium, hit, background, lights, world, depth, prev),
+			None => self.shade(hit, background, lights, world, depth, prev),
+		}
+	}
+
+	// A random walk through the medium, until the ray comes out at a surface.
+	// Steps inside don't count as bounces, since a walk can take thousands of
+	// them, but one that goes on for too long is given up on.
+	#[allow(clippy::too_many_arguments)]
+	fn walk(&self, medium: &Arc<Medium>, hit: Option<HitRecord>, background: &dyn Background, lights: &LightList, world: &HitList, depth: i32, prev: Option<(f64, Vec3)>) -> Color {
+		let mut weight = Color::new(1.0, 1.0, 1.0);
+		let mut hit = hit;
+		let mut current: Option<Ray> = None;
+		for _ in 0..MAX_WALK_STEPS {
+			let ray = current.as_ref().unwrap_or(self);
+			let distance = hit.as_ref().map_or(f64::INFINITY, |rec| rec.t() * ray.direction().len());
+			let (step_weight, scattered) = ray.sample_medium(medium, distance);
+			weight *= &step_weight;
+			match scattered {
+				Some(scattered) => {
+					count_ray();
+					hit = world.hit(&scattered, 0.001, f64::INFINITY);
+					current = Some(scattered);
+				}
+				None => {
+					return match current {
+						Some(ray) => weight * &ray.shade(hit, background, lights, world, depth, None),
+						None => weight * &self.shade(hit, background, lights, world, depth, prev),
+					};
+				}
+			}
+		}
+		Color::new(0.0, 0.0, 0.0)
+	}
+
+	// Where the ray collides with a particle of its medium, if it does before
+	// it reaches distance, and the new ray it scatters into. The collision is
+	// found with one color's density and weighted by all of them. A ray with
+	// nothing ahead of it slipped out of its object, so it's let go.
+	fn sample_medium(&self, medium: &Arc<Medium>, distance: f64) -> (Color, Option<Ray>) {
+		if distance.is_infinite() {
+			return (Color::new(1.0, 1.0, 1.0), None);
+		}
+		let sigma_t = self.spectrum(medium.sigma_t());
+		let channel = ((random_f64() * 3.0) as usize).min(2);
+		let t = if sigma_t[channel] > 0.0 {
+			-(1.0 - random_f64()).ln() / sigma_t[channel]
+		} else {
+			f64::INFINITY
+		};
+		if t < distance {
+			let transmittance = exp(&(sigma_t * -t));
+			let pdf = (sigma_t[0] * transmittance[0] + sigma_t[1] * transmittance[1] + sigma_t[2] * transmittance[2]) / 3.0;
+			let weight = transmittance * &sigma_t * &self.spectrum(medium.albedo()) / pdf;
+			let point = *self.origin() + &(self.direction().unit() * t);
+			let mut scattered = Ray::new(point, random_unit_vector(), self.time()).with_medium(medium.clone());
+			scattered.wavelengths = self.wavelengths;
+			(weight, Some(scattered))
+		} else {
+			let transmittance = exp(&(sigma_t * -distance));
+			let p = (transmittance[0] + transmittance[1] + transmittance[2]) / 3.0;
+			(transmittance / p, None)
+		}
+	}
+
+	// Diffuse surfaces light themselves from the background and one chosen
+	// light directly, as well as through the scattered ray. Both estimates are
+	// kept, weighted by multiple importance sampling. prev is the pdf this ray
+	// was scattered with and the normal it left from, when the surface it left
+	// did that.
+	fn shade(&self, hit: Option<HitRecord>, background: &dyn Background, lights: &LightList, world: &HitList, depth: i32, prev: Option<(f64, Vec3)>) -> Color {
+		if let Some(rec) = hit {
+			let mut emitted = self.spectrum(&rec.material().emitted_toward(self, &rec));
+			if let (Some((scatter_pdf, normal)), Some(i)) = (prev, lights.find(rec.object())) {
+				let light_pdf = lights.pmf(self.origin(), &normal, i) * lights.light(i).pdf(self.origin(), self.direction());
+				emitted *= power_heuristic(scatter_pdf, light_pdf);
+			}
+			// Direct lighting doesn't depend on where the scattered ray goes, so
+			// it's estimated even when scattering fails.
+			let has_pdf = rec.material().has_pdf(&rec);
+			if has_pdf {
+				// On the last bounce the scattered ray can't reach the lights,
+				// so the direct estimate gets all of the weight.
+				let last = depth <= 1;
+				emitted += &(self.sample_background(background, world, &rec, last) + &self.sample_lights(lights, world, &rec, last));
+			}
 			if let Some((attenuation, scattered)) = rec.material().scatter(self, &rec) {
-				emitted + &(attenuation * &scattered.color(background, world, depth - 1))
-            } 
-            else {
-                emitted
+				let (attenuation, scattered) = self.carry_wavelengths(self.spectrum(&attenuation), scattered);
+				let next = if has_pdf {
+					Some((rec.material().pdf(self, &rec, scattered.direction()), *rec.normal()))
+				} else {
+					None
+				};
+				emitted + &(attenuation * &scattered.trace(background, lights, world, depth - 1, next))
+			} else {
+				emitted
+			}
+		} else {
+			let value = self.spectrum(&background.value(self.direction()));
+			match prev {
+				Some((pdf, _)) => value * power_heuristic(pdf, background.pdf(self.direction())),
+				None => value,
+			}
+		}
+	}
+
+	fn sample_background(&self, background: &dyn Background, world: &HitList, rec: &HitRecord, last: bool) -> Color {
+		if let Some((direction, radiance, light_pdf)) = background.sample() {
+			let scatter_pdf = rec.material().pdf(self, rec, &direction);
+			if scatter_pdf <= 0.0 {
+				return Color::new(0.0, 0.0, 0.0);
+			}
+			count_ray();
+			let shadow = Ray::new(*rec.point(), direction, self.time());
+			if world.hit(&shadow, 0.001, f64::INFINITY).is_none() {
+				let weight = if last {
+					1.0
+				} else {
+					power_heuristic(light_pdf, scatter_pdf)
+				};
+				let f = self.spectrum(&rec.material().eval(self, rec, &direction));
+				return f * &self.spectrum(&radiance) * (weight / light_pdf);
+			}
+		}
+		Color::new(0.0, 0.0, 0.0)
+	}
+
+	// One light, chosen by the light sampler, gets a shadow ray. Delta lights
+	// can't be found any other way, so they need no weighting.
+	fn sample_lights(&self, lights: &LightList, world: &HitList, rec: &HitRecord, last: bool) -> Color {
+		let (i, pmf) = match lights.sample(rec.point(), rec.normal()) {
+			Some(picked) => picked,
+			None => return Color::new(0.0, 0.0, 0.0),
+		};
+		if let Some(ls) = lights.light(i).sample(rec.point(), self.time()) {
+			let scatter_pdf = rec.material().pdf(self, rec, &ls.direction);
+			if scatter_pdf <= 0.0 {
+				return Color::new(0.0, 0.0, 0.0);
+			}
+			count_ray();
+			let shadow = Ray::new(*rec.point(), ls.direction, self.time());
+			// Stop just short of the light, nothing behind it can block it.
+			if world.hit(&shadow, 0.001, ls.distance * (1.0 - 1.0e-6)).is_none() {
+				let light_pdf = pmf * ls.pdf;
+				let weight = if ls.delta || last {
+					1.0
+				} else {
+					power_heuristic(light_pdf, scatter_pdf)
+				};
+				let f = self.spectrum(&rec.material().eval(self, rec, &ls.direction));
+				return f * &self.spectrum(&ls.radiance) * (weight / light_pdf);
 			}
-        } 
-        else {
-            *background
 		}
+		Color::new(0.0, 0.0, 0.0)
+	}
+}
+
+fn exp(v: &Color) -> Color {
+	Color::new(v[0].exp(), v[1].exp(), v[2].exp())
+}
+
+fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+	let a = pdf * pdf;
+	let b = other_pdf * other_pdf;
+	if a + b > 0.0 {
+		a / (a + b)
+	} else {
+		0.0
 	}
 }
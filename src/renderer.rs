@@ -0,0 +1,279 @@
+// renderer.rs
+// High-level rendering interface
+
+use crate::background::{Background, SolidBackground};
+use crate::camera::Camera;
+use crate::checkpoint::Checkpoint;
+use crate::denoise::{Denoiser, GuideBuffer};
+use crate::filter::{BoxFilter, Filter};
+use crate::hitable::HitList;
+use crate::light::LightList;
+use crate::light_sampler::LightSampling;
+use crate::png::PngPicture;
+use crate::progressive::Progressive;
+use crate::random::{mix_seed, random_seed};
+use crate::stats::{Counters, Progress};
+use crate::threadpool::ThreadPool;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PROGRESS_INTERVAL: u32 = 1000;
+
+// Everything about a render that isn't the scene or the camera.
+#[derive(Clone)]
+pub struct RenderSettings {
+	pub width: u32,
+	pub height: u32,
+	// The upper limit of samples per pixel. Progressive renders may stop sooner.
+	pub samples: u32,
+	pub max_depth: i32,
+	pub threads: usize,
+	pub filter: Arc<dyn Filter + Send + Sync>,
+	pub denoiser: Option<Denoiser>,
+	pub progressive: Progressive,
+	pub seed: u64,
+	// How the lights are chosen between.
+	pub light_sampling: LightSampling,
+	// Trace sampled wavelengths instead of RGB, so that dispersion works.
+	pub spectral: bool,
+	// How many pixels to receive between progress callbacks.
+	pub progress_interval: u32,
+}
+
+impl RenderSettings {
+	pub fn new(width: u32, height: u32, samples: u32, max_depth: i32, threads: usize) -> Self {
+		Self {
+			width,
+			height,
+			samples,
+			max_depth,
+			threads,
+			filter: Arc::new(BoxFilter::default()),
+			denoiser: None,
+			progressive: Progressive::default(),
+			seed: random_seed(),
+			light_sampling: LightSampling::Bvh,
+			spectral: false,
+			progress_interval: DEFAULT_PROGRESS_INTERVAL,
+		}
+	}
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StopReason {
+	Completed,
+	TimeBudget,
+	NoiseThreshold,
+	Cancelled,
+}
+
+pub struct RenderOutput {
+	// The accumulated samples, which can also be saved as a checkpoint.
+	pub state: Checkpoint,
+	pub counters: Counters,
+	pub trace_time: Duration,
+	pub stop_reason: StopReason,
+	denoiser: Option<Denoiser>,
+}
+
+impl RenderOutput {
+	// The finished image, denoised if the settings asked for it.
+	pub fn framebuffer(&self) -> PngPicture {
+		framebuffer(&self.state, self.denoiser.as_ref())
+	}
+}
+
+// Reconstruct (and optionally denoise) the image from a render's state. The
+// state itself is left alone, so more samples can still be added to it.
+pub fn framebuffer(state: &Checkpoint, denoiser: Option<&Denoiser>) -> PngPicture {
+	let mut picture = state.picture.clone();
+	if let Some(denoiser) = denoiser {
+		picture.denoise(denoiser, &state.guides);
+	}
+	picture
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&Progress) + 'a>;
+type PassCallback<'a> = Box<dyn FnMut(&Checkpoint, bool) + 'a>;
+
+pub struct Renderer<'a> {
+	world: Arc<HitList>,
+	camera: Arc<Camera>,
+	background: Arc<dyn Background + Send + Sync>,
+	lights: Arc<LightList>,
+	settings: RenderSettings,
+	cancel: Arc<AtomicBool>,
+	resume: Option<Checkpoint>,
+	progress_callback: Option<ProgressCallback<'a>>,
+	pass_callback: Option<PassCallback<'a>>,
+}
+
+impl<'a> Renderer<'a> {
+	pub fn new(world: Arc<HitList>, camera: Arc<Camera>, settings: RenderSettings) -> Self {
+		Self {
+			world,
+			camera,
+			background: Arc::new(SolidBackground::default()),
+			lights: Arc::new(LightList::default()),
+			settings,
+			cancel: Arc::new(AtomicBool::new(false)),
+			resume: None,
+			progress_callback: None,
+			pass_callback: None,
+		}
+	}
+
+	// What escaping rays see. The default is black, so only emissive objects light the scene.
+	pub fn set_background(&mut self, background: Arc<dyn Background + Send + Sync>) {
+		self.background = background;
+	}
+
+	// Point, spot and directional lights, and the emissive objects to sample
+	// as area lights.
+	pub fn set_lights(&mut self, lights: Arc<LightList>) {
+		self.lights = lights;
+	}
+
+	pub fn settings(&self) -> &RenderSettings {
+		&self.settings
+	}
+
+	// Setting the flag stops the render after the samples in flight, and
+	// render() returns whatever was traced.
+	pub fn cancel_flag(&self) -> Arc<AtomicBool> {
+		self.cancel.clone()
+	}
+
+	// Share a cancel flag between several renderers, such as one per frame.
+	pub fn set_cancel_flag(&mut self, cancel: Arc<AtomicBool>) {
+		self.cancel = cancel;
+	}
+
+	// Continue from an earlier render instead of starting from nothing. The
+	// checkpoint's seed takes over from the settings. A checkpoint that can't
+	// be continued with these settings is refused with the reason, and
+	// settings that changed harmlessly are returned as warnings.
+	pub fn resume(&mut self, mut checkpoint: Checkpoint) -> Result<Vec<String>, String> {
+		let warnings = checkpoint.accept_settings(&self.settings)?;
+		self.resume = Some(checkpoint);
+		Ok(warnings)
+	}
+
+	// Called every progress_interval pixels.
+	pub fn on_progress(&mut self, callback: impl FnMut(&Progress) + 'a) {
+		self.progress_callback = Some(Box::new(callback));
+	}
+
+	// Called after every pass with the render's state, and whether it was the last pass.
+	pub fn on_pass(&mut self, callback: impl FnMut(&Checkpoint, bool) + 'a) {
+		self.pass_callback = Some(Box::new(callback));
+	}
+
+	pub fn render(&mut self) -> RenderOutput {
+		let width = self.settings.width;
+		let height = self.settings.height;
+		let samples = self.settings.samples;
+		let total_pixels = width * height;
+		let progressive = self.settings.progressive.clone();
+		let start = Instant::now();
+		let mut trace_time = Duration::from_secs(0);
+		let mut stop_reason = StopReason::Completed;
+		let mut pool = ThreadPool::new(self.settings.threads, self.cancel.clone());
+		let mut lights = self.lights.as_ref().clone();
+		lights.build(self.settings.light_sampling, self.world.bounding_box(self.camera.time0(), self.camera.time1()));
+		let lights = Arc::new(lights);
+
+		// Everything accumulated so far. This is exactly what gets checkpointed.
+		let mut state = match self.resume.take() {
+			Some(ckpt) => ckpt,
+			None => Checkpoint::new(&self.settings),
+		};
+		let mut progress = Progress::new(samples as u64 * total_pixels as u64, state.samples_done as u64 * total_pixels as u64);
+
+		// Without progressive settings, this loop runs exactly once with all of the samples.
+		while state.samples_done < samples {
+			let pass_samples = progressive.next_pass(samples - state.samples_done);
+			// Every pass gets its own seed, so resumed passes don't repeat earlier samples.
+			let pass_seed = mix_seed(&[state.seed, state.passes as u64]);
+			let pass_start = Instant::now();
+			// Spawn the thread pool with the work that needs to be done.
+			for j in 0..height {
+				for i in 0..width {
+					pool.run_c(j, i, self.camera.clone(), self.world.clone(), self.settings.filter.clone(), self.background.clone(), lights.clone(), pass_seed, pass_samples, width, height, state.max_depth, self.settings.spectral);
+				}
+			}
+			// Even though we get here, the work the threads are doing isn't necessarily done.
+			let mut pixels_remaining = 0;
+
+			// We get the data from the threads. Recv may block here, which might prevent
+			// us from getting data from another thread, however, the work has to get done
+			// anyway before the pass is finished.
+			let mut pass_picture = PngPicture::new(width, height);
+			let mut pass_guides = GuideBuffer::new(width, height);
+			for t in pool.threads.iter_mut() {
+				for _ in 0..t.packets_sent {
+					let d = t.data.recv().unwrap();
+					// Each pixel's samples are splatted into its neighbors too, weighted by the filter.
+					for s in d.splats.iter() {
+						pass_picture.add_sample(s.col, s.row, &s.color, s.weight);
+					}
+					pass_guides.set_pixel(d.col, d.row, &d.albedo, &d.normal, d.depth);
+					state.sample_counts[(d.row * width + d.col) as usize] += d.samples;
+					progress.add(&d.counters);
+
+					if pixels_remaining == 0 {
+						if let Some(callback) = self.progress_callback.as_mut() {
+							callback(&progress);
+						}
+						pixels_remaining = self.settings.progress_interval.max(1);
+					}
+					pixels_remaining -= 1;
+				}
+				t.packets_sent = 0;
+			}
+			if let Some(callback) = self.progress_callback.as_mut() {
+				callback(&progress);
+			}
+			trace_time += pass_start.elapsed();
+
+			// Even a cancelled pass used up its seed, so it still counts as a pass.
+			let cancelled = self.cancel.load(Ordering::SeqCst);
+			state.picture.merge(&pass_picture);
+			state.passes += 1;
+			if cancelled {
+				// A partial pass says nothing reliable about the noise, and its guides
+				// are only worth keeping if there is nothing better.
+				if state.samples_done == 0 {
+					state.guides = pass_guides;
+				}
+				stop_reason = StopReason::Cancelled;
+			} else {
+				state.noise.add_pass(&pass_picture);
+				state.guides.blend(&pass_guides, pass_samples as f64 / (state.samples_done + pass_samples) as f64);
+				state.samples_done += pass_samples;
+				if state.samples_done < samples && progressive.out_of_time(start.elapsed()) {
+					stop_reason = StopReason::TimeBudget;
+				} else if state.samples_done < samples && progressive.converged(&state.noise) {
+					stop_reason = StopReason::NoiseThreshold;
+				}
+			}
+
+			let done = stop_reason != StopReason::Completed || state.samples_done >= samples;
+			if let Some(callback) = self.pass_callback.as_mut() {
+				callback(&state, done);
+			}
+			if done {
+				break;
+			}
+		}
+
+		RenderOutput {
+			state,
+			counters: *progress.counters(),
+			trace_time,
+			stop_reason,
+			denoiser: self.settings.denoiser.clone(),
+		}
+	}
+}
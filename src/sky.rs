@@ -0,0 +1,383 @@
+// sky.rs
+// Procedural skies and the sun
+
+use crate::background::Background;
+use crate::random::random_f64;
+use crate::vector::{Color, Vec3};
+use std::f64::consts::PI;
+use std::fs::read_to_string;
+use std::io::{Error, ErrorKind};
+use std::vec::Vec;
+
+// Half of the angle the sun covers, as seen from the ground.
+const SUN_ANGULAR_RADIUS: f64 = 0.2667;
+// Luminance of the sun before the atmosphere, in kcd/m^2 like the sky models.
+const SUN_LUMINANCE: f64 = 2.0e6;
+
+// The direction toward a sun at this elevation above the horizon and azimuth,
+// both in degrees. Azimuth 0 is toward -z, and 90 is toward +x.
+pub fn sun_direction(elevation: f64, azimuth: f64) -> Vec3 {
+	let el = elevation.to_radians();
+	let az = azimuth.to_radians();
+	Vec3::new(el.cos() * az.sin(), el.sin(), -el.cos() * az.cos())
+}
+
+// The book's sky, blending from the horizon color to the zenith color.
+pub struct GradientSky {
+	horizon: Color,
+	zenith: Color,
+}
+
+impl Default for GradientSky {
+	fn default() -> Self {
+		Self::new(Color::new(1.0, 1.0, 1.0), Color::new(0.5, 0.7, 1.0))
+	}
+}
+
+impl GradientSky {
+	pub fn new(horizon: Color, zenith: Color) -> Self {
+		Self {
+			horizon,
+			zenith,
+		}
+	}
+}
+
+impl Background for GradientSky {
+	fn value(&self, direction: &Vec3) -> Color {
+		let t = 0.5 * (direction.unit().y() + 1.0);
+		self.horizon * (1.0 - t) + &(self.zenith * t)
+	}
+}
+
+// The sun is a small disk of constant radiance. It is tiny and very bright,
+// so it has to be found by sampling it directly.
+pub struct Sun {
+	direction: Vec3,
+	cos_radius: f64,
+	radiance: Color,
+}
+
+impl Sun {
+	pub fn new(direction: Vec3, radiance: Color) -> Self {
+		Self {
+			direction: direction.unit(),
+			cos_radius: SUN_ANGULAR_RADIUS.to_radians().cos(),
+			radiance,
+		}
+	}
+
+	// The sun as seen through a clear atmosphere of the given turbidity. Its
+	// color comes from Rayleigh and aerosol extinction along the path through
+	// the air (Preetham et al., appendix A), evaluated at one wavelength per
+	// channel. A sun below the horizon gives no light.
+	pub fn through_atmosphere(direction: Vec3, turbidity: f64) -> Self {
+		let direction = direction.unit();
+		if direction.y() <= 0.0 {
+			return Self::new(direction, Color::new(0.0, 0.0, 0.0));
+		}
+		let theta = direction.y().acos();
+		let air_mass = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+		let beta = 0.04608 * turbidity - 0.04586;
+		let transmittance = |lambda: f64| {
+			let rayleigh = (-0.008735 * lambda.powf(-4.08) * air_mass).exp();
+			let aerosol = (-beta * lambda.powf(-1.3) * air_mass).exp();
+			rayleigh * aerosol
+		};
+		let color = Color::new(transmittance(0.680), transmittance(0.550), transmittance(0.440));
+		Self::new(direction, color * SUN_LUMINANCE)
+	}
+
+	pub fn direction(&self) -> &Vec3 {
+		&self.direction
+	}
+
+	pub fn solid_angle(&self) -> f64 {
+		2.0 * PI * (1.0 - self.cos_radius)
+	}
+
+	pub fn contains(&self, direction: &Vec3) -> bool {
+		direction.unit().dot(&self.direction) >= self.cos_radius
+	}
+
+	pub fn value(&self, direction: &Vec3) -> Color {
+		if self.contains(direction) {
+			self.radiance
+		} else {
+			Color::new(0.0, 0.0, 0.0)
+		}
+	}
+
+	// A uniformly chosen direction within the disk.
+	pub fn sample(&self) -> Option<(Vec3, f64)> {
+		if self.radiance.r() <= 0.0 && self.radiance.g() <= 0.0 && self.radiance.b() <= 0.0 {
+			return None;
+		}
+		let cos_theta = 1.0 - random_f64() * (1.0 - self.cos_radius);
+		let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+		let phi = 2.0 * PI * random_f64();
+		let (u, v) = self.direction.basis();
+		let direction = u * (sin_theta * phi.cos()) + &(v * (sin_theta * phi.sin())) + &(self.direction * cos_theta);
+		Some((direction, 1.0 / self.solid_angle()))
+	}
+
+	pub fn pdf(&self, direction: &Vec3) -> f64 {
+		if self.contains(direction) {
+			1.0 / self.solid_angle()
+		} else {
+			0.0
+		}
+	}
+}
+
+// CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: f64, y: f64, lum: f64) -> Color {
+	if y <= 0.0 {
+		return Color::new(0.0, 0.0, 0.0);
+	}
+	let cx = x / y * lum;
+	let cz = (1.0 - x - y) / y * lum;
+	let r = 3.2406 * cx - 1.5372 * lum - 0.4986 * cz;
+	let g = -0.9689 * cx + 1.8758 * lum + 0.0415 * cz;
+	let b = 0.0557 * cx - 0.2040 * lum + 1.0570 * cz;
+	Color::new(r.max(0.0), g.max(0.0), b.max(0.0))
+}
+
+// The Perez et al. sky luminance distribution.
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+	let cos_gamma = gamma.cos();
+	(1.0 + c[0] * (c[1] / cos_theta).exp()) * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+// Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight".
+// Radiance is in kcd/m^2, which is much brighter than anything else in a
+// scene, so the intensity usually has to come down to compensate.
+pub struct PreethamSky {
+	sun: Sun,
+	// Luminance and chromaticity at the zenith.
+	zenith: [f64; 3],
+	// Perez coefficients for Y, x and y.
+	coeffs: [[f64; 5]; 3],
+	// Perez at the zenith, the denominator for every direction.
+	zenith_perez: [f64; 3],
+	intensity: f64,
+}
+
+impl PreethamSky {
+	// Elevation and azimuth of the sun are in degrees. The model is only valid
+	// for turbidity from 2 (very clear) to 10 (hazy).
+	pub fn new(elevation: f64, azimuth: f64, turbidity: f64, intensity: f64) -> Self {
+		let t = turbidity.clamp(2.0, 10.0);
+		let direction = sun_direction(elevation, azimuth);
+		let theta_s = (PI / 2.0 - elevation.to_radians()).clamp(0.0, PI / 2.0);
+
+		let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+		let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+		let th = theta_s;
+		let th2 = th * th;
+		let th3 = th2 * th;
+		let zenith_x = t * t * (0.00166 * th3 - 0.00375 * th2 + 0.00209 * th) + t * (-0.02903 * th3 + 0.06377 * th2 - 0.03202 * th + 0.00394) + (0.11693 * th3 - 0.21196 * th2 + 0.06052 * th + 0.25886);
+		let zenith_yc = t * t * (0.00275 * th3 - 0.00610 * th2 + 0.00317 * th) + t * (-0.04214 * th3 + 0.08970 * th2 - 0.04153 * th + 0.00516) + (0.15346 * th3 - 0.26756 * th2 + 0.06670 * th + 0.26688);
+
+		let coeffs = [
+			[0.1787 * t - 1.4630, -0.3554 * t + 0.4275, -0.0227 * t + 5.3251, 0.1206 * t - 2.5771, -0.0670 * t + 0.3703],
+			[-0.0193 * t - 0.2592, -0.0665 * t + 0.0008, -0.0004 * t + 0.2125, -0.0641 * t - 0.8989, -0.0033 * t + 0.0452],
+			[-0.0167 * t - 0.2608, -0.0950 * t + 0.0092, -0.0079 * t + 0.2102, -0.0441 * t - 1.6537, -0.0109 * t + 0.0529],
+		];
+		let mut zenith_perez = [0.0; 3];
+		for (zp, c) in zenith_perez.iter_mut().zip(coeffs.iter()) {
+			*zp = perez(c, 1.0, theta_s);
+		}
+
+		Self {
+			sun: Sun::through_atmosphere(direction, t),
+			zenith: [zenith_y.max(0.0), zenith_x, zenith_yc],
+			coeffs,
+			zenith_perez,
+			intensity,
+		}
+	}
+
+	pub fn sun(&self) -> &Sun {
+		&self.sun
+	}
+
+	fn sky(&self, direction: &Vec3) -> Color {
+		let d = direction.unit();
+		// There is no ground in the model.
+		if d.y() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		let gamma = d.dot(self.sun.direction()).clamp(-1.0, 1.0).acos();
+		let mut vals = [0.0; 3];
+		for (i, val) in vals.iter_mut().enumerate() {
+			*val = self.zenith[i] * perez(&self.coeffs[i], d.y(), gamma) / self.zenith_perez[i];
+		}
+		xyy_to_rgb(vals[1], vals[2], vals[0])
+	}
+}
+
+impl Background for PreethamSky {
+	fn value(&self, direction: &Vec3) -> Color {
+		(self.sky(direction) + &self.sun.value(direction)) * self.intensity
+	}
+
+	fn sample(&self) -> Option<(Vec3, Color, f64)> {
+		let (direction, pdf) = self.sun.sample()?;
+		Some((direction, self.value(&direction), pdf))
+	}
+
+	fn pdf(&self, direction: &Vec3) -> f64 {
+		self.sun.pdf(direction)
+	}
+}
+
+// The fitted coefficients of the Hosek-Wilkie model, as published by the
+// authors in ArHosekSkyModelData_RGB.h. They are too large to carry around
+// here, so they are read from that file.
+pub struct HosekWilkieData {
+	// Per channel: 2 albedos x 10 turbidities x 6 elevation control points x 9 coefficients.
+	configs: [Vec<f64>; 3],
+	// Per channel: 2 albedos x 10 turbidities x 6 elevation control points.
+	radiances: [Vec<f64>; 3],
+}
+
+impl HosekWilkieData {
+	pub fn from_file(fname: &str) -> Result<Self, Error> {
+		let text = read_to_string(fname)?;
+		let mut configs: [Vec<f64>; 3] = Default::default();
+		let mut radiances: [Vec<f64>; 3] = Default::default();
+		for channel in 0..3 {
+			configs[channel] = parse_array(&text, &format!("datasetRGB{}", channel + 1), 2 * 10 * 6 * 9)?;
+			radiances[channel] = parse_array(&text, &format!("datasetRGBRad{}", channel + 1), 2 * 10 * 6)?;
+		}
+		Ok(Self {
+			configs,
+			radiances,
+		})
+	}
+}
+
+// Pulls the numbers out of a C array definition such as "double name[] = { ... };".
+fn parse_array(text: &str, name: &str, len: usize) -> Result<Vec<f64>, Error> {
+	let invalid = |msg: &str| Error::new(ErrorKind::InvalidData, format!("{}: {}", name, msg));
+	let start = text.find(&format!("{}[]", name)).ok_or_else(|| invalid("not found"))?;
+	let open = start + text[start..].find('{').ok_or_else(|| invalid("no data"))?;
+	let close = open + text[open..].find('}').ok_or_else(|| invalid("unterminated"))?;
+	let mut body = String::new();
+	let mut rest = &text[open + 1..close];
+	// Strip the comments, the file labels its rows with them.
+	while !rest.is_empty() {
+		if let Some(after) = rest.strip_prefix("/*") {
+			rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+		} else if let Some(after) = rest.strip_prefix("//") {
+			rest = after.find('\n').map_or("", |end| &after[end..]);
+		} else {
+			let c = rest.chars().next().unwrap();
+			body.push(c);
+			rest = &rest[c.len_utf8()..];
+		}
+	}
+	let mut vals = Vec::with_capacity(len);
+	for tok in body.split(|c: char| c == ',' || c.is_whitespace()).filter(|t| !t.is_empty()) {
+		vals.push(tok.parse::<f64>().map_err(|_| invalid("bad number"))?);
+	}
+	if vals.len() != len {
+		return Err(invalid(&format!("expected {} values, found {}", len, vals.len())));
+	}
+	Ok(vals)
+}
+
+// Interpolates the dataset for a sky: a quintic Bezier over the sun's
+// elevation, and linear over turbidity and ground albedo.
+fn cook(dataset: &[f64], stride: usize, turbidity: f64, albedo: f64, elevation: f64) -> Vec<f64> {
+	let int_t = (turbidity as usize).clamp(1, 10);
+	let rem = turbidity - int_t as f64;
+	let s = (elevation / (PI / 2.0)).powf(1.0 / 3.0);
+	let bezier = |offset: usize, i: usize| {
+		let m = &dataset[offset..];
+		let r = 1.0 - s;
+		r.powi(5) * m[i] + 5.0 * r.powi(4) * s * m[i + stride] + 10.0 * r.powi(3) * s.powi(2) * m[i + 2 * stride] + 10.0 * r.powi(2) * s.powi(3) * m[i + 3 * stride] + 5.0 * r * s.powi(4) * m[i + 4 * stride] + s.powi(5) * m[i + 5 * stride]
+	};
+	let block = stride * 6;
+	let mut out = vec![0.0; stride];
+	for (i, o) in out.iter_mut().enumerate() {
+		*o = (1.0 - albedo) * (1.0 - rem) * bezier(block * (int_t - 1), i) + albedo * (1.0 - rem) * bezier(block * 10 + block * (int_t - 1), i);
+		if int_t < 10 {
+			*o += (1.0 - albedo) * rem * bezier(block * int_t, i) + albedo * rem * bezier(block * 10 + block * int_t, i);
+		}
+	}
+	out
+}
+
+// Hosek and Wilkie, "An Analytic Model for Full Spectral Sky-Dome Radiance",
+// using the authors' RGB fit. Like Preetham, it is much brighter than the
+// rest of a scene, and the intensity is there to bring it down.
+pub struct HosekWilkieSky {
+	sun: Sun,
+	configs: [[f64; 9]; 3],
+	radiances: [f64; 3],
+	intensity: f64,
+}
+
+impl HosekWilkieSky {
+	// Elevation and azimuth of the sun are in degrees. Turbidity goes from 1
+	// to 10, and the ground albedo from 0 to 1.
+	pub fn new(data: &HosekWilkieData, elevation: f64, azimuth: f64, turbidity: f64, albedo: f64, intensity: f64) -> Self {
+		let t = turbidity.clamp(1.0, 10.0);
+		let a = albedo.clamp(0.0, 1.0);
+		let el = elevation.to_radians().clamp(0.0, PI / 2.0);
+		let mut configs = [[0.0; 9]; 3];
+		let mut radiances = [0.0; 3];
+		for channel in 0..3 {
+			configs[channel].copy_from_slice(&cook(&data.configs[channel], 9, t, a, el));
+			radiances[channel] = cook(&data.radiances[channel], 1, t, a, el)[0];
+		}
+		Self {
+			sun: Sun::through_atmosphere(sun_direction(elevation, azimuth), t),
+			configs,
+			radiances,
+			intensity,
+		}
+	}
+
+	pub fn sun(&self) -> &Sun {
+		&self.sun
+	}
+
+	fn sky(&self, direction: &Vec3) -> Color {
+		let d = direction.unit();
+		if d.y() <= 0.0 {
+			return Color::new(0.0, 0.0, 0.0);
+		}
+		let cos_theta = d.y();
+		let cos_gamma = d.dot(self.sun.direction()).clamp(-1.0, 1.0);
+		let gamma = cos_gamma.acos();
+		let mut rgb = [0.0; 3];
+		for (channel, val) in rgb.iter_mut().enumerate() {
+			let c = &self.configs[channel];
+			let exp_m = (c[4] * gamma).exp();
+			let ray_m = cos_gamma * cos_gamma;
+			let mie_m = (1.0 + cos_gamma * cos_gamma) / (1.0 + c[8] * c[8] - 2.0 * c[8] * cos_gamma).powf(1.5);
+			let zenith = cos_theta.sqrt();
+			let f = (1.0 + c[0] * (c[1] / (cos_theta + 0.01)).exp()) * (c[2] + c[3] * exp_m + c[5] * ray_m + c[6] * mie_m + c[7] * zenith);
+			*val = (f * self.radiances[channel]).max(0.0);
+		}
+		Color::new(rgb[0], rgb[1], rgb[2])
+	}
+}
+
+impl Background for HosekWilkieSky {
+	fn value(&self, direction: &Vec3) -> Color {
+		(self.sky(direction) + &self.sun.value(direction)) * self.intensity
+	}
+
+	fn sample(&self) -> Option<(Vec3, Color, f64)> {
+		let (direction, pdf) = self.sun.sample()?;
+		Some((direction, self.value(&direction), pdf))
+	}
+
+	fn pdf(&self, direction: &Vec3) -> f64 {
+		self.sun.pdf(direction)
+	}
+}
@@ -0,0 +1,316 @@
+// spectrum.rs
+// Hero wavelength sampling, color matching and dispersion
+
+use crate::vector::Color;
+use std::sync::OnceLock;
+
+// The wavelengths traced together. Three, so that radiance at each of them
+// fits in a Color.
+pub const WAVELENGTHS: usize = 3;
+pub const LAMBDA_MIN: f64 = 360.0;
+pub const LAMBDA_MAX: f64 = 830.0;
+
+// The wavelengths one camera ray carries, in nanometers. The first is the
+// hero, the others are spread evenly from it so that together they cover the
+// spectrum.
+#[derive(Copy, Clone, Debug)]
+pub struct Wavelengths {
+	lambda: [f64; WAVELENGTHS],
+	pdf: [f64; WAVELENGTHS],
+	terminated: bool,
+}
+
+impl Wavelengths {
+	// Picks wavelengths where the eye is most sensitive more often (after
+	// PBRT's visible wavelength sampling).
+	pub fn sample(u: f64) -> Self {
+		let mut lambda = [0.0; WAVELENGTHS];
+		let mut pdf = [0.0; WAVELENGTHS];
+		for i in 0..WAVELENGTHS {
+			let ui = (u + i as f64 / WAVELENGTHS as f64).fract();
+			lambda[i] = (538.0 - 138.888889 * (0.85691062 - 1.82750197 * ui).atanh()).clamp(LAMBDA_MIN, LAMBDA_MAX);
+			pdf[i] = visible_pdf(lambda[i]);
+		}
+		Self {
+			lambda,
+			pdf,
+			terminated: false,
+		}
+	}
+
+	pub fn hero(&self) -> f64 {
+		self.lambda[0]
+	}
+
+	pub fn lambda(&self, i: usize) -> f64 {
+		self.lambda[i]
+	}
+
+	// Whether only the hero is left.
+	pub fn is_terminated(&self) -> bool {
+		self.terminated
+	}
+
+	// Once a path depends on the wavelength, such as by refracting through
+	// dispersive glass, it can only carry the hero.
+	pub fn terminate_secondary(mut self) -> Self {
+		self.terminated = true;
+		self
+	}
+
+	// The radiance a path has to be scaled by when it drops the secondary
+	// wavelengths, so the hero alone makes up for them.
+	pub fn termination_weight() -> Color {
+		Color::new(WAVELENGTHS as f64, 0.0, 0.0)
+	}
+
+	// The value of an RGB color at each wavelength.
+	pub fn from_rgb(&self, rgb: &Color) -> Color {
+		let tables = tables();
+		let coefficients = tables.rgb_to_basis.apply(rgb);
+		let mut spectrum = Color::new(0.0, 0.0, 0.0);
+		for i in 0..WAVELENGTHS {
+			let basis = basis(self.lambda[i]);
+			spectrum[i] = (coefficients[0] * basis[0] + coefficients[1] * basis[1] + coefficients[2] * basis[2]).max(0.0);
+		}
+		spectrum
+	}
+
+	// The linear sRGB color of radiance traced at these wavelengths.
+	pub fn to_rgb(&self, radiance: &Color) -> Color {
+		let mut xyz = Color::new(0.0, 0.0, 0.0);
+		for i in 0..WAVELENGTHS {
+			if self.pdf[i] <= 0.0 {
+				continue;
+			}
+			xyz += &(cie_xyz(self.lambda[i]) * (radiance[i] / (self.pdf[i] * WAVELENGTHS as f64)));
+		}
+		xyz_to_rgb(&xyz)
+	}
+}
+
+fn visible_pdf(lambda: f64) -> f64 {
+	if !(LAMBDA_MIN..=LAMBDA_MAX).contains(&lambda) {
+		return 0.0;
+	}
+	let c = (0.0072 * (lambda - 538.0)).cosh();
+	0.0039398042 / (c * c)
+}
+
+// The CIE 1931 color matching functions, as fitted by Wyman, Sloan and
+// Shirley 2013.
+pub fn cie_xyz(lambda: f64) -> Color {
+	let g = |mu: f64, s1: f64, s2: f64| {
+		let t = (lambda - mu) / if lambda < mu {
+			s1
+		} else {
+			s2
+		};
+		(-0.5 * t * t).exp()
+	};
+	let x = 1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2);
+	let y = 0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1);
+	let z = 1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8);
+	Color::new(x, y, z)
+}
+
+// The color of a surface that reflects this much at each wavelength, under
+// white light. Sampled every 10 nm, so it's meant for smooth spectra, like
+// the ones that come from interference.
+pub fn reflectance_to_rgb(reflectance: impl Fn(f64) -> f64) -> Color {
+	let mut xyz = Color::new(0.0, 0.0, 0.0);
+	let mut white = Color::new(0.0, 0.0, 0.0);
+	let mut lambda = 380.0;
+	while lambda <= 780.0 {
+		let cmf = cie_xyz(lambda);
+		xyz += &(cmf * reflectance(lambda));
+		white += &cmf;
+		lambda += 10.0;
+	}
+	let rgb = XYZ_TO_SRGB.apply(&xyz);
+	let white = XYZ_TO_SRGB.apply(&white);
+	Color::new((rgb.r() / white.r()).clamp(0.0, 1.0), (rgb.g() / white.g()).clamp(0.0, 1.0), (rgb.b() / white.b()).clamp(0.0, 1.0))
+}
+
+// The linear sRGB color of a black body at this temperature, scaled to a
+// luminance of 1. Like everything else here it's balanced so that an equal
+// energy spectrum is white, which makes 5500 K look about white.
+pub fn blackbody(kelvin: f64) -> Color {
+	// Planck's law, with the wavelength in meters.
+	const H: f64 = 6.62607015e-34;
+	const C: f64 = 2.99792458e8;
+	const K: f64 = 1.380649e-23;
+	let planck = |lambda: f64| {
+		let l = lambda * 1.0e-9;
+		2.0 * H * C * C / (l.powi(5) * ((H * C / (l * K * kelvin.max(1.0))).exp() - 1.0))
+	};
+	let mut xyz = Color::new(0.0, 0.0, 0.0);
+	let mut lambda = LAMBDA_MIN;
+	while lambda <= LAMBDA_MAX {
+		xyz += &(cie_xyz(lambda) * planck(lambda));
+		lambda += 1.0;
+	}
+	let rgb = xyz_to_rgb(&xyz);
+	let rgb = Color::new(rgb.r().max(0.0), rgb.g().max(0.0), rgb.b().max(0.0));
+	if rgb.luminance() <= 0.0 {
+		return Color::new(0.0, 0.0, 0.0);
+	}
+	rgb / rgb.luminance()
+}
+
+// XYZ, normalized so that a spectrum of 1 has Y = 1, to linear sRGB. The
+// result is white balanced so that spectrum comes out white.
+fn xyz_to_rgb(xyz: &Color) -> Color {
+	let tables = tables();
+	let rgb = XYZ_TO_SRGB.apply(&(*xyz / tables.y_integral));
+	Color::new(rgb.r() / tables.white.r(), rgb.g() / tables.white.g(), rgb.b() / tables.white.b())
+}
+
+// Smooth blue, green and red spectra that add up to 1 everywhere. RGB is
+// upsampled as a mix of these, which is cruder than Jakob and Hanika's
+// method, but needs no table and turns white into exactly 1.
+fn basis(lambda: f64) -> [f64; 3] {
+	let blue = 1.0 / (1.0 + ((lambda - 490.0) / 12.0).exp());
+	let red = 1.0 / (1.0 + (-(lambda - 590.0) / 12.0).exp());
+	[red, 1.0 - red - blue, blue]
+}
+
+#[derive(Copy, Clone)]
+struct Matrix3([[f64; 3]; 3]);
+
+const XYZ_TO_SRGB: Matrix3 = Matrix3([[3.2404542, -1.5371385, -0.4985314], [-0.9692660, 1.8760108, 0.0415560], [0.0556434, -0.2040259, 1.0572252]]);
+
+impl Matrix3 {
+	fn apply(&self, v: &Color) -> Color {
+		let m = &self.0;
+		Color::new(m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2], m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2], m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2])
+	}
+
+	fn inverse(&self) -> Self {
+		let m = &self.0;
+		let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0]) + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+		let mut inv = [[0.0; 3]; 3];
+		for (r, row) in inv.iter_mut().enumerate() {
+			for (c, value) in row.iter_mut().enumerate() {
+				// The cofactor of m[c][r], over the determinant.
+				let (r0, r1) = ((c + 1) % 3, (c + 2) % 3);
+				let (c0, c1) = ((r + 1) % 3, (r + 2) % 3);
+				*value = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+			}
+		}
+		Self(inv)
+	}
+}
+
+// Everything that has to be integrated over the spectrum once.
+struct Tables {
+	y_integral: f64,
+	// sRGB of a spectrum of 1, before white balancing.
+	white: Color,
+	// From an RGB color to how much of each basis spectrum to mix.
+	rgb_to_basis: Matrix3,
+}
+
+fn tables() -> &'static Tables {
+	static TABLES: OnceLock<Tables> = OnceLock::new();
+	TABLES.get_or_init(|| {
+		// Integrate in 1 nm steps.
+		let mut xyz_white = Color::new(0.0, 0.0, 0.0);
+		let mut xyz_basis = [Color::new(0.0, 0.0, 0.0); 3];
+		let mut lambda = LAMBDA_MIN;
+		while lambda <= LAMBDA_MAX {
+			let cmf = cie_xyz(lambda);
+			xyz_white += &cmf;
+			let b = basis(lambda);
+			for i in 0..3 {
+				xyz_basis[i] += &(cmf * b[i]);
+			}
+			lambda += 1.0;
+		}
+		let y_integral = xyz_white.y();
+		let white = XYZ_TO_SRGB.apply(&(xyz_white / y_integral));
+		let mut basis_to_rgb = [[0.0; 3]; 3];
+		for i in 0..3 {
+			let rgb = XYZ_TO_SRGB.apply(&(xyz_basis[i] / y_integral));
+			basis_to_rgb[0][i] = rgb.r() / white.r();
+			basis_to_rgb[1][i] = rgb.g() / white.g();
+			basis_to_rgb[2][i] = rgb.b() / white.b();
+		}
+		Tables {
+			y_integral,
+			white,
+			rgb_to_basis: Matrix3(basis_to_rgb).inverse(),
+		}
+	})
+}
+
+// Index of refraction, which may depend on the wavelength (in nanometers).
+// Cauchy and Sellmeier coefficients are for wavelengths in micrometers, the
+// way glass catalogs list them.
+#[derive(Copy, Clone, Debug)]
+pub enum Ior {
+	Constant(f64),
+	Cauchy {
+		a: f64,
+		b: f64,
+	},
+	Sellmeier {
+		b: [f64; 3],
+		c: [f64; 3],
+	},
+}
+
+impl Ior {
+	// Schott N-BK7, the usual crown glass.
+	pub fn bk7() -> Self {
+		Ior::Sellmeier {
+			b: [1.03961212, 0.231792344, 1.01046945],
+			c: [0.00600069867, 0.0200179144, 103.560653],
+		}
+	}
+
+	// Schott SF11, a dense flint glass that disperses a lot.
+	pub fn sf11() -> Self {
+		Ior::Sellmeier {
+			b: [1.73759695, 0.313747346, 1.89878101],
+			c: [0.013188707, 0.0623068142, 155.23629],
+		}
+	}
+
+	pub fn fused_silica() -> Self {
+		Ior::Sellmeier {
+			b: [0.6961663, 0.4079426, 0.8974794],
+			c: [0.00467914826, 0.0135120631, 97.9340025],
+		}
+	}
+
+	pub fn at(&self, lambda: f64) -> f64 {
+		let um2 = (lambda * 1.0e-3) * (lambda * 1.0e-3);
+		match self {
+			Ior::Constant(ir) => *ir,
+			Ior::Cauchy {
+				a,
+				b,
+			} => a + b / um2,
+			Ior::Sellmeier {
+				b,
+				c,
+			} => {
+				let mut n2 = 1.0;
+				for i in 0..3 {
+					n2 += b[i] * um2 / (um2 - c[i]);
+				}
+				n2.sqrt()
+			}
+		}
+	}
+
+	// The index at the sodium d line, which is what an RGB render uses.
+	pub fn nominal(&self) -> f64 {
+		self.at(587.6)
+	}
+
+	pub fn is_dispersive(&self) -> bool {
+		!matches!(self, Ior::Constant(_))
+	}
+}
@@ -0,0 +1,180 @@
+// stats.rs
+// Render counters, progress reporting, and final statistics
+
+use std::cell::Cell;
+use std::fs::read_to_string;
+use std::ops::AddAssign;
+use std::time::{Duration, Instant};
+
+#[derive(Default, Copy, Clone)]
+pub struct Counters {
+	// Camera samples traced.
+	pub samples: u64,
+	// Every query against the scene, including shadow and guide rays.
+	pub rays: u64,
+	// Bounces along camera paths, used for the average path length.
+	pub path_segments: u64,
+	pub bvh_nodes: u64,
+}
+
+impl AddAssign<&Counters> for Counters {
+	fn add_assign(&mut self, rhs: &Counters) {
+		self.samples += rhs.samples;
+		self.rays += rhs.rays;
+		self.path_segments += rhs.path_segments;
+		self.bvh_nodes += rhs.bvh_nodes;
+	}
+}
+
+// The counters are kept per thread so that tracing never has to synchronize.
+// Workers hand theirs back with every packet.
+thread_local! {
+	static COUNTERS: Cell<Counters> = Cell::new(Counters::default());
+}
+
+fn bump(f: impl FnOnce(&mut Counters)) {
+	COUNTERS.with(|c| {
+		let mut counters = c.get();
+		f(&mut counters);
+		c.set(counters);
+	});
+}
+
+pub fn count_sample() {
+	bump(|c| c.samples += 1);
+}
+
+pub fn count_ray() {
+	bump(|c| c.rays += 1);
+}
+
+pub fn count_path_segment() {
+	bump(|c| c.path_segments += 1);
+}
+
+pub fn count_bvh_node() {
+	bump(|c| c.bvh_nodes += 1);
+}
+
+// Return this thread's counters and start over from zero.
+pub fn take() -> Counters {
+	COUNTERS.with(|c| c.replace(Counters::default()))
+}
+
+// The peak resident set size in bytes. Only Linux reports this, through /proc.
+pub fn peak_memory() -> Option<u64> {
+	let status = read_to_string("/proc/self/status").ok()?;
+	let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+	let kb = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+	Some(kb * 1024)
+}
+
+// Prints a single, constantly rewritten progress line.
+pub struct Progress {
+	start: Instant,
+	total_samples: u64,
+	already_done: u64,
+	counters: Counters,
+}
+
+impl Progress {
+	// Progress is measured in samples, not pixels, so that it works across passes.
+	// Samples done by an earlier (resumed) run count toward the percentage, but not the rates.
+	pub fn new(total_samples: u64, already_done: u64) -> Self {
+		Self {
+			start: Instant::now(),
+			total_samples,
+			already_done,
+			counters: Counters::default(),
+		}
+	}
+
+	pub fn add(&mut self, counters: &Counters) {
+		self.counters += counters;
+	}
+
+	pub fn counters(&self) -> &Counters {
+		&self.counters
+	}
+
+	pub fn elapsed(&self) -> Duration {
+		self.start.elapsed()
+	}
+
+	pub fn print(&self) {
+		let elapsed = self.elapsed().as_secs_f64();
+		let done = (self.already_done + self.counters.samples).min(self.total_samples);
+		let percent = if self.total_samples == 0 {
+			100.0
+		} else {
+			100.0 * done as f64 / self.total_samples as f64
+		};
+		let samples_per_sec = rate(self.counters.samples, elapsed);
+		let rays_per_sec = rate(self.counters.rays, elapsed);
+		let eta = if samples_per_sec > 0.0 {
+			format_duration((self.total_samples - done) as f64 / samples_per_sec)
+		} else {
+			String::from("--:--:--")
+		};
+		eprint!(
+			"\r{:6.2}% done, {} elapsed, {} left, {} samples/s, {} rays/s.   ",
+			percent,
+			format_duration(elapsed),
+			eta,
+			format_count(samples_per_sec),
+			format_count(rays_per_sec)
+		);
+	}
+}
+
+// Print the end of render report.
+pub fn report(counters: &Counters, build_time: Duration, trace_time: Duration) {
+	let trace_secs = trace_time.as_secs_f64();
+	eprintln!("Statistics:");
+	eprintln!("  Samples traced:         {}", counters.samples);
+	eprintln!("  Total rays:             {}", counters.rays);
+	eprintln!("  Rays per second:        {}", format_count(rate(counters.rays, trace_secs)));
+	eprintln!("  Average path length:    {:.3}", ratio(counters.path_segments, counters.samples));
+	eprintln!("  BVH nodes per ray:      {:.3}", ratio(counters.bvh_nodes, counters.rays));
+	eprintln!("  Time building:          {}", format_duration(build_time.as_secs_f64()));
+	eprintln!("  Time tracing:           {}", format_duration(trace_secs));
+	match peak_memory() {
+		Some(bytes) => eprintln!("  Peak memory:            {:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+		None => eprintln!("  Peak memory:            unavailable"),
+	}
+}
+
+fn rate(count: u64, secs: f64) -> f64 {
+	if secs > 0.0 {
+		count as f64 / secs
+	} else {
+		0.0
+	}
+}
+
+fn ratio(num: u64, den: u64) -> f64 {
+	if den > 0 {
+		num as f64 / den as f64
+	} else {
+		0.0
+	}
+}
+
+fn format_duration(secs: f64) -> String {
+	let secs = secs.max(0.0);
+	let whole = secs as u64;
+	format!("{:02}:{:02}:{:04.1}", whole / 3600, whole / 60 % 60, secs % 60.0)
+}
+
+// Large counts are easier to read with a suffix, e.g. 1.25M.
+fn format_count(val: f64) -> String {
+	if val >= 1.0e9 {
+		format!("{:.2}G", val / 1.0e9)
+	} else if val >= 1.0e6 {
+		format!("{:.2}M", val / 1.0e6)
+	} else if val >= 1.0e3 {
+		format!("{:.2}K", val / 1.0e3)
+	} else {
+		format!("{:.0}", val)
+	}
+}
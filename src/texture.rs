@@ -6,6 +6,7 @@
 use crate::perlin::Perlin;
 use crate::vector::{Color, Vec3};
 use std::fs::File;
+use std::io::{Error, ErrorKind};
 use std::sync::Arc;
 
 pub trait Texture {
@@ -89,6 +90,14 @@ pub struct NoiseTexture {
 unsafe impl Send for NoiseTexture {}
 unsafe impl Sync for NoiseTexture {}
 
+impl NoiseTexture {
+	pub fn new() -> Self {
+		Self {
+			perlin: Perlin::new(),
+		}
+	}
+}
+
 impl Texture for NoiseTexture {
 	fn value(&self, _u: f64, _v: f64, p: &Vec3) -> Color {
 		Vec3::new(1.0, 1.0, 1.0) * self.perlin.noise(p)
@@ -97,6 +106,8 @@ impl Texture for NoiseTexture {
 
 pub struct ImageTexture {
 	data: Vec<(f64, f64, f64)>,
+	// Empty when the image is opaque.
+	alpha: Vec<f64>,
 	width: usize,
 	height: usize
 }
@@ -105,47 +116,76 @@ impl ImageTexture {
 	pub fn new(data: Vec<(f64, f64, f64)>, width: usize, height: usize) -> Self {
 		Self {
 			data,
+			alpha: Vec::new(),
 			width,
 			height
 		}
 	}
 
+	pub fn with_alpha(mut self, alpha: Vec<f64>) -> Self {
+		self.alpha = alpha;
+		self
+	}
+
 	pub fn from_file(fname: &str) -> Self {
+		Self::open(fname).unwrap()
+	}
+
+	// Like from_file(), but for files that may not be there or may not be PNGs.
+	pub fn open(fname: &str) -> Result<Self, Error> {
 		// The decoder is a build for reader and can be used to set various decoding options
-		// via `Transformations`. The default output transformation is `Transformations::EXPAND
-		// | Transformations::STRIP_ALPHA`.
-		let decoder = png::Decoder::new(File::open(fname).unwrap());
-		let (info, mut reader) = decoder.read_info().unwrap();
+		// via `Transformations`. The default output transformation expands palettes and strips
+		// 16 bit samples to 8, and keeps the alpha channel.
+		let decoder = png::Decoder::new(File::open(fname)?);
+		let (info, mut reader) = decoder.read_info()?;
 		let width = info.width as usize;
 		let height = info.height as usize;
 		// Allocate the output buffer.
 		let mut buf = vec![0; info.buffer_size()];
-		let mut data = Vec::<(f64, f64, f64)>::with_capacity(info.buffer_size() / 3);
+		// Palettes are expanded, so this is 1 for gray, 3 for RGB, and one more
+		// for either with alpha.
+		let channels = info.color_type.samples();
+		let mut data = Vec::<(f64, f64, f64)>::with_capacity(width * height);
+		let mut alpha = Vec::<f64>::with_capacity(width * height);
 		
 		// Read the next frame. An APNG might contain multiple frames.
-		while let Ok(_) = reader.next_frame(&mut buf) {
+		while reader.next_frame(&mut buf).is_ok() {
 		// Inspect more details of the last read frame.
 		// let in_animation = reader.info().frame_control.is_some();
-			for i in (0..buf.len()).step_by(4) {
-				let rgb = (buf[i] as f64 / 255.0, buf[i + 1] as f64 / 255.0, buf[i + 2] as f64 / 255.0);
+			for pixel in buf.chunks_exact(channels) {
+				let c = |i: usize| pixel[i] as f64 / 255.0;
+				let rgb = if channels < 3 {
+					(c(0), c(0), c(0))
+				} else {
+					(c(0), c(1), c(2))
+				};
 				data.push(rgb);
+				if channels % 2 == 0 {
+					alpha.push(c(channels - 1));
+				}
 			}
 		}
+		if data.is_empty() {
+			return Err(Error::new(ErrorKind::InvalidData, "no image data"));
+		}
 
-		Self {
+		Ok(Self {
 			data,
+			alpha,
 			width,
 			height,
-		}
+		})
 	}
-}
 
-impl Texture for ImageTexture {
-	fn value(&self, u: f64, v: f64, _point: &Vec3) -> Color {
-		if self.data.len() == 0 {
-			return Color::new(0.0, 1.0, 1.0);
+	// How opaque the image is at u, v, from 0 to 1.
+	pub fn alpha(&self, u: f64, v: f64) -> f64 {
+		if self.alpha.is_empty() {
+			return 1.0;
 		}
+		self.alpha[self.index(u, v)]
+	}
 
+	fn index(&self, u: f64, v: f64) -> usize {
 		// Clamp input texture coordinates to [0,1] x [1,0]
 		let u = clamp(u, 0.0, 1.0);
 		let v = 1.0 - clamp(v, 0.0, 1.0); // Flip V to image coordinates
@@ -160,12 +200,42 @@ impl Texture for ImageTexture {
 		if j >= self.height {
 			j = self.height - 1;
 		}
-		let pixel = self.data[j * self.width + i];
+		j * self.width + i
+	}
+}
+
+impl Texture for ImageTexture {
+	fn value(&self, u: f64, v: f64, _point: &Vec3) -> Color {
+		if self.data.is_empty() {
+			return Color::new(0.0, 1.0, 1.0);
+		}
+
+		let pixel = self.data[self.index(u, v)];
 
 		Color::new(pixel.0, pixel.1, pixel.2)
 	}
 }
 
+// An image's alpha channel as a gray texture, such as for a cutout's opacity.
+pub struct AlphaTexture {
+	image: Arc<ImageTexture>,
+}
+
+impl AlphaTexture {
+	pub fn new(image: Arc<ImageTexture>) -> Self {
+		Self {
+			image,
+		}
+	}
+}
+
+impl Texture for AlphaTexture {
+	fn value(&self, u: f64, v: f64, _point: &Vec3) -> Color {
+		let alpha = self.image.alpha(u, v);
+		Color::new(alpha, alpha, alpha)
+	}
+}
+
 fn clamp(val: f64, min: f64, max: f64) -> f64 {
 	if val < min {
 		min
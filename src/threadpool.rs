@@ -1,7 +1,13 @@
+use crate::background::{Background, SolidBackground};
 use crate::camera::Camera;
+use crate::filter::{BoxFilter, Filter};
 use crate::hitable::HitList;
-use crate::random::random_f64;
+use crate::light::LightList;
+use crate::random::{self, mix_seed, random_f64};
+use crate::spectrum::Wavelengths;
+use crate::stats::{self, count_sample, Counters};
 use crate::vector::Vec3;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 use std::thread::{spawn, JoinHandle};
@@ -12,25 +18,36 @@ pub struct ControlPacket {
 	pub col: u32,
 	pub camera: Arc<Camera>,
 	pub objects: Arc<HitList>,
+	pub filter: Arc<dyn Filter + Send + Sync>,
+	pub background: Arc<dyn Background + Send + Sync>,
+	pub lights: Arc<LightList>,
 	pub done: bool,
+	pub seed: u64,
 	pub samples: u32,
 	pub image_width: u32,
 	pub image_height: u32,
 	pub max_depth: i32,
+	pub spectral: bool,
 }
 
 impl ControlPacket {
-	pub const fn new(row: u32, col: u32, camera: Arc<Camera>, objects: Arc<HitList>, samples: u32, image_width: u32, image_height: u32, max_depth: i32) -> Self {
+	#[allow(clippy::too_many_arguments)]
+	pub fn new(row: u32, col: u32, camera: Arc<Camera>, objects: Arc<HitList>, filter: Arc<dyn Filter + Send + Sync>, background: Arc<dyn Background + Send + Sync>, lights: Arc<LightList>, seed: u64, samples: u32, image_width: u32, image_height: u32, max_depth: i32, spectral: bool) -> Self {
 		Self {
 			row,
 			col,
 			camera,
 			objects,
+			filter,
+			background,
+			lights,
 			done: false,
+			seed,
 			samples,
 			image_width,
 			image_height,
 			max_depth,
+			spectral,
 		}
 	}
 	pub fn done() -> Self {
@@ -39,27 +56,54 @@ impl ControlPacket {
 			col: 0,
 			camera: Arc::new(Camera::default()),
 			objects: Arc::new(HitList::default()),
+			filter: Arc::new(BoxFilter::default()),
+			background: Arc::new(SolidBackground::default()),
+			lights: Arc::new(LightList::default()),
 			done: true,
+			seed: 0,
 			samples: 0,
 			image_width: 0,
 			image_height: 0,
 			max_depth: 0,
+			spectral: false,
 		}
 	}
 }
 
-pub struct DataPacket {
+// A filter-weighted sum of samples landing on one pixel. The color has
+// already been multiplied by the weights.
+pub struct Splat {
 	pub row: u32,
 	pub col: u32,
 	pub color: Vec3,
+	pub weight: f64,
+}
+
+pub struct DataPacket {
+	pub row: u32,
+	pub col: u32,
+	// The number of samples actually traced. This is less than requested
+	// (possibly zero) if the render was cancelled.
+	pub samples: u32,
+	pub splats: Vec<Splat>,
+	pub albedo: Vec3,
+	pub normal: Vec3,
+	pub depth: f64,
+	pub counters: Counters,
 }
 
 impl DataPacket {
-	pub const fn new(row: u32, col: u32, color: Vec3) -> Self {
+	#[allow(clippy::too_many_arguments)]
+	pub const fn new(row: u32, col: u32, samples: u32, splats: Vec<Splat>, albedo: Vec3, normal: Vec3, depth: f64, counters: Counters) -> Self {
 		Self {
 			row,
 			col,
-			color,
+			samples,
+			splats,
+			albedo,
+			normal,
+			depth,
+			counters,
 		}
 	}
 }
@@ -77,29 +121,86 @@ pub struct ThreadPool {
 }
 
 impl ThreadPool {
-	pub fn new(num_threads: usize) -> Self {
+	// Once cancel is set, the workers stop tracing as soon as they finish the
+	// sample they're on. Every packet still gets an answer, it just may be
+	// empty, so whoever is receiving doesn't have to know about cancellation.
+	pub fn new(num_threads: usize, cancel: Arc<AtomicBool>) -> Self {
 		assert!(num_threads > 0);
 		let mut threads = Vec::with_capacity(num_threads);
-		let background = Vec3::new(0.0, 0.0, 0.0);
 		for _ in 0..num_threads {
 			let (data_s, data_r): (Sender<DataPacket>, Receiver<_>) = channel();
 			let (control_s, control_r): (Sender<ControlPacket>, Receiver<_>) = channel();
 			let cws = data_s.clone();
+			let cancel = cancel.clone();
 			let t = Thread {
 				thread: spawn(move || {
 					while let Ok(packet) = control_r.recv() {
-						if packet.done == true {
+						if packet.done {
 							break;
 						}
+						// Seeding per pixel makes the result independent of which thread got the pixel.
+						random::seed(mix_seed(&[packet.seed, packet.row as u64, packet.col as u64]));
 						let iwf = packet.image_width as f64 - 1.0;
 						let ihf = packet.image_height as f64 - 1.0;
-						let mut color = Vec3::new(0.0, 0.0, 0.0);
+						// Every sample of this pixel can reach this many pixels out in each direction.
+						let extent = (packet.filter.radius() + 0.5).ceil() as i64;
+						let span = (2 * extent + 1) as usize;
+						let mut splats = vec![(Vec3::new(0.0, 0.0, 0.0), 0.0); span * span];
+						let mut albedo = Vec3::new(0.0, 0.0, 0.0);
+						let mut normal = Vec3::new(0.0, 0.0, 0.0);
+						let mut depth = 0.0;
+						let mut traced = 0;
 						for _ in 0..packet.samples {
-							let u = (random_f64() + packet.col as f64) / iwf;
-							let v = (random_f64() + packet.row as f64) / ihf;
-							color += &packet.camera.get_ray(u, v).color(&background, &packet.objects, 10);
+							if cancel.load(Ordering::Relaxed) {
+								break;
+							}
+							traced += 1;
+							count_sample();
+							let sx = random_f64() + packet.col as f64;
+							let sy = random_f64() + packet.row as f64;
+							let ray = packet.camera.get_ray(sx / iwf, sy / ihf);
+							// Spectral samples trace radiance at a few wavelengths, which the
+							// film turns back into RGB.
+							let (color, (a, n, d)) = if packet.spectral {
+								let wavelengths = Wavelengths::sample(random_f64());
+								let ray = ray.with_wavelengths(wavelengths);
+								let (radiance, guides) = ray.color(packet.background.as_ref(), &packet.lights, &packet.objects, packet.max_depth);
+								(wavelengths.to_rgb(&radiance), guides)
+							} else {
+								ray.color(packet.background.as_ref(), &packet.lights, &packet.objects, packet.max_depth)
+							};
+							albedo += &a;
+							normal += &n;
+							depth += d;
+							for j in 0..span {
+								for i in 0..span {
+									// Offset from the sample to the center of the neighboring pixel.
+									let dx = (packet.col as i64 - extent + i as i64) as f64 + 0.5 - sx;
+									let dy = (packet.row as i64 - extent + j as i64) as f64 + 0.5 - sy;
+									let weight = packet.filter.evaluate(dx, dy);
+									let splat = &mut splats[j * span + i];
+									splat.0 += &(color * weight);
+									splat.1 += weight;
+								}
+							}
+						}
+						let mut out = Vec::with_capacity(span * span);
+						for (idx, (color, weight)) in splats.into_iter().enumerate() {
+							let col = packet.col as i64 - extent + (idx % span) as i64;
+							let row = packet.row as i64 - extent + (idx / span) as i64;
+							if weight == 0.0 || col < 0 || row < 0 || col >= packet.image_width as i64 || row >= packet.image_height as i64 {
+								continue;
+							}
+							out.push(Splat {
+								row: row as u32,
+								col: col as u32,
+								color,
+								weight,
+							});
 						}
-						let dp = DataPacket::new(packet.row, packet.col, color);
+						// The guides are averaged here, the color is averaged when the picture is written.
+						let scale = 1.0 / (traced as f64).max(1.0);
+						let dp = DataPacket::new(packet.row, packet.col, traced, out, albedo * scale, normal * scale, depth * scale, stats::take());
 
 						cws.send(dp).unwrap();
 					}
@@ -126,8 +227,9 @@ impl ThreadPool {
 		res.is_ok()
 	}
 
-	pub fn run_c(&mut self, row: u32, col: u32, camera: Arc<Camera>, objects: Arc<HitList>, samples: u32, image_width: u32, image_height: u32, max_depth: i32) -> bool {
-		let cp = ControlPacket::new(row, col, camera, objects, samples, image_width, image_height, max_depth);
+	#[allow(clippy::too_many_arguments)]
+	pub fn run_c(&mut self, row: u32, col: u32, camera: Arc<Camera>, objects: Arc<HitList>, filter: Arc<dyn Filter + Send + Sync>, background: Arc<dyn Background + Send + Sync>, lights: Arc<LightList>, seed: u64, samples: u32, image_width: u32, image_height: u32, max_depth: i32, spectral: bool) -> bool {
+		let cp = ControlPacket::new(row, col, camera, objects, filter, background, lights, seed, samples, image_width, image_height, max_depth, spectral);
 		self.run(cp)
 	}
 }
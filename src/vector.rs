@@ -63,6 +63,24 @@ impl Vec3 {
 		Self::new(self.x() / l, self.y() / l, self.z() / l)
 	}
 
+	// Two unit vectors perpendicular to this one (which has to be a unit
+	// vector) and to each other.
+	pub fn basis(&self) -> (Self, Self) {
+		let a = if self.x().abs() > 0.9 {
+			Self::new(0.0, 1.0, 0.0)
+		} else {
+			Self::new(1.0, 0.0, 0.0)
+		};
+		let v = self.cross(&a).unit();
+		let u = self.cross(&v);
+		(u, v)
+	}
+
+	// Brightness of a linear sRGB color.
+	pub fn luminance(&self) -> f64 {
+		0.2126 * self.r() + 0.7152 * self.g() + 0.0722 * self.b()
+	}
+
 	pub fn near_zero(&self) -> bool {
 		self.len() <= 0.001 && self.len() >= -0.001
 	}